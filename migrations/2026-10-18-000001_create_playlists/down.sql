@@ -0,0 +1 @@
+DROP TABLE playlists
\ No newline at end of file
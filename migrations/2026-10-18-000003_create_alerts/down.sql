@@ -0,0 +1,4 @@
+DROP INDEX alert_history_time;
+DROP TABLE alert_history;
+DROP TABLE alert_states;
+DROP TABLE alert_rules;
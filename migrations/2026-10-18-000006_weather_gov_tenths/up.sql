@@ -0,0 +1,10 @@
+-- weather.gov readings were saved in whole degrees F instead of 1/10 degrees.
+UPDATE thermostats SET temperature = temperature * 10 WHERE name = 'weather.gov';
+UPDATE thermostats_hourly
+  SET temperature = temperature * 10, temperature_min = temperature_min * 10,
+      temperature_max = temperature_max * 10
+  WHERE name = 'weather.gov';
+UPDATE thermostats_daily
+  SET temperature = temperature * 10, temperature_min = temperature_min * 10,
+      temperature_max = temperature_max * 10
+  WHERE name = 'weather.gov';
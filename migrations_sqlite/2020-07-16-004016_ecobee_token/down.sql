@@ -0,0 +1 @@
+DROP TABLE ecobee_token
\ No newline at end of file
@@ -0,0 +1,6 @@
+CREATE TABLE ecobee_token (
+  id INTEGER PRIMARY KEY,
+  access_token VARCHAR NOT NULL,
+  refresh_token VARCHAR NOT NULL,
+  expires TIMESTAMP NOT NULL
+)
\ No newline at end of file
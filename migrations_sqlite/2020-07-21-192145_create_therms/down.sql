@@ -0,0 +1 @@
+DROP TABLE thermostats
\ No newline at end of file
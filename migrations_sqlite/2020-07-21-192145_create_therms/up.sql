@@ -0,0 +1,8 @@
+CREATE TABLE thermostats (
+  id INTEGER PRIMARY KEY,
+  name VARCHAR NOT NULL,
+  time TIMESTAMP NOT NULL,
+  is_hygrostat BOOLEAN NOT NULL,
+  temperature INT NOT NULL,
+  relative_humidity INT NOT NULL
+)
\ No newline at end of file
@@ -0,0 +1,16 @@
+CREATE TABLE photos (
+  id INTEGER PRIMARY KEY,
+  source VARCHAR NOT NULL,
+  guid VARCHAR NOT NULL,
+  file_id VARCHAR NOT NULL,
+  checksum VARCHAR NOT NULL,
+  taken_at TIMESTAMP,
+  orientation SMALLINT NOT NULL DEFAULT 1,
+  caption VARCHAR,
+  width INT NOT NULL,
+  height INT NOT NULL,
+  added TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  UNIQUE (source, guid)
+);
+CREATE INDEX photos_checksum ON photos (checksum);
+CREATE INDEX photos_file_id ON photos (file_id);
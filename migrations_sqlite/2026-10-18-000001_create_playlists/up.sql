@@ -0,0 +1,8 @@
+CREATE TABLE playlists (
+  id INTEGER PRIMARY KEY,
+  display VARCHAR NOT NULL UNIQUE,
+  interval_seconds INT NOT NULL,
+  shuffle BOOLEAN NOT NULL DEFAULT FALSE,
+  rule VARCHAR NOT NULL DEFAULT 'all',
+  updated TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
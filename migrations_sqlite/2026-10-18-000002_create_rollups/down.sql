@@ -0,0 +1,3 @@
+DROP INDEX thermostats_time;
+DROP TABLE thermostats_daily;
+DROP TABLE thermostats_hourly;
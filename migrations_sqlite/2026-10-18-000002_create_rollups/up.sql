@@ -0,0 +1,33 @@
+CREATE TABLE thermostats_hourly (
+  id INTEGER PRIMARY KEY,
+  name VARCHAR NOT NULL,
+  time TIMESTAMP NOT NULL,
+  is_hygrostat BOOLEAN NOT NULL,
+  samples INT NOT NULL,
+  temperature INT NOT NULL,
+  temperature_min INT NOT NULL,
+  temperature_max INT NOT NULL,
+  relative_humidity INT NOT NULL,
+  relative_humidity_min INT NOT NULL,
+  relative_humidity_max INT NOT NULL,
+  UNIQUE (name, time)
+);
+CREATE INDEX thermostats_hourly_time ON thermostats_hourly (time, id);
+
+CREATE TABLE thermostats_daily (
+  id INTEGER PRIMARY KEY,
+  name VARCHAR NOT NULL,
+  time TIMESTAMP NOT NULL,
+  is_hygrostat BOOLEAN NOT NULL,
+  samples INT NOT NULL,
+  temperature INT NOT NULL,
+  temperature_min INT NOT NULL,
+  temperature_max INT NOT NULL,
+  relative_humidity INT NOT NULL,
+  relative_humidity_min INT NOT NULL,
+  relative_humidity_max INT NOT NULL,
+  UNIQUE (name, time)
+);
+CREATE INDEX thermostats_daily_time ON thermostats_daily (time, id);
+
+CREATE INDEX IF NOT EXISTS thermostats_time ON thermostats (time, id);
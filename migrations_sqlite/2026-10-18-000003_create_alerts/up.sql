@@ -0,0 +1,35 @@
+CREATE TABLE alert_rules (
+  id INTEGER PRIMARY KEY AUTOINCREMENT, -- ids are not reused, so history stays with its rule
+  name VARCHAR NOT NULL UNIQUE,
+  sensor VARCHAR NOT NULL,
+  condition VARCHAR NOT NULL,
+  metric VARCHAR NOT NULL DEFAULT 'temperature',
+  threshold DOUBLE PRECISION NOT NULL DEFAULT 0,
+  hysteresis DOUBLE PRECISION NOT NULL DEFAULT 0,
+  duration_seconds INT NOT NULL DEFAULT 0,
+  webhook_url VARCHAR,
+  email VARCHAR,
+  enabled BOOLEAN NOT NULL DEFAULT TRUE,
+  updated TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE alert_states (
+  rule_id INT NOT NULL REFERENCES alert_rules (id) ON DELETE CASCADE,
+  sensor VARCHAR NOT NULL,
+  state VARCHAR NOT NULL,
+  since TIMESTAMP NOT NULL,
+  value DOUBLE PRECISION,
+  PRIMARY KEY (rule_id, sensor)
+);
+
+CREATE TABLE alert_history (
+  id INTEGER PRIMARY KEY,
+  rule_id INT NOT NULL,
+  rule_name VARCHAR NOT NULL,
+  sensor VARCHAR NOT NULL,
+  state VARCHAR NOT NULL,
+  value DOUBLE PRECISION,
+  message VARCHAR NOT NULL,
+  time TIMESTAMP NOT NULL
+);
+CREATE INDEX alert_history_time ON alert_history (time, id);
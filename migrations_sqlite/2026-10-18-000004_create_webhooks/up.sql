@@ -0,0 +1,25 @@
+CREATE TABLE webhooks (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  url VARCHAR NOT NULL,
+  events VARCHAR NOT NULL,
+  secret VARCHAR NOT NULL,
+  enabled BOOLEAN NOT NULL DEFAULT TRUE,
+  created TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  updated TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE webhook_deliveries (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  webhook_id INT NOT NULL REFERENCES webhooks (id) ON DELETE CASCADE,
+  event VARCHAR NOT NULL,
+  payload TEXT NOT NULL,
+  status VARCHAR NOT NULL,
+  attempts INT NOT NULL DEFAULT 0,
+  next_attempt TIMESTAMP,
+  response_status INT,
+  error VARCHAR,
+  created TIMESTAMP NOT NULL,
+  delivered TIMESTAMP
+);
+CREATE INDEX webhook_deliveries_due ON webhook_deliveries (status, next_attempt);
+CREATE INDEX webhook_deliveries_webhook ON webhook_deliveries (webhook_id, id);
@@ -0,0 +1,5 @@
+CREATE TABLE export_cursors (
+  target VARCHAR PRIMARY KEY,
+  last_id INT NOT NULL,
+  updated TIMESTAMP NOT NULL
+);
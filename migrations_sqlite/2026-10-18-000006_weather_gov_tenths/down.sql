@@ -0,0 +1,9 @@
+UPDATE thermostats_daily
+  SET temperature = temperature / 10, temperature_min = temperature_min / 10,
+      temperature_max = temperature_max / 10
+  WHERE name = 'weather.gov';
+UPDATE thermostats_hourly
+  SET temperature = temperature / 10, temperature_min = temperature_min / 10,
+      temperature_max = temperature_max / 10
+  WHERE name = 'weather.gov';
+UPDATE thermostats SET temperature = temperature / 10 WHERE name = 'weather.gov';
@@ -0,0 +1,347 @@
+use crate::db::DbConnection;
+use crate::Thermostat;
+use chrono::{DateTime, Duration, NaiveDateTime, TimeZone, Utc};
+use diesel::prelude::*;
+use diesel::sql_types::{Timestamp, Varchar};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+use rule::NewAlertEvent;
+pub use rule::{AlertEvent, AlertRule, AlertState, NewAlertRule};
+
+mod notify;
+mod rule;
+mod smtp;
+
+// this file covers the alert rule engine, which the worker runs against each
+// round of readings
+
+pub const PENDING: &str = "pending";
+pub const FIRING: &str = "firing";
+pub const RESOLVED: &str = "resolved";
+/// A rule for this sensor name applies to every sensor.
+pub const ANY_SENSOR: &str = "*";
+/// How far back `stale` rules look for sensors. A sensor that has been quiet
+/// for longer is only still checked while one of its alerts is firing.
+const LAST_SEEN_DAYS: i64 = 30;
+
+/// # Condition
+/// What a rule looks for.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Condition {
+    /// A reading over the threshold.
+    Above,
+    /// A reading under the threshold.
+    Below,
+    /// A sensor that has not reported for the rule's duration.
+    Stale,
+}
+
+impl Condition {
+    pub fn parse(name: &str) -> Option<Self> {
+        serde_json::from_value(serde_json::Value::String(name.to_string())).ok()
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Condition::Above => "above",
+            Condition::Below => "below",
+            Condition::Stale => "stale",
+        }
+    }
+}
+
+/// # Metric
+/// Which part of a reading `above` and `below` compare.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Metric {
+    /// Degrees F.
+    Temperature,
+    /// Percent relative humidity.
+    Humidity,
+}
+
+impl Metric {
+    pub fn parse(name: &str) -> Option<Self> {
+        serde_json::from_value(serde_json::Value::String(name.to_string())).ok()
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Metric::Temperature => "temperature",
+            Metric::Humidity => "humidity",
+        }
+    }
+
+    /// The reading's value, or `None` when the sensor does not measure it:
+    /// humidity-only sensors have no temperature, and the weather has no
+    /// humidity.
+    fn value(self, reading: &Thermostat) -> Option<f64> {
+        match self {
+            Metric::Temperature => reading.fahrenheit(),
+            Metric::Humidity => reading.humidity().map(f64::from),
+        }
+    }
+
+    fn format(self, value: f64) -> String {
+        match self {
+            Metric::Temperature => format!("{:.1}°F", value),
+            Metric::Humidity => format!("{:.0}%", value),
+        }
+    }
+}
+
+#[derive(QueryableByName)]
+struct LastSeen {
+    #[sql_type = "Varchar"]
+    name: String,
+    #[sql_type = "Timestamp"]
+    time: NaiveDateTime,
+}
+
+/// When each sensor last reported, for `stale` rules.
+fn last_seen(
+    connection: &DbConnection,
+    now: &DateTime<Utc>,
+) -> QueryResult<HashMap<String, DateTime<Utc>>> {
+    let query = diesel::sql_query(
+        "SELECT name, MAX(time) AS time FROM thermostats WHERE time >= $1 GROUP BY name",
+    )
+    .bind::<Timestamp, _>((*now - Duration::days(LAST_SEEN_DAYS)).naive_utc());
+    crate::db::log_query(&query);
+    Ok(query
+        .load::<LastSeen>(connection)?
+        .into_iter()
+        .map(|seen| (seen.name, Utc.from_utc_datetime(&seen.time)))
+        .collect())
+}
+
+fn matches(rule: &AlertRule, sensor: &str) -> bool {
+    rule.sensor == ANY_SENSOR || rule.sensor == sensor
+}
+
+/// # Check
+/// What one rule found for one sensor this round.
+struct Check {
+    /// The condition holds.
+    active: bool,
+    /// A firing alert can resolve. Differs from `!active` by the rule's
+    /// hysteresis.
+    cleared: bool,
+    value: Option<f64>,
+    /// How long the condition must hold before the alert fires.
+    hold: Duration,
+}
+
+/// # Evaluate
+/// Checks every enabled rule against this round's readings, moving each
+/// sensor between fine, `pending` and `firing`. Alerts that start or stop
+/// firing are written to the history and sent to the rule's webhook and
+/// email address.
+pub fn evaluate(connection: &DbConnection, readings: &[Thermostat]) -> QueryResult<()> {
+    let rules = AlertRule::enabled(connection)?;
+    if rules.is_empty() {
+        return Ok(());
+    }
+    let now = Utc::now();
+    let mut states: HashMap<(i32, String), AlertState> = AlertState::all(connection)?
+        .into_iter()
+        .map(|state| ((state.rule_id, state.sensor.clone()), state))
+        .collect();
+    let wants_last_seen = rules
+        .iter()
+        .any(|rule| Condition::parse(&rule.condition) == Some(Condition::Stale));
+    let last_seen = if wants_last_seen {
+        last_seen(connection, &now)?
+    } else {
+        HashMap::new()
+    };
+
+    for rule in &rules {
+        let condition = match Condition::parse(&rule.condition) {
+            Some(condition) => condition,
+            None => {
+                tracing::warn!(rule = rule.id, "Unknown alert condition {}", rule.condition);
+                continue;
+            }
+        };
+        let mut checks = BTreeMap::new();
+        if condition == Condition::Stale {
+            // Sensors with an alert are checked even when they have not
+            // reported in a long time, so the alert keeps firing.
+            let mut sensors: BTreeMap<&str, Option<&DateTime<Utc>>> = last_seen
+                .iter()
+                .filter(|(sensor, _)| matches(rule, sensor))
+                .map(|(sensor, time)| (sensor.as_str(), Some(time)))
+                .collect();
+            for (rule_id, sensor) in states.keys() {
+                if *rule_id == rule.id {
+                    sensors.entry(sensor).or_insert(None);
+                }
+            }
+            if rule.sensor != ANY_SENSOR {
+                sensors.entry(&rule.sensor).or_insert(None);
+            }
+            let quiet = Duration::seconds(i64::from(rule.duration_seconds));
+            for (sensor, time) in sensors {
+                let active = match time {
+                    Some(time) => now - *time >= quiet,
+                    None => true,
+                };
+                checks.insert(
+                    sensor.to_string(),
+                    Check {
+                        active,
+                        cleared: !active,
+                        value: time.map(|time| (now - *time).num_minutes() as f64),
+                        hold: Duration::zero(),
+                    },
+                );
+            }
+        } else {
+            let metric = match Metric::parse(&rule.metric) {
+                Some(metric) => metric,
+                None => {
+                    tracing::warn!(rule = rule.id, "Unknown alert metric {}", rule.metric);
+                    continue;
+                }
+            };
+            for reading in readings
+                .iter()
+                .filter(|reading| matches(rule, &reading.name))
+            {
+                let value = match metric.value(reading) {
+                    Some(value) => value,
+                    None => continue,
+                };
+                let (active, cleared) = match condition {
+                    Condition::Below => (
+                        value < rule.threshold,
+                        value >= rule.threshold + rule.hysteresis,
+                    ),
+                    _ => (
+                        value > rule.threshold,
+                        value <= rule.threshold - rule.hysteresis,
+                    ),
+                };
+                checks.insert(
+                    reading.name.clone(),
+                    Check {
+                        active,
+                        cleared,
+                        value: Some(value),
+                        hold: Duration::seconds(i64::from(rule.duration_seconds)),
+                    },
+                );
+            }
+        }
+        for (sensor, check) in checks {
+            let current = states.remove(&(rule.id, sensor.clone()));
+            step(connection, rule, &sensor, current, check, &now)?;
+        }
+    }
+    Ok(())
+}
+
+/// Moves one sensor's alert along.
+fn step(
+    connection: &DbConnection,
+    rule: &AlertRule,
+    sensor: &str,
+    current: Option<AlertState>,
+    check: Check,
+    now: &DateTime<Utc>,
+) -> QueryResult<()> {
+    let state = |state: &str, since: NaiveDateTime| AlertState {
+        rule_id: rule.id,
+        sensor: sensor.to_string(),
+        state: state.to_string(),
+        since,
+        value: check.value,
+    };
+    match current {
+        Some(current) if current.state == FIRING => {
+            if check.cleared {
+                AlertState::remove(connection, rule.id, sensor)?;
+                record(connection, rule, sensor, RESOLVED, check.value, now)?;
+            } else {
+                state(FIRING, current.since).save(connection)?;
+            }
+        }
+        Some(current) => {
+            if !check.active {
+                AlertState::remove(connection, rule.id, sensor)?;
+            } else if *now - current.since() >= check.hold {
+                state(FIRING, current.since).save(connection)?;
+                record(connection, rule, sensor, FIRING, check.value, now)?;
+            } else {
+                state(PENDING, current.since).save(connection)?;
+            }
+        }
+        None if check.active && check.hold <= Duration::zero() => {
+            state(FIRING, now.naive_utc()).save(connection)?;
+            record(connection, rule, sensor, FIRING, check.value, now)?;
+        }
+        None if check.active => state(PENDING, now.naive_utc()).save(connection)?,
+        None => (),
+    }
+    Ok(())
+}
+
+/// Writes an alert starting or stopping firing to the history, and sends it.
+fn record(
+    connection: &DbConnection,
+    rule: &AlertRule,
+    sensor: &str,
+    state: &str,
+    value: Option<f64>,
+    now: &DateTime<Utc>,
+) -> QueryResult<()> {
+    let event = NewAlertEvent {
+        rule_id: rule.id,
+        rule_name: rule.name.clone(),
+        sensor: sensor.to_string(),
+        state: state.to_string(),
+        value,
+        message: message(rule, sensor, state, value),
+        time: now.naive_utc(),
+    };
+    if state == FIRING {
+        tracing::warn!(rule = rule.id, sensor, "Alert firing: {}", event.message);
+    } else {
+        tracing::info!(rule = rule.id, sensor, "Alert resolved: {}", event.message);
+    }
+    AlertEvent::insert(connection, &event)?;
+    notify::send(rule, &event);
+    Ok(())
+}
+
+/// Says what happened, like "Fridge temperature is 46.2°F, above 45.0°F".
+fn message(rule: &AlertRule, sensor: &str, state: &str, value: Option<f64>) -> String {
+    let condition = Condition::parse(&rule.condition).unwrap_or(Condition::Above);
+    let metric = Metric::parse(&rule.metric).unwrap_or(Metric::Temperature);
+    match (condition, state, value) {
+        (Condition::Stale, FIRING, Some(minutes)) => {
+            format!("{} has not reported for {} minutes", sensor, minutes)
+        }
+        (Condition::Stale, FIRING, None) => format!("{} has not reported", sensor),
+        (Condition::Stale, _, _) => format!("{} is reporting again", sensor),
+        (_, FIRING, Some(value)) => format!(
+            "{} {} is {}, {} {}",
+            sensor,
+            metric.name(),
+            metric.format(value),
+            condition.name(),
+            metric.format(rule.threshold)
+        ),
+        (_, _, Some(value)) => format!(
+            "{} {} is back to {}",
+            sensor,
+            metric.name(),
+            metric.format(value)
+        ),
+        (_, _, None) => format!("{} {} is {}", sensor, metric.name(), state),
+    }
+}
@@ -0,0 +1,88 @@
+use super::rule::{AlertRule, NewAlertEvent};
+use super::smtp;
+use chrono::{DateTime, TimeZone, Utc};
+use serde::Serialize;
+use std::time::Duration;
+
+// this file covers telling people about alerts, by webhook and by email
+
+/// Webhooks that take longer than this are given up on.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// # Notification
+/// The JSON body POSTed to a rule's webhook.
+#[derive(Serialize)]
+struct Notification<'a> {
+    rule_id: i32,
+    rule: &'a str,
+    sensor: &'a str,
+    /// `firing` or `resolved`.
+    state: &'a str,
+    value: Option<f64>,
+    message: &'a str,
+    time: DateTime<Utc>,
+}
+
+/// # Send
+/// Sends an event to the rule's webhook and email addresses. Failures are
+/// logged and counted; the alert is in the history either way.
+pub fn send(rule: &AlertRule, event: &NewAlertEvent) {
+    if let Some(url) = &rule.webhook_url {
+        let notification = Notification {
+            rule_id: event.rule_id,
+            rule: &event.rule_name,
+            sensor: &event.sensor,
+            state: &event.state,
+            value: event.value,
+            message: &event.message,
+            time: Utc.from_utc_datetime(&event.time),
+        };
+        let result = serde_json::to_string(&notification)
+            .map_err(anyhow::Error::from)
+            .and_then(|body| post(url, body));
+        sent("webhook", rule, result);
+    }
+    if let Some(email) = &rule.email {
+        let result = match &crate::config::get().smtp {
+            Some(settings) => {
+                let subject = format!("[therm_hub] {}: {}", rule.name, event.state);
+                smtp::send(settings, email, &subject, &event.message)
+            }
+            None => Err(anyhow::anyhow!("SMTP is not configured")),
+        };
+        sent("email", rule, result);
+    }
+}
+
+fn sent(channel: &str, rule: &AlertRule, result: anyhow::Result<()>) {
+    let outcome = match result {
+        Ok(_) => "ok",
+        Err(err) => {
+            tracing::error!(
+                rule = rule.id,
+                "Could not send alert {}: {:#}",
+                channel,
+                err
+            );
+            "error"
+        }
+    };
+    crate::metrics::inc(
+        crate::metrics::ALERT_NOTIFICATIONS,
+        &[("channel", channel), ("result", outcome)],
+    );
+}
+
+#[tokio::main]
+async fn post(url: &str, body: String) -> anyhow::Result<()> {
+    crate::REQWEST
+        .post(url)
+        .header("Content-Type", "application/json")
+        .header("User-Agent", "github.com/ryanknu/therm_hub")
+        .timeout(WEBHOOK_TIMEOUT)
+        .body(body)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
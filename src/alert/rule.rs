@@ -0,0 +1,234 @@
+use crate::db::DbConnection;
+use crate::schema::{alert_history, alert_rules, alert_states};
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use diesel::prelude::*;
+use serde::Serialize;
+
+// this file covers the alert tables: `alert_rules`, the state of every rule
+// that is pending or firing in `alert_states`, and `alert_history`
+
+#[derive(Clone, Debug, Queryable, Serialize)]
+pub struct AlertRule {
+    pub id: i32,
+    pub name: String,
+    /// A sensor's name, or `*` for every sensor.
+    pub sensor: String,
+    /// `above`, `below` or `stale`.
+    pub condition: String,
+    /// `temperature` (degrees F) or `humidity` (percent).
+    pub metric: String,
+    pub threshold: f64,
+    /// How far back past the threshold a reading has to go before a firing
+    /// alert resolves.
+    pub hysteresis: f64,
+    /// How long the condition has to hold before the alert fires. For
+    /// `stale`, how long a sensor has to go without reporting.
+    pub duration_seconds: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub webhook_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    pub enabled: bool,
+    /// When the rule was last saved, in UTC.
+    pub updated: NaiveDateTime,
+}
+
+#[derive(AsChangeset, Insertable)]
+#[table_name = "alert_rules"]
+// `None` clears the notification instead of leaving it alone.
+#[changeset_options(treat_none_as_null = "true")]
+pub struct NewAlertRule {
+    pub name: String,
+    pub sensor: String,
+    pub condition: String,
+    pub metric: String,
+    pub threshold: f64,
+    pub hysteresis: f64,
+    pub duration_seconds: i32,
+    pub webhook_url: Option<String>,
+    pub email: Option<String>,
+    pub enabled: bool,
+    pub updated: NaiveDateTime,
+}
+
+/// # Alert State
+/// A rule whose condition holds for a sensor. Sensors that are fine have no
+/// row.
+#[derive(Clone, Debug, Insertable, Queryable, Serialize)]
+#[table_name = "alert_states"]
+pub struct AlertState {
+    pub rule_id: i32,
+    pub sensor: String,
+    /// `pending` until the condition has held for the rule's duration, then
+    /// `firing`.
+    pub state: String,
+    /// When the condition started to hold, in UTC.
+    pub since: NaiveDateTime,
+    pub value: Option<f64>,
+}
+
+/// # Alert Event
+/// A rule starting or stopping firing for a sensor.
+#[derive(Clone, Debug, Queryable, Serialize)]
+pub struct AlertEvent {
+    pub id: i32,
+    pub rule_id: i32,
+    pub rule_name: String,
+    pub sensor: String,
+    /// `firing` or `resolved`.
+    pub state: String,
+    pub value: Option<f64>,
+    pub message: String,
+    /// In UTC.
+    pub time: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "alert_history"]
+pub struct NewAlertEvent {
+    pub rule_id: i32,
+    pub rule_name: String,
+    pub sensor: String,
+    pub state: String,
+    pub value: Option<f64>,
+    pub message: String,
+    pub time: NaiveDateTime,
+}
+
+impl AlertRule {
+    pub fn all(connection: &DbConnection) -> QueryResult<Vec<Self>> {
+        let select = alert_rules::table.order(alert_rules::id);
+        crate::db::log_query(&select);
+        select.load(connection)
+    }
+
+    pub fn enabled(connection: &DbConnection) -> QueryResult<Vec<Self>> {
+        let select = alert_rules::table
+            .filter(alert_rules::enabled.eq(true))
+            .order(alert_rules::id);
+        crate::db::log_query(&select);
+        select.load(connection)
+    }
+
+    pub fn by_id(connection: &DbConnection, id: i32) -> QueryResult<Option<Self>> {
+        let select = alert_rules::table.filter(alert_rules::id.eq(id)).limit(1);
+        crate::db::log_query(&select);
+        Ok(select.load(connection)?.into_iter().next())
+    }
+
+    /// Saves a new rule. Returns `None` when the name is taken.
+    pub fn create(connection: &DbConnection, rule: &NewAlertRule) -> QueryResult<Option<Self>> {
+        connection.transaction(|| {
+            if Self::by_name(connection, &rule.name)?.is_some() {
+                return Ok(None);
+            }
+            let insert = diesel::insert_into(alert_rules::table).values(rule);
+            crate::db::log_query(&insert);
+            insert.execute(connection)?;
+            Self::by_name(connection, &rule.name)
+        })
+    }
+
+    /// Replaces a rule's settings and forgets its state, so it is judged
+    /// afresh. Returns `None` when there is no such rule.
+    pub fn update(
+        connection: &DbConnection,
+        id: i32,
+        rule: &NewAlertRule,
+    ) -> QueryResult<Option<Self>> {
+        connection.transaction(|| {
+            let update =
+                diesel::update(alert_rules::table.filter(alert_rules::id.eq(id))).set(rule);
+            crate::db::log_query(&update);
+            if update.execute(connection)? == 0 {
+                return Ok(None);
+            }
+            AlertState::clear(connection, id)?;
+            Self::by_id(connection, id)
+        })
+    }
+
+    /// Returns whether there was a rule to delete.
+    pub fn delete(connection: &DbConnection, id: i32) -> QueryResult<bool> {
+        connection.transaction(|| {
+            // SQLite only cascades with foreign keys turned on, so the state
+            // is deleted here too.
+            AlertState::clear(connection, id)?;
+            let delete = diesel::delete(alert_rules::table.filter(alert_rules::id.eq(id)));
+            crate::db::log_query(&delete);
+            Ok(delete.execute(connection)? > 0)
+        })
+    }
+
+    fn by_name(connection: &DbConnection, name: &str) -> QueryResult<Option<Self>> {
+        let select = alert_rules::table
+            .filter(alert_rules::name.eq(name))
+            .limit(1);
+        crate::db::log_query(&select);
+        Ok(select.load(connection)?.into_iter().next())
+    }
+}
+
+impl AlertState {
+    pub fn since(&self) -> DateTime<Utc> {
+        Utc.from_utc_datetime(&self.since)
+    }
+
+    pub fn all(connection: &DbConnection) -> QueryResult<Vec<Self>> {
+        let select = alert_states::table.order((alert_states::rule_id, alert_states::sensor));
+        crate::db::log_query(&select);
+        select.load(connection)
+    }
+
+    /// Writes the state, replacing any the rule had for the sensor.
+    pub fn save(&self, connection: &DbConnection) -> QueryResult<()> {
+        connection.transaction(|| {
+            Self::remove(connection, self.rule_id, &self.sensor)?;
+            let insert = diesel::insert_into(alert_states::table).values(self);
+            crate::db::log_query(&insert);
+            insert.execute(connection)?;
+            Ok(())
+        })
+    }
+
+    pub fn remove(connection: &DbConnection, rule_id: i32, sensor: &str) -> QueryResult<usize> {
+        let delete = diesel::delete(
+            alert_states::table
+                .filter(alert_states::rule_id.eq(rule_id))
+                .filter(alert_states::sensor.eq(sensor)),
+        );
+        crate::db::log_query(&delete);
+        delete.execute(connection)
+    }
+
+    fn clear(connection: &DbConnection, rule_id: i32) -> QueryResult<usize> {
+        let delete = diesel::delete(alert_states::table.filter(alert_states::rule_id.eq(rule_id)));
+        crate::db::log_query(&delete);
+        delete.execute(connection)
+    }
+}
+
+impl AlertEvent {
+    /// The newest events first.
+    pub fn recent(
+        connection: &DbConnection,
+        rule_id: Option<i32>,
+        limit: i64,
+    ) -> QueryResult<Vec<Self>> {
+        let mut select = alert_history::table
+            .order((alert_history::time.desc(), alert_history::id.desc()))
+            .limit(limit)
+            .into_boxed();
+        if let Some(rule_id) = rule_id {
+            select = select.filter(alert_history::rule_id.eq(rule_id));
+        }
+        crate::db::log_query(&select);
+        select.load(connection)
+    }
+
+    pub fn insert(connection: &DbConnection, event: &NewAlertEvent) -> QueryResult<usize> {
+        let insert = diesel::insert_into(alert_history::table).values(event);
+        crate::db::log_query(&insert);
+        insert.execute(connection)
+    }
+}
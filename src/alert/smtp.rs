@@ -0,0 +1,153 @@
+use crate::config::{Smtp, SmtpTls};
+use native_tls::{TlsConnector, TlsStream};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+// this file covers sending plain text email over SMTP, which is all alerts
+// need
+
+/// How long to wait on the server before giving up.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+trait Stream: Read + Write {}
+impl<T: Read + Write> Stream for T {}
+
+/// # Session
+/// A conversation with the server: commands go out and replies are checked
+/// against the code the command expects.
+struct Session<S: Read + Write> {
+    stream: BufReader<S>,
+}
+
+impl<S: Read + Write> Session<S> {
+    /// Waits for the server's greeting.
+    fn greet(stream: S) -> anyhow::Result<Self> {
+        let mut session = Self::new(stream);
+        session.reply(220)?;
+        Ok(session)
+    }
+
+    fn new(stream: S) -> Self {
+        Self {
+            stream: BufReader::new(stream),
+        }
+    }
+
+    fn into_inner(self) -> S {
+        self.stream.into_inner()
+    }
+
+    /// Reads a reply, which may span several lines, and fails unless it has
+    /// the expected code.
+    fn reply(&mut self, expected: u16) -> anyhow::Result<String> {
+        let mut reply = String::new();
+        loop {
+            let mut line = String::new();
+            if self.stream.read_line(&mut line)? == 0 {
+                anyhow::bail!("the SMTP server hung up");
+            }
+            reply.push_str(&line);
+            // `250-` continues the reply; `250 ` ends it.
+            if line.len() < 4 || line.as_bytes()[3] != b'-' {
+                break;
+            }
+        }
+        match reply.get(..3).and_then(|code| code.parse::<u16>().ok()) {
+            Some(code) if code == expected => Ok(reply),
+            _ => Err(anyhow::anyhow!("the SMTP server said {}", reply.trim_end())),
+        }
+    }
+
+    fn command(&mut self, command: &str, expected: u16) -> anyhow::Result<String> {
+        let stream = self.stream.get_mut();
+        stream.write_all(command.as_bytes())?;
+        stream.write_all(b"\r\n")?;
+        stream.flush()?;
+        self.reply(expected)
+    }
+
+    fn ehlo(&mut self) -> anyhow::Result<String> {
+        self.command("EHLO localhost", 250)
+    }
+}
+
+fn tls(host: &str, stream: TcpStream) -> anyhow::Result<TlsStream<TcpStream>> {
+    TlsConnector::new()?
+        .connect(host, stream)
+        .map_err(|err| anyhow::anyhow!("TLS with {} failed: {}", host, err))
+}
+
+/// Subjects may only be ASCII; anything else is encoded (RFC 2047).
+fn encode_header(value: &str) -> String {
+    if value.is_ascii() {
+        value.to_string()
+    } else {
+        format!("=?UTF-8?B?{}?=", base64::encode(value))
+    }
+}
+
+/// # Message
+/// The headers and body, with line endings fixed and lines that start with a
+/// dot escaped.
+fn message(from: &str, to: &[&str], subject: &str, body: &str) -> String {
+    let mut message = format!(
+        "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nMIME-Version: 1.0\r\n\
+         Content-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: 8bit\r\n\r\n",
+        from,
+        to.join(", "),
+        encode_header(subject),
+        chrono::Utc::now().to_rfc2822(),
+    );
+    for line in body.lines() {
+        if line.starts_with('.') {
+            message.push('.');
+        }
+        message.push_str(line);
+        message.push_str("\r\n");
+    }
+    message.push('.');
+    message
+}
+
+/// # Send
+/// Emails `body` to `to`, which may list several addresses separated by
+/// commas.
+pub fn send(settings: &Smtp, to: &str, subject: &str, body: &str) -> anyhow::Result<()> {
+    let recipients: Vec<&str> = to
+        .split(',')
+        .map(str::trim)
+        .filter(|address| !address.is_empty())
+        .collect();
+    if recipients.is_empty() {
+        anyhow::bail!("no email address");
+    }
+    let tcp = TcpStream::connect((settings.host.as_str(), settings.port))?;
+    tcp.set_read_timeout(Some(TIMEOUT))?;
+    tcp.set_write_timeout(Some(TIMEOUT))?;
+    let mut session = match settings.tls {
+        SmtpTls::None => Session::<Box<dyn Stream>>::greet(Box::new(tcp))?,
+        SmtpTls::Tls => Session::<Box<dyn Stream>>::greet(Box::new(tls(&settings.host, tcp)?))?,
+        SmtpTls::Starttls => {
+            let mut plain = Session::greet(tcp)?;
+            plain.ehlo()?;
+            plain.command("STARTTLS", 220)?;
+            Session::<Box<dyn Stream>>::new(Box::new(tls(&settings.host, plain.into_inner())?))
+        }
+    };
+    session.ehlo()?;
+    if let Some(username) = &settings.username {
+        let password = settings.password.as_deref().unwrap_or("");
+        let credentials = base64::encode(format!("\0{}\0{}", username, password));
+        session.command(&format!("AUTH PLAIN {}", credentials), 235)?;
+    }
+    session.command(&format!("MAIL FROM:<{}>", settings.from), 250)?;
+    for recipient in &recipients {
+        session.command(&format!("RCPT TO:<{}>", recipient), 250)?;
+    }
+    session.command("DATA", 354)?;
+    session.command(&message(&settings.from, &recipients, subject, body), 250)?;
+    // The email is sent; the server hanging up early does not matter.
+    session.command("QUIT", 221).ok();
+    Ok(())
+}
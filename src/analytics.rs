@@ -0,0 +1,378 @@
+use crate::db::DbConnection;
+use crate::export::Units;
+use crate::rollup::{self, Resolution, Rollup};
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDate, TimeZone, Utc};
+use diesel::QueryResult;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+// this file covers heating and cooling degree-days, worked out from the
+// weather.gov readings the worker saves every run
+
+/// The outdoor temperature comes from the weather.gov reading.
+const WEATHER_GOV: &str = "weather.gov";
+/// The usual base temperature, in degrees F.
+pub const DEFAULT_BASE_F: f64 = 65.0;
+/// Seasons run from July 1 to June 30, so each holds one whole winter.
+const SEASON_START_MONTH: u32 = 7;
+
+/// # Degree Day Query
+/// Which days to add up, from `start_date` to `end_date` inclusive, in the
+/// server's time zone. Bases are in `units`. `compare` is how many earlier
+/// years to compare the same dates with.
+pub struct DegreeDayQuery {
+    pub start_date: NaiveDate,
+    pub end_date: NaiveDate,
+    pub units: Units,
+    pub heating_base: f64,
+    pub cooling_base: f64,
+    pub compare: i32,
+}
+
+/// # Day
+/// One day's mean outdoor temperature and degree-days.
+#[derive(Serialize)]
+pub struct Day {
+    date: NaiveDate,
+    mean: f64,
+    /// Hours with a reading. Missing for days older than the hourly
+    /// summaries, which are worked out from the daily summary of the UTC
+    /// day.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hours: Option<usize>,
+    heating: f64,
+    cooling: f64,
+}
+
+/// # Total
+/// Degree-days added up over a month, a season or the whole range.
+/// `missing_days` are days so far without any readings.
+#[derive(Serialize)]
+pub struct Total {
+    period: String,
+    start_date: NaiveDate,
+    end_date: NaiveDate,
+    days: usize,
+    missing_days: i64,
+    heating: f64,
+    cooling: f64,
+}
+
+/// # Comparison
+/// The same dates some years earlier, and how much this range differs from
+/// them.
+#[derive(Serialize)]
+pub struct Comparison {
+    years_ago: i32,
+    total: Total,
+    heating_difference: f64,
+    cooling_difference: f64,
+}
+
+/// # Degree Days
+/// The answer to a `DegreeDayQuery`.
+#[derive(Serialize)]
+pub struct DegreeDays {
+    units: Units,
+    heating_base: f64,
+    cooling_base: f64,
+    total: Total,
+    days: Vec<Day>,
+    months: Vec<Total>,
+    seasons: Vec<Total>,
+    previous_years: Vec<Comparison>,
+}
+
+fn round(value: f64) -> f64 {
+    (value * 10.0).round() / 10.0
+}
+
+/// The first moment of a day in the server's time zone.
+fn local_midnight(date: NaiveDate) -> DateTime<Utc> {
+    let midnight = date.and_hms_opt(0, 0, 0).unwrap_or_default();
+    match Local.from_local_datetime(&midnight).earliest() {
+        Some(time) => time.with_timezone(&Utc),
+        None => Utc.from_utc_datetime(&midnight),
+    }
+}
+
+/// The same date `years` earlier. February 29 becomes February 28.
+fn years_before(date: NaiveDate, years: i32) -> NaiveDate {
+    let year = date.year() - years;
+    date.with_year(year)
+        .or_else(|| NaiveDate::from_ymd_opt(year, date.month(), 28))
+        .unwrap_or(date)
+}
+
+/// # Daily Means
+/// The mean outdoor temperature, in degrees F, of every day in the range
+/// that has readings, and how many hours it has. Days are put together from
+/// the hourly summaries, or from the daily summaries where retention has
+/// deleted those.
+fn daily_means(
+    connection: &DbConnection,
+    start_date: NaiveDate,
+    end_date: NaiveDate,
+) -> QueryResult<BTreeMap<NaiveDate, (f64, Option<usize>)>> {
+    let mut means = BTreeMap::new();
+    let after_end = end_date + Duration::days(1);
+    // The first day the hourly summaries still have all of.
+    let hourly_from = match rollup::kept_since(Resolution::Hourly) {
+        Some(since) => start_date.max(since.with_timezone(&Local).date_naive() + Duration::days(1)),
+        None => start_date,
+    };
+
+    if start_date < hourly_from {
+        let until = after_end.min(hourly_from);
+        let daily = Rollup::query_sensor(
+            connection,
+            Resolution::Daily,
+            WEATHER_GOV,
+            &Utc.from_utc_datetime(&start_date.and_hms_opt(0, 0, 0).unwrap_or_default()),
+            &Utc.from_utc_datetime(&until.and_hms_opt(0, 0, 0).unwrap_or_default()),
+        )?;
+        for day in daily {
+            let fahrenheit = f64::from(day.temperature) / 10.0;
+            means.insert(day.time().date_naive(), (fahrenheit, None));
+        }
+    }
+
+    if hourly_from < after_end {
+        let hourly = Rollup::query_sensor(
+            connection,
+            Resolution::Hourly,
+            WEATHER_GOV,
+            &local_midnight(hourly_from),
+            &local_midnight(after_end),
+        )?;
+        let mut hours: BTreeMap<NaiveDate, Vec<f64>> = BTreeMap::new();
+        for hour in hourly {
+            hours
+                .entry(hour.time().with_timezone(&Local).date_naive())
+                .or_default()
+                .push(f64::from(hour.temperature) / 10.0);
+        }
+        for (date, temperatures) in hours {
+            let mean = temperatures.iter().sum::<f64>() / temperatures.len() as f64;
+            means.insert(date, (mean, Some(temperatures.len())));
+        }
+    }
+    Ok(means)
+}
+
+/// # Days
+/// Degree-days for every day in the range that has readings.
+fn days(
+    connection: &DbConnection,
+    query: &DegreeDayQuery,
+    years_ago: i32,
+) -> QueryResult<Vec<Day>> {
+    let start_date = years_before(query.start_date, years_ago);
+    let end_date = years_before(query.end_date, years_ago);
+    Ok(daily_means(connection, start_date, end_date)?
+        .into_iter()
+        .map(|(date, (fahrenheit, hours))| {
+            let mean = query.units.convert(fahrenheit);
+            Day {
+                date,
+                mean: round(mean),
+                hours,
+                heating: (query.heating_base - mean).max(0.0),
+                cooling: (mean - query.cooling_base).max(0.0),
+            }
+        })
+        .collect())
+}
+
+/// # Total
+/// Adds up the days from `start_date` to `end_date`. Days after today are
+/// not counted as missing.
+fn total(days: &[Day], period: String, start_date: NaiveDate, end_date: NaiveDate) -> Total {
+    let days: Vec<&Day> = days
+        .iter()
+        .filter(|day| day.date >= start_date && day.date <= end_date)
+        .collect();
+    let today = Local::now().date_naive();
+    let elapsed = (end_date.min(today) - start_date).num_days() + 1;
+    Total {
+        period,
+        start_date,
+        end_date,
+        days: days.len(),
+        missing_days: (elapsed - days.len() as i64).max(0),
+        heating: round(days.iter().map(|day| day.heating).sum()),
+        cooling: round(days.iter().map(|day| day.cooling).sum()),
+    }
+}
+
+/// # Periods
+/// Totals for each period the range touches, cut to the range. `period`
+/// gives the name and last day of the period a date is in.
+fn periods<F>(days: &[Day], start_date: NaiveDate, end_date: NaiveDate, period: F) -> Vec<Total>
+where
+    F: Fn(NaiveDate) -> (String, NaiveDate),
+{
+    let mut totals = Vec::new();
+    let mut date = start_date;
+    while date <= end_date {
+        let (name, last) = period(date);
+        let last = last.min(end_date);
+        totals.push(total(days, name, date, last));
+        date = last + Duration::days(1);
+    }
+    totals
+}
+
+/// Months, like `2020-08`.
+fn month(date: NaiveDate) -> (String, NaiveDate) {
+    let next = if date.month() == 12 {
+        NaiveDate::from_ymd_opt(date.year() + 1, 1, 1)
+    } else {
+        NaiveDate::from_ymd_opt(date.year(), date.month() + 1, 1)
+    };
+    let last = next.map_or(date, |next| next - Duration::days(1));
+    (date.format("%Y-%m").to_string(), last)
+}
+
+/// Seasons from July to June, like `2020-21`.
+fn season(date: NaiveDate) -> (String, NaiveDate) {
+    let year = if date.month() >= SEASON_START_MONTH {
+        date.year()
+    } else {
+        date.year() - 1
+    };
+    let last = NaiveDate::from_ymd_opt(year + 1, SEASON_START_MONTH, 1)
+        .map_or(date, |next| next - Duration::days(1));
+    (format!("{}-{:02}", year, (year + 1).rem_euclid(100)), last)
+}
+
+/// # Degree Days
+/// Works out heating and cooling degree-days for each day from its mean
+/// outdoor temperature, and adds them up by month, by season and over the
+/// whole range, with the same range in earlier years to compare.
+pub fn degree_days(connection: &DbConnection, query: &DegreeDayQuery) -> QueryResult<DegreeDays> {
+    let (start_date, end_date) = (query.start_date, query.end_date);
+    let days = days(connection, query, 0)?;
+    let this = total(&days, String::from("total"), start_date, end_date);
+
+    let mut previous_years = Vec::new();
+    for years_ago in 1..=query.compare {
+        let earlier = self::days(connection, query, years_ago)?;
+        let (start_date, end_date) = (
+            years_before(start_date, years_ago),
+            years_before(end_date, years_ago),
+        );
+        let total = total(&earlier, String::from("total"), start_date, end_date);
+        previous_years.push(Comparison {
+            years_ago,
+            heating_difference: round(this.heating - total.heating),
+            cooling_difference: round(this.cooling - total.cooling),
+            total,
+        });
+    }
+
+    Ok(DegreeDays {
+        units: query.units,
+        heating_base: query.heating_base,
+        cooling_base: query.cooling_base,
+        months: periods(&days, start_date, end_date, month),
+        seasons: periods(&days, start_date, end_date, season),
+        total: this,
+        days: days
+            .into_iter()
+            .map(|day| Day {
+                heating: round(day.heating),
+                cooling: round(day.cooling),
+                ..day
+            })
+            .collect(),
+        previous_years,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap()
+    }
+
+    fn day(date: NaiveDate, mean: f64) -> Day {
+        Day {
+            date,
+            mean,
+            hours: Some(24),
+            heating: (DEFAULT_BASE_F - mean).max(0.0),
+            cooling: (mean - DEFAULT_BASE_F).max(0.0),
+        }
+    }
+
+    #[test]
+    fn leap_days_fall_back_to_february_28() {
+        assert_eq!(years_before(date(2024, 2, 29), 1), date(2023, 2, 28));
+        assert_eq!(years_before(date(2024, 2, 29), 4), date(2020, 2, 29));
+        assert_eq!(years_before(date(2021, 8, 15), 2), date(2019, 8, 15));
+    }
+
+    #[test]
+    fn months_end_on_their_last_day() {
+        assert_eq!(
+            month(date(2020, 2, 10)),
+            (String::from("2020-02"), date(2020, 2, 29))
+        );
+        assert_eq!(
+            month(date(2020, 12, 1)),
+            (String::from("2020-12"), date(2020, 12, 31))
+        );
+    }
+
+    #[test]
+    fn seasons_run_from_july_to_june() {
+        assert_eq!(
+            season(date(2020, 7, 1)),
+            (String::from("2020-21"), date(2021, 6, 30))
+        );
+        assert_eq!(
+            season(date(2021, 6, 30)),
+            (String::from("2020-21"), date(2021, 6, 30))
+        );
+        assert_eq!(
+            season(date(1999, 12, 25)),
+            (String::from("1999-00"), date(2000, 6, 30))
+        );
+    }
+
+    #[test]
+    fn periods_are_cut_to_the_range() {
+        let days = vec![day(date(2020, 6, 30), 50.0), day(date(2020, 7, 1), 70.0)];
+        let seasons = periods(&days, date(2020, 6, 15), date(2020, 7, 10), season);
+        let names: Vec<&str> = seasons.iter().map(|total| total.period.as_str()).collect();
+        assert_eq!(names, vec!["2019-20", "2020-21"]);
+        assert_eq!(seasons[0].start_date, date(2020, 6, 15));
+        assert_eq!(seasons[0].end_date, date(2020, 6, 30));
+        assert_eq!(seasons[1].start_date, date(2020, 7, 1));
+        assert_eq!(seasons[1].end_date, date(2020, 7, 10));
+        assert_eq!(seasons[0].heating, 15.0);
+        assert_eq!(seasons[1].cooling, 5.0);
+    }
+
+    #[test]
+    fn totals_count_missing_days() {
+        let days = vec![
+            day(date(2020, 1, 1), 30.0),
+            day(date(2020, 1, 3), 40.5),
+            day(date(2020, 1, 5), 70.0),
+        ];
+        let total = total(
+            &days,
+            String::from("total"),
+            date(2020, 1, 1),
+            date(2020, 1, 4),
+        );
+        assert_eq!(total.days, 2);
+        assert_eq!(total.missing_days, 2);
+        assert_eq!(total.heating, 59.5);
+        assert_eq!(total.cooling, 0.0);
+    }
+}
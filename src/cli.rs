@@ -0,0 +1,158 @@
+use crate::{ecobee, export, import, web, worker, NOW_RES};
+use chrono::Utc;
+use std::io::BufRead;
+
+// this file covers the maintenance commands, so operators do not have to curl
+// the HTTP API or write SQL
+
+pub const USAGE: &str = "\
+Usage: therm_hub [--config <file>] [--print-config] [command]
+
+Commands:
+  serve                Run migrations, check the remote APIs, then start the
+                       worker and the web server (the default)
+  migrate              Run database migrations and exit
+  poll-once            Poll Ecobee and weather.gov once, store the readings
+                       and print them
+  ecobee pair          Link an Ecobee account with a PIN entered at ecobee.com
+  ecobee token status  Show whether an Ecobee token is saved and when it
+                       expires
+  photos sync          Refresh the background photos and wait for it
+  export [options]     Write readings as CSV, NDJSON or Parquet
+                       (--start-date, --end-date, --sensors, --format,
+                       --units, --output)
+  import [options]     Read readings written by export back in, skipping ones
+                       already stored (--input, --format)
+  help                 Show this message
+";
+
+/// # Run
+/// Runs a command other than `serve`.
+pub fn run(args: &[String]) -> anyhow::Result<()> {
+    let words: Vec<&str> = args.iter().map(String::as_str).collect();
+    match words.as_slice() {
+        ["migrate"] => migrate(),
+        ["poll-once"] => poll_once(),
+        ["ecobee", "pair"] => ecobee_pair(),
+        ["ecobee", "token", "status"] => ecobee_token_status(),
+        ["photos", "sync"] => photos_sync(),
+        ["export", ..] => export::run_cli(&args[1..]),
+        ["import", ..] => import::run_cli(&args[1..]),
+        _ => anyhow::bail!(
+            "unknown command `{}`; run `therm_hub help` for the list",
+            args.join(" ")
+        ),
+    }
+}
+
+fn migrate() -> anyhow::Result<()> {
+    if !crate::run_migrations() {
+        anyhow::bail!("migrations failed");
+    }
+    println!("Database is up to date");
+    Ok(())
+}
+
+/// # Poll Once
+/// Does one run of the worker and prints what it read. Fails if a remote API
+/// could not be polled.
+fn poll_once() -> anyhow::Result<()> {
+    let ok = worker::check();
+    if let Ok(now) = NOW_RES.read() {
+        for thermostat in &now.thermostats {
+            let temperature = match thermostat.fahrenheit() {
+                Some(fahrenheit) => format!("{:.1}°F", fahrenheit),
+                None => String::from("-"),
+            };
+            let humidity = match thermostat.humidity() {
+                Some(humidity) => format!("{}%", humidity),
+                None => String::from("-"),
+            };
+            println!(
+                "{}  {}  {}  {}",
+                thermostat.time().to_rfc3339(),
+                thermostat.name,
+                temperature,
+                humidity
+            );
+        }
+    }
+    if !ok {
+        anyhow::bail!("not every remote API could be polled");
+    }
+    Ok(())
+}
+
+/// # Ecobee Pair
+/// The command line version of `/install/1` and `/install/2`: shows a PIN,
+/// waits for it to be entered at ecobee.com, then saves the token.
+fn ecobee_pair() -> anyhow::Result<()> {
+    if crate::config::get().ecobee.is_none() {
+        anyhow::bail!("Ecobee is not configured; set ecobee.client_id first");
+    }
+    let install = request_pin()?;
+    println!("PIN: {}", install.ecobee_pin);
+    println!("Enter it at ecobee.com under My Apps > Add Application, then press Enter.");
+    let mut line = String::new();
+    std::io::stdin().lock().read_line(&mut line)?;
+
+    let response = ecobee::get_token_blocking(&install.code, ecobee::GRANT_PIN)?;
+    let db = crate::establish_connection();
+    match ecobee::save_token(&response.to_token(), &db) {
+        Some(token) => {
+            println!("Paired; the token expires {}", token.expires().to_rfc3339());
+            Ok(())
+        }
+        None => anyhow::bail!("could not save the Ecobee token"),
+    }
+}
+
+#[tokio::main]
+async fn request_pin() -> anyhow::Result<ecobee::InstallResponse> {
+    ecobee::install().await
+}
+
+fn ecobee_token_status() -> anyhow::Result<()> {
+    let db = crate::establish_connection();
+    match ecobee::saved_token(&db) {
+        None => println!("No Ecobee token is saved; run `therm_hub ecobee pair`"),
+        Some(token) => {
+            let expires = token.expires();
+            let minutes = (expires - Utc::now()).num_minutes();
+            if minutes < 0 {
+                println!(
+                    "Token expired {} ({} minutes ago); the worker refreshes it on its next run",
+                    expires.to_rfc3339(),
+                    -minutes
+                );
+            } else {
+                println!(
+                    "Token expires {} (in {} minutes)",
+                    expires.to_rfc3339(),
+                    minutes
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// # Photos Sync
+/// Refreshes the background photos and prints the finished job.
+fn photos_sync() -> anyhow::Result<()> {
+    if crate::config::get().photos.is_none() {
+        anyhow::bail!("photos are not configured; set photos.cache_dir first");
+    }
+    let job = web::sync_photos().ok_or_else(|| anyhow::anyhow!("could not start a refresh"))?;
+    println!(
+        "Downloaded {}, skipped {}, failed {}, removed {}",
+        job.downloaded, job.skipped, job.failed, job.removed
+    );
+    for error in &job.errors {
+        println!("  {}", error);
+    }
+    match job.state {
+        web::JobState::Failed => anyhow::bail!("the refresh failed"),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,300 @@
+use crate::db::DbConnection;
+use crate::rollup::{Resolution, Rollup};
+use chrono::{DateTime, Duration, Utc};
+use diesel::QueryResult;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+// this file covers what is worked out from a hygrostat's temperature and
+// relative humidity: dew point, heat index, absolute humidity, how
+// comfortable it is, and whether it has been damp long enough to grow mold
+
+/// Magnus formula constants (Alduchov and Eskridge), for degrees C.
+const MAGNUS_A: f64 = 17.625;
+const MAGNUS_B: f64 = 243.04;
+/// Comfortable indoor temperatures, in degrees F...
+const COMFORT_MIN_F: f64 = 68.0;
+const COMFORT_MAX_F: f64 = 78.0;
+/// ...and relative humidities, in percent.
+const COMFORT_MIN_RH: f64 = 30.0;
+const COMFORT_MAX_RH: f64 = 60.0;
+/// Dew points at or above this, in degrees F, feel muggy.
+const MUGGY_DEW_POINT_F: f64 = 60.0;
+/// A sensor is at risk of mold once its dew point has been at or above
+/// `MUGGY_DEW_POINT_F` for this many hours in a row.
+const MOLD_HOURS: i64 = 24;
+/// How far back hours are counted.
+const MOLD_WINDOW_DAYS: i64 = 7;
+
+fn round(value: f64) -> f64 {
+    (value * 10.0).round() / 10.0
+}
+
+fn celsius(fahrenheit: f64) -> f64 {
+    (fahrenheit - 32.0) * 5.0 / 9.0
+}
+
+fn fahrenheit(celsius: f64) -> f64 {
+    celsius * 9.0 / 5.0 + 32.0
+}
+
+/// # Dew Point
+/// The temperature, in degrees F, the air would have to cool to for water
+/// to condense, by the Magnus formula.
+pub fn dew_point(temperature_f: f64, humidity: f64) -> Option<f64> {
+    if humidity <= 0.0 || humidity > 100.0 {
+        return None;
+    }
+    let t = celsius(temperature_f);
+    let gamma = (humidity / 100.0).ln() + MAGNUS_A * t / (MAGNUS_B + t);
+    Some(fahrenheit(MAGNUS_B * gamma / (MAGNUS_A - gamma)))
+}
+
+/// # Heat Index
+/// How hot it feels, in degrees F, using the National Weather Service's
+/// method: Steadman's simple formula, or the Rothfusz regression with its
+/// adjustments once that comes to 80°F or more.
+pub fn heat_index(t: f64, rh: f64) -> f64 {
+    let simple = 0.5 * (t + 61.0 + (t - 68.0) * 1.2 + rh * 0.094);
+    if (simple + t) / 2.0 < 80.0 {
+        return simple;
+    }
+    let mut index = -42.379 + 2.049_015_23 * t + 10.143_331_27 * rh
+        - 0.224_755_41 * t * rh
+        - 0.006_837_83 * t * t
+        - 0.054_817_17 * rh * rh
+        + 0.001_228_74 * t * t * rh
+        + 0.000_852_82 * t * rh * rh
+        - 0.000_001_99 * t * t * rh * rh;
+    if rh < 13.0 && (80.0..=112.0).contains(&t) {
+        index -= (13.0 - rh) / 4.0 * ((17.0 - (t - 95.0).abs()) / 17.0).sqrt();
+    } else if rh > 85.0 && (80.0..=87.0).contains(&t) {
+        index += (rh - 85.0) / 10.0 * (87.0 - t) / 5.0;
+    }
+    index
+}
+
+/// # Absolute Humidity
+/// Grams of water in a cubic meter of air.
+pub fn absolute_humidity(temperature_f: f64, humidity: f64) -> f64 {
+    let t = celsius(temperature_f);
+    let saturation_hpa = 6.112 * (17.67 * t / (t + 243.5)).exp();
+    saturation_hpa * humidity * 2.1674 / (273.15 + t)
+}
+
+/// # Class
+/// How comfortable a room is, with temperature before humidity.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Class {
+    Cold,
+    Hot,
+    Dry,
+    Humid,
+    Comfortable,
+}
+
+impl Class {
+    fn of(temperature_f: f64, humidity: f64, dew_point_f: f64, heat_index_f: f64) -> Self {
+        if temperature_f < COMFORT_MIN_F {
+            Class::Cold
+        } else if heat_index_f > COMFORT_MAX_F {
+            Class::Hot
+        } else if humidity < COMFORT_MIN_RH {
+            Class::Dry
+        } else if humidity > COMFORT_MAX_RH || dew_point_f >= MUGGY_DEW_POINT_F {
+            Class::Humid
+        } else {
+            Class::Comfortable
+        }
+    }
+}
+
+/// # Comfort
+/// Everything worked out from one temperature and humidity. Temperatures are
+/// in degrees F.
+#[derive(Clone, Debug, Serialize)]
+pub struct Comfort {
+    pub dew_point_f: f64,
+    pub heat_index_f: f64,
+    pub absolute_humidity_g_m3: f64,
+    pub class: Class,
+}
+
+impl Comfort {
+    /// # New
+    /// Works out comfort for a sensor that measures both temperature and
+    /// humidity. Anything else has none.
+    pub fn new(temperature_f: Option<f64>, humidity: Option<i32>) -> Option<Self> {
+        let (temperature_f, humidity) = (temperature_f?, f64::from(humidity?));
+        let dew_point_f = dew_point(temperature_f, humidity)?;
+        let heat_index_f = heat_index(temperature_f, humidity);
+        Some(Self {
+            dew_point_f: round(dew_point_f),
+            heat_index_f: round(heat_index_f),
+            absolute_humidity_g_m3: round(absolute_humidity(temperature_f, humidity)),
+            class: Class::of(temperature_f, humidity, dew_point_f, heat_index_f),
+        })
+    }
+}
+
+/// # Mold Risk
+/// How long a hygrostat's dew point has been muggy, counting back from its
+/// latest hour, up to a week.
+#[derive(Clone, Debug, Serialize)]
+pub struct MoldRisk {
+    pub sensor: String,
+    pub at_risk: bool,
+    pub hours: i64,
+    /// The start of the first muggy hour, if the latest one is.
+    pub since: Option<DateTime<Utc>>,
+}
+
+/// # Mold Risks
+/// Checks every hygrostat's hourly summaries for the last week. A sensor is
+/// at risk once its dew point has been at or above 60°F for 24 hours in a
+/// row; a missing hour ends the run.
+pub fn mold_risks(connection: &DbConnection) -> QueryResult<Vec<MoldRisk>> {
+    let end_date = Utc::now();
+    let start_date = end_date - Duration::days(MOLD_WINDOW_DAYS);
+    let hours = Rollup::query_page(
+        connection,
+        Resolution::Hourly,
+        &start_date,
+        &end_date,
+        None,
+        i64::MAX,
+    )?;
+    // Dew points by sensor, oldest first.
+    let mut sensors: BTreeMap<String, Vec<(DateTime<Utc>, f64)>> = BTreeMap::new();
+    for hour in hours {
+        // Humidity-only sensors have no dew point.
+        let dew_point_f = match Comfort::new(hour.fahrenheit(), hour.humidity()) {
+            Some(comfort) => comfort.dew_point_f,
+            None => continue,
+        };
+        sensors
+            .entry(hour.name.clone())
+            .or_default()
+            .push((hour.time(), dew_point_f));
+    }
+
+    Ok(sensors
+        .into_iter()
+        .map(|(sensor, hours)| {
+            let (hours, since) = muggy_run(&hours);
+            MoldRisk {
+                sensor,
+                at_risk: hours >= MOLD_HOURS,
+                hours,
+                since,
+            }
+        })
+        .collect())
+}
+
+/// # Muggy Run
+/// How many hours in a row, counting back from the last, have a muggy dew
+/// point, and when the first of them started. `hours` are hour starts and
+/// dew points, oldest first.
+fn muggy_run(hours: &[(DateTime<Utc>, f64)]) -> (i64, Option<DateTime<Utc>>) {
+    let mut since: Option<DateTime<Utc>> = None;
+    let mut muggy = 0;
+    for (time, dew_point_f) in hours.iter().rev() {
+        if *dew_point_f < MUGGY_DEW_POINT_F {
+            break;
+        }
+        if let Some(since) = since {
+            if since - *time != Duration::hours(1) {
+                break;
+            }
+        }
+        since = Some(*time);
+        muggy += 1;
+    }
+    (muggy, since)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn close(actual: f64, expected: f64) -> bool {
+        (actual - expected).abs() < 0.05
+    }
+
+    #[test]
+    fn dew_point_matches_the_magnus_formula() {
+        assert!(close(dew_point(68.0, 50.0).unwrap(), 48.67));
+        // Saturated air is at its dew point.
+        assert!(close(dew_point(72.0, 100.0).unwrap(), 72.0));
+        assert_eq!(dew_point(72.0, 0.0), None);
+        assert_eq!(dew_point(72.0, 101.0), None);
+    }
+
+    #[test]
+    fn heat_index_matches_the_nws() {
+        assert!(close(heat_index(90.0, 70.0), 105.92));
+        // Cool enough for Steadman's formula.
+        assert!(close(heat_index(70.0, 50.0), 69.05));
+        // Dry heat is adjusted down, and humid warmth up.
+        assert!(close(heat_index(100.0, 10.0), 94.12));
+        assert!(close(heat_index(85.0, 90.0), 101.78));
+    }
+
+    #[test]
+    fn absolute_humidity_in_grams() {
+        assert!(close(absolute_humidity(68.0, 50.0), 8.64));
+    }
+
+    #[test]
+    fn comfort_classes() {
+        let class = |f: f64, rh: i32| Comfort::new(Some(f), Some(rh)).unwrap().class;
+        assert_eq!(class(72.0, 45), Class::Comfortable);
+        assert_eq!(class(64.0, 45), Class::Cold);
+        assert_eq!(class(85.0, 45), Class::Hot);
+        assert_eq!(class(72.0, 20), Class::Dry);
+        assert_eq!(class(72.0, 65), Class::Humid);
+        assert!(Comfort::new(None, Some(50)).is_none());
+        assert!(Comfort::new(Some(72.0), None).is_none());
+    }
+
+    fn hours(dew_points: &[(i64, f64)]) -> Vec<(DateTime<Utc>, f64)> {
+        dew_points
+            .iter()
+            .map(|(hour, dew_point_f)| (Utc.timestamp_opt(hour * 3600, 0).unwrap(), *dew_point_f))
+            .collect()
+    }
+
+    #[test]
+    fn muggy_runs_count_back_from_the_last_hour() {
+        let run = hours(&[(0, 65.0), (1, 55.0), (2, 60.0), (3, 62.0), (4, 61.0)]);
+        assert_eq!(
+            muggy_run(&run),
+            (3, Some(Utc.timestamp_opt(2 * 3600, 0).unwrap()))
+        );
+    }
+
+    #[test]
+    fn muggy_runs_end_at_a_missing_hour() {
+        let run = hours(&[(0, 65.0), (1, 65.0), (3, 65.0), (4, 65.0)]);
+        assert_eq!(
+            muggy_run(&run),
+            (2, Some(Utc.timestamp_opt(3 * 3600, 0).unwrap()))
+        );
+    }
+
+    #[test]
+    fn no_muggy_run_when_the_last_hour_is_dry() {
+        assert_eq!(muggy_run(&hours(&[(0, 65.0), (1, 50.0)])), (0, None));
+        assert_eq!(muggy_run(&[]), (0, None));
+    }
+
+    #[test]
+    fn a_day_of_muggy_hours_is_a_mold_risk() {
+        let run: Vec<(i64, f64)> = (0..30).map(|hour| (hour, 61.0)).collect();
+        let (muggy, _) = muggy_run(&hours(&run));
+        assert!(muggy >= MOLD_HOURS);
+    }
+}
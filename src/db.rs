@@ -0,0 +1,157 @@
+use diesel::query_builder::QueryFragment;
+use diesel::r2d2::{ConnectionManager, HandleError, Pool, PoolError, PooledConnection};
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::time::Duration;
+
+// this file covers the database connection pool shared by the web server and
+// the worker
+
+/// The database the binary is built for: Postgres, or SQLite with the
+/// `sqlite` feature.
+#[cfg(not(feature = "sqlite"))]
+pub type DbConnection = diesel::PgConnection;
+#[cfg(not(feature = "sqlite"))]
+pub type Backend = diesel::pg::Pg;
+#[cfg(feature = "sqlite")]
+pub type DbConnection = diesel::SqliteConnection;
+#[cfg(feature = "sqlite")]
+pub type Backend = diesel::sqlite::Sqlite;
+
+pub type Connection = PooledConnection<ConnectionManager<DbConnection>>;
+
+lazy_static! {
+    static ref POOL: Pool<ConnectionManager<DbConnection>> = build();
+}
+
+#[derive(Serialize)]
+pub struct PoolStats {
+    /// Open connections, idle or not.
+    pub connections: u32,
+    pub idle: u32,
+    pub in_use: u32,
+    pub max_size: u32,
+}
+
+/// Logs connections the pool could not open, which it otherwise keeps quiet
+/// about while retrying.
+#[derive(Debug)]
+struct LogErrors;
+
+impl HandleError<diesel::r2d2::Error> for LogErrors {
+    fn handle_error(&self, error: diesel::r2d2::Error) {
+        crate::metrics::inc(crate::metrics::DB_CONNECTION_ERRORS, &[]);
+        tracing::error!("Database connection failed: {}", error);
+    }
+}
+
+/// Sets up every SQLite connection the pool opens: waits for locks instead of
+/// failing, and uses the write-ahead log so readers do not block the worker.
+#[cfg(feature = "sqlite")]
+#[derive(Debug)]
+struct SqlitePragmas;
+
+#[cfg(feature = "sqlite")]
+impl diesel::r2d2::CustomizeConnection<DbConnection, diesel::r2d2::Error> for SqlitePragmas {
+    fn on_acquire(&self, connection: &mut DbConnection) -> Result<(), diesel::r2d2::Error> {
+        use diesel::connection::SimpleConnection;
+        connection
+            .batch_execute(
+                "PRAGMA busy_timeout = 5000; PRAGMA journal_mode = WAL; PRAGMA foreign_keys = ON;",
+            )
+            .map_err(diesel::r2d2::Error::QueryError)
+    }
+}
+
+/// # Check URL
+/// Makes sure `DATABASE_URL` is for the database this binary is built for:
+/// `postgres://` normally, or `sqlite://` or a file path with the `sqlite`
+/// feature.
+pub fn check_url(database_url: &str) -> Result<(), String> {
+    let is_postgres =
+        database_url.starts_with("postgres://") || database_url.starts_with("postgresql://");
+    if cfg!(feature = "sqlite") && is_postgres {
+        Err("DATABASE_URL is a Postgres URL, but this build uses SQLite".to_string())
+    } else if !cfg!(feature = "sqlite") && !is_postgres {
+        Err(
+            "DATABASE_URL is not a Postgres URL; build with --features sqlite for SQLite"
+                .to_string(),
+        )
+    } else {
+        Ok(())
+    }
+}
+
+/// The database URL as the driver expects it. SQLite takes a file path, so a
+/// `sqlite://` prefix is dropped.
+fn url(database_url: &str) -> String {
+    match database_url.strip_prefix("sqlite://") {
+        Some(path) if cfg!(feature = "sqlite") => path.to_string(),
+        _ => database_url.to_string(),
+    }
+}
+
+/// # Build
+/// Creates the pool from the `[database]` settings. No connection is opened
+/// until one is needed, so a database that is down is reported where it is
+/// used instead of here.
+fn build() -> Pool<ConnectionManager<DbConnection>> {
+    let settings = crate::config::get().database.clone();
+    let database_url = url(&settings.url);
+    let max_size = settings.pool_size.max(1);
+    let min_idle = settings.pool_min_idle.min(max_size);
+    let connect_timeout = settings.connect_timeout_seconds;
+    let idle_timeout = settings.idle_timeout_seconds;
+    let builder = Pool::builder()
+        .max_size(max_size)
+        .min_idle(Some(min_idle))
+        .connection_timeout(Duration::from_secs(connect_timeout))
+        .idle_timeout(Some(Duration::from_secs(idle_timeout)))
+        .error_handler(Box::new(LogErrors));
+    #[cfg(feature = "sqlite")]
+    let builder = builder.connection_customizer(Box::new(SqlitePragmas));
+    builder.build_unchecked(ConnectionManager::new(database_url))
+}
+
+/// # Connection
+/// Checks a connection out of the pool. It goes back when it is dropped.
+/// Blocks, so async code should use `run` instead.
+pub fn connection() -> Result<Connection, PoolError> {
+    POOL.get()
+}
+
+/// # Run
+/// Runs database work on the blocking thread pool, so it does not hold up
+/// the async executor, with a connection from the pool.
+pub async fn run<T, F>(work: F) -> anyhow::Result<T>
+where
+    F: FnOnce(&DbConnection) -> T + Send + 'static,
+    T: Send + 'static,
+{
+    // Queries are logged under the request that made them.
+    let span = tracing::Span::current();
+    let result = tokio::task::spawn_blocking(move || {
+        let _span = span.entered();
+        let connection = connection()?;
+        Ok::<_, PoolError>(work(&connection))
+    })
+    .await?;
+    Ok(result?)
+}
+
+/// # Log Query
+/// Logs a query's SQL under the `sql` target. Off unless the log filter turns
+/// it on, like `info,sql=debug`; the SQL is only formatted when it is.
+pub fn log_query<T: QueryFragment<Backend>>(query: &T) {
+    tracing::debug!(target: "sql", "{}", diesel::debug_query::<Backend, _>(query));
+}
+
+pub fn stats() -> PoolStats {
+    let state = POOL.state();
+    PoolStats {
+        connections: state.connections,
+        idle: state.idle_connections,
+        in_use: state.connections - state.idle_connections,
+        max_size: POOL.max_size(),
+    }
+}
@@ -6,8 +6,8 @@ use serde::Deserialize;
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct InstallResponse {
-    ecobee_pin: String,
-    code: String,
+    pub ecobee_pin: String,
+    pub code: String,
 }
 
 // RK: The `rename_all=camelCase` above will make InstallResponse have `ecobeePin` in the JSON
@@ -36,7 +36,10 @@ pub async fn install() -> anyhow::Result<InstallResponse> {
 
 #[cfg(any(test, feature = "offline"))]
 pub async fn install() -> anyhow::Result<InstallResponse> {
-    let client_id = std::env::var("ECOBEE_CLIENT_ID").unwrap();
+    let client_id = match &crate::config::get().ecobee {
+        Some(ecobee) => ecobee.client_id.clone(),
+        None => anyhow::bail!("Ecobee is not configured"),
+    };
     let url = format!(
         "https://api.ecobee.com/authorize?response_type=ecobeePin&client_id={}&scope=smartRead",
         client_id
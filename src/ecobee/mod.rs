@@ -1,8 +1,11 @@
 // Re-export everything used in other modules, so implementors do not need to know the module structure.
 pub use install::install;
+pub use install::InstallResponse;
 pub use reading::read;
 pub use reading::Reading;
 pub use token::get_from_remote as get_token;
+pub use token::get_from_remote_blocking as get_token_blocking;
+pub use token::get_token as saved_token;
 pub use token::GrantType::PIN as GRANT_PIN;
 pub use token::Token;
 pub use token::{current_token, save_token};
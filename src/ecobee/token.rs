@@ -1,12 +1,10 @@
+use crate::db::DbConnection;
 #[cfg(not(any(test, feature = "offline")))]
 use crate::parse;
 use crate::schema::ecobee_token;
-use chrono::{NaiveDateTime, Utc};
+use chrono::{DateTime, NaiveDateTime, Utc};
 use diesel::prelude::*;
-use diesel::PgConnection;
 use serde::{Deserialize, Serialize};
-#[cfg(not(any(test, feature = "offline")))]
-use std::env;
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct TokenResponse {
@@ -36,6 +34,10 @@ impl Token {
     pub fn is_expired(&self) -> bool {
         true
     }
+
+    pub fn expires(&self) -> DateTime<Utc> {
+        DateTime::<Utc>::from_utc(self.expires, Utc)
+    }
 }
 
 pub enum GrantType {
@@ -55,16 +57,14 @@ impl TokenResponse {
     }
 }
 
-pub fn get_token(db: &PgConnection) -> Option<Token> {
+pub fn get_token(db: &DbConnection) -> Option<Token> {
     use crate::schema::ecobee_token::dsl;
 
     let select = dsl::ecobee_token
         .select((dsl::id, dsl::access_token, dsl::refresh_token, dsl::expires))
         .limit(1);
 
-    if cfg!(feature = "queries") {
-        crate::log_message(&diesel::debug_query::<diesel::pg::Pg, _>(&select).to_string());
-    }
+    crate::db::log_query(&select);
 
     match select.load::<Token>(db) {
         Ok(query_result) => match query_result.first() {
@@ -75,7 +75,7 @@ pub fn get_token(db: &PgConnection) -> Option<Token> {
     }
 }
 
-pub fn save_token(token: &Token, db: &PgConnection) -> Option<Token> {
+pub fn save_token(token: &Token, db: &DbConnection) -> Option<Token> {
     use crate::schema::ecobee_token::dsl;
 
     match get_token(db) {
@@ -86,11 +86,10 @@ pub fn save_token(token: &Token, db: &PgConnection) -> Option<Token> {
                 refresh_token: token.refresh_token.clone(),
             });
 
-            if cfg!(feature = "queries") {
-                crate::log_message(&diesel::debug_query::<diesel::pg::Pg, _>(&insert).to_string());
-            }
+            crate::db::log_query(&insert);
 
-            insert.get_result(db).ok()
+            insert.execute(db).ok()?;
+            get_token(db)
         }
         Some(db_token) => {
             let update = diesel::update(&db_token).set((
@@ -99,10 +98,9 @@ pub fn save_token(token: &Token, db: &PgConnection) -> Option<Token> {
                 dsl::refresh_token.eq(token.refresh_token.clone()),
             ));
 
-            if cfg!(feature = "queries") {
-                crate::log_message(&diesel::debug_query::<diesel::pg::Pg, _>(&update).to_string());
-            }
-            update.get_result::<Token>(db).ok()
+            crate::db::log_query(&update);
+            update.execute(db).ok()?;
+            get_token(db)
         }
     }
 }
@@ -131,7 +129,10 @@ pub async fn get_from_remote(code: &str, grant_type: GrantType) -> anyhow::Resul
         GrantType::PIN => "ecobeePin",
         GrantType::RefreshToken => "refresh_token",
     };
-    let client_id = env::var("ECOBEE_CLIENT_ID").unwrap();
+    let client_id = match &crate::config::get().ecobee {
+        Some(ecobee) => ecobee.client_id.clone(),
+        None => anyhow::bail!("Ecobee is not configured"),
+    };
     let url = format!(
         "https://api.ecobee.com/token?grant_type={}&code={}&client_id={}",
         grant_type, code, client_id
@@ -150,14 +151,24 @@ pub async fn get_from_remote(code: &str, grant_type: GrantType) -> anyhow::Resul
 
 /// # Current Token
 /// Retrieves a current token from the DB or refreshes and saves the token from the remote API.
-pub fn current_token(db: &PgConnection) -> Option<Token> {
+pub fn current_token(db: &DbConnection) -> Option<Token> {
     match get_token(db) {
         None => None,
         Some(token) => {
             if token.is_expired() {
                 match get_from_remote_blocking(&token.refresh_token, GrantType::RefreshToken) {
-                    Err(_) => None,
+                    Err(_) => {
+                        crate::metrics::inc(
+                            crate::metrics::TOKEN_REFRESHES,
+                            &[("result", "failure")],
+                        );
+                        None
+                    }
                     Ok(response) => {
+                        crate::metrics::inc(
+                            crate::metrics::TOKEN_REFRESHES,
+                            &[("result", "success")],
+                        );
                         save_token(&response.to_token(), db);
                         Some(response.to_token())
                     }
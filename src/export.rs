@@ -0,0 +1,493 @@
+use crate::db::DbConnection;
+use crate::Thermostat;
+use chrono::{DateTime, Local, SecondsFormat, Utc};
+use serde::ser::{SerializeStruct, Serializer};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+
+// this file covers exporting readings for spreadsheets and notebooks
+
+/// Rows read from the database per round trip. Also the Parquet row group size.
+const CHUNK: i64 = 10000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Csv,
+    Ndjson,
+    Parquet,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "csv" => Some(Format::Csv),
+            "ndjson" | "jsonl" => Some(Format::Ndjson),
+            "parquet" => Some(Format::Parquet),
+            _ => None,
+        }
+    }
+
+    /// # From Accept
+    /// Picks a format from an `Accept` header. Clients that accept anything
+    /// get CSV.
+    pub fn from_accept(accept: &str) -> Option<Self> {
+        for media_range in accept.split(',') {
+            let media_type = media_range.split(';').next().unwrap_or("").trim();
+            match media_type {
+                "text/csv" | "text/*" | "*/*" => return Some(Format::Csv),
+                "application/x-ndjson" | "application/ndjson" => return Some(Format::Ndjson),
+                "application/vnd.apache.parquet" | "application/x-parquet" => {
+                    return Some(Format::Parquet)
+                }
+                _ => (),
+            }
+        }
+        None
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Csv => "text/csv",
+            Format::Ndjson => "application/x-ndjson",
+            Format::Parquet => "application/vnd.apache.parquet",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Csv => "csv",
+            Format::Ndjson => "ndjson",
+            Format::Parquet => "parquet",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Units {
+    #[default]
+    F,
+    C,
+}
+
+impl Units {
+    fn temperature_column(self) -> &'static str {
+        match self {
+            Units::F => "temperature_f",
+            Units::C => "temperature_c",
+        }
+    }
+
+    /// Converts a temperature in 1/10 degrees F. Sensors that only report
+    /// humidity store a large negative temperature, which becomes `None`.
+    fn temperature(self, tenths_f: i32) -> Option<f64> {
+        if tenths_f <= -1000 {
+            return None;
+        }
+        let degrees = self.convert(f64::from(tenths_f) / 10.0);
+        Some((degrees * 10.0).round() / 10.0)
+    }
+
+    /// Converts a temperature in degrees F.
+    pub fn convert(self, fahrenheit: f64) -> f64 {
+        match self {
+            Units::F => fahrenheit,
+            Units::C => (fahrenheit - 32.0) * 5.0 / 9.0,
+        }
+    }
+}
+
+/// # Export Query
+/// Which readings to export. An empty `sensors` list means all of them.
+pub struct ExportQuery {
+    pub start_date: DateTime<Utc>,
+    pub end_date: DateTime<Utc>,
+    pub sensors: Vec<String>,
+    pub units: Units,
+}
+
+/// One exported reading, with times in both UTC and the server's local time
+/// zone and the temperature in the requested units.
+struct ExportRow {
+    name: String,
+    #[cfg(feature = "parquet")]
+    micros: i64,
+    time_utc: String,
+    time_local: String,
+    is_hygrostat: bool,
+    units: Units,
+    temperature: Option<f64>,
+    relative_humidity_pct: Option<i32>,
+}
+
+// Hand written so the temperature key says which units it is in, and the keys
+// come out in the same order as the CSV columns.
+impl Serialize for ExportRow {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("ExportRow", 6)?;
+        state.serialize_field("name", &self.name)?;
+        state.serialize_field("time_utc", &self.time_utc)?;
+        state.serialize_field("time_local", &self.time_local)?;
+        state.serialize_field("is_hygrostat", &self.is_hygrostat)?;
+        state.serialize_field(self.units.temperature_column(), &self.temperature)?;
+        state.serialize_field("relative_humidity_pct", &self.relative_humidity_pct)?;
+        state.end()
+    }
+}
+
+impl ExportRow {
+    fn new(thermostat: &Thermostat, units: Units) -> Self {
+        let time = thermostat.time();
+        Self {
+            name: thermostat.name.clone(),
+            #[cfg(feature = "parquet")]
+            micros: time.timestamp_micros(),
+            time_utc: time.to_rfc3339_opts(SecondsFormat::Secs, true),
+            time_local: time
+                .with_timezone(&Local)
+                .to_rfc3339_opts(SecondsFormat::Secs, false),
+            is_hygrostat: thermostat.is_hygrostat,
+            units,
+            temperature: units.temperature(thermostat.temperature),
+            relative_humidity_pct: if thermostat.is_hygrostat {
+                Some(thermostat.relative_humidity)
+            } else {
+                None
+            },
+        }
+    }
+}
+
+/// # Export
+/// Reads the requested readings from the database a chunk at a time and
+/// writes them to `out` in the requested format. Returns the number of rows
+/// written.
+pub fn export(
+    connection: &DbConnection,
+    query: &ExportQuery,
+    format: Format,
+    out: &mut dyn Write,
+) -> anyhow::Result<usize> {
+    let names = if query.sensors.is_empty() {
+        None
+    } else {
+        Some(query.sensors.as_slice())
+    };
+    let mut encoder = Encoder::new(format, query.units)?;
+    encoder.begin(out)?;
+    let mut count = 0;
+    let mut after = None;
+    loop {
+        let rows = Thermostat::query_page(
+            connection,
+            &query.start_date,
+            &query.end_date,
+            names,
+            after,
+            CHUNK,
+        )?;
+        let export_rows: Vec<ExportRow> = rows
+            .iter()
+            .map(|row| ExportRow::new(row, query.units))
+            .collect();
+        encoder.rows(&export_rows, out)?;
+        count += rows.len();
+        match rows.last() {
+            Some(last) if rows.len() as i64 == CHUNK => after = Some((last.time(), last.id)),
+            _ => break,
+        }
+    }
+    encoder.finish(out)?;
+    out.flush()?;
+    Ok(count)
+}
+
+enum Encoder {
+    Csv(Units),
+    Ndjson,
+    #[cfg(feature = "parquet")]
+    Parquet(Box<parquet_export::ParquetEncoder>),
+}
+
+impl Encoder {
+    fn new(format: Format, units: Units) -> anyhow::Result<Self> {
+        match format {
+            Format::Csv => Ok(Encoder::Csv(units)),
+            Format::Ndjson => Ok(Encoder::Ndjson),
+            #[cfg(feature = "parquet")]
+            Format::Parquet => Ok(Encoder::Parquet(Box::new(
+                parquet_export::ParquetEncoder::new(units)?,
+            ))),
+            #[cfg(not(feature = "parquet"))]
+            Format::Parquet => Err(anyhow::anyhow!(
+                "this build does not support Parquet; rebuild with `--features parquet`"
+            )),
+        }
+    }
+
+    fn begin(&mut self, out: &mut dyn Write) -> anyhow::Result<()> {
+        if let Encoder::Csv(units) = self {
+            writeln!(
+                out,
+                "name,time_utc,time_local,is_hygrostat,{},relative_humidity_pct",
+                units.temperature_column()
+            )?;
+        }
+        Ok(())
+    }
+
+    fn rows(&mut self, rows: &[ExportRow], out: &mut dyn Write) -> anyhow::Result<()> {
+        match self {
+            Encoder::Csv(_) => {
+                for row in rows {
+                    writeln!(
+                        out,
+                        "{},{},{},{},{},{}",
+                        csv_field(&row.name),
+                        row.time_utc,
+                        row.time_local,
+                        row.is_hygrostat,
+                        optional_field(row.temperature),
+                        optional_field(row.relative_humidity_pct),
+                    )?;
+                }
+            }
+            Encoder::Ndjson => {
+                for row in rows {
+                    serde_json::to_writer(&mut *out, row)?;
+                    out.write_all(b"\n")?;
+                }
+            }
+            #[cfg(feature = "parquet")]
+            Encoder::Parquet(encoder) => encoder.row_group(rows)?,
+        }
+        Ok(())
+    }
+
+    #[allow(unused_variables)]
+    fn finish(self, out: &mut dyn Write) -> anyhow::Result<()> {
+        #[cfg(feature = "parquet")]
+        {
+            if let Encoder::Parquet(encoder) = self {
+                out.write_all(&encoder.finish()?)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Quotes a CSV field if it contains anything that would break the row.
+fn csv_field(value: &str) -> String {
+    if value.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn optional_field<T: ToString>(value: Option<T>) -> String {
+    match value {
+        Some(value) => value.to_string(),
+        None => String::new(),
+    }
+}
+
+/// # Run CLI
+/// Handles `therm_hub export`. Writes to `--output` or stdout.
+///
+/// therm_hub export --start-date 2020-08-01T00:00:00-05:00
+///     --end-date 2020-09-01T00:00:00-05:00 [--sensors a,b] [--format csv]
+///     [--units f] [--output file.csv]
+pub fn run_cli(args: &[String]) -> anyhow::Result<()> {
+    let mut start_date = None;
+    let mut end_date = None;
+    let mut sensors = Vec::new();
+    let mut format = None;
+    let mut units = Units::default();
+    let mut output = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .cloned()
+                .ok_or_else(|| anyhow::anyhow!("{} needs a value", arg))
+        };
+        match arg.as_str() {
+            "--start-date" => start_date = Some(DateTime::parse_from_rfc3339(&value()?)?),
+            "--end-date" => end_date = Some(DateTime::parse_from_rfc3339(&value()?)?),
+            "--sensors" => sensors = split_sensors(&value()?),
+            "--format" => {
+                let name = value()?;
+                format = Some(
+                    Format::from_name(&name)
+                        .ok_or_else(|| anyhow::anyhow!("unknown format {}", name))?,
+                );
+            }
+            "--units" => {
+                let name = value()?;
+                units = serde_json::from_value(serde_json::Value::String(name.clone()))
+                    .map_err(|_| anyhow::anyhow!("unknown units {}", name))?;
+            }
+            "--output" => output = Some(value()?),
+            other => return Err(anyhow::anyhow!("unknown argument {}", other)),
+        }
+    }
+
+    let query = ExportQuery {
+        start_date: start_date
+            .ok_or_else(|| anyhow::anyhow!("--start-date is required"))?
+            .with_timezone(&Utc),
+        end_date: end_date
+            .ok_or_else(|| anyhow::anyhow!("--end-date is required"))?
+            .with_timezone(&Utc),
+        sensors,
+        units,
+    };
+    // Guess the format from the output file name if it was not given.
+    let format = format
+        .or_else(|| {
+            output
+                .as_ref()
+                .and_then(|path| path.rsplit('.').next())
+                .and_then(Format::from_name)
+        })
+        .unwrap_or(Format::Csv);
+
+    let connection = crate::establish_connection();
+    let count = match output {
+        Some(path) => {
+            let mut file = std::io::BufWriter::new(std::fs::File::create(&path)?);
+            export(&connection, &query, format, &mut file)?
+        }
+        None => {
+            let stdout = std::io::stdout();
+            let mut stdout = stdout.lock();
+            export(&connection, &query, format, &mut stdout)?
+        }
+    };
+    eprintln!("Exported {} readings", count);
+    Ok(())
+}
+
+/// Splits a comma separated list of sensor names.
+pub fn split_sensors(sensors: &str) -> Vec<String> {
+    sensors
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+#[cfg(feature = "parquet")]
+mod parquet_export {
+    use super::{ExportRow, Units};
+    use parquet::column::writer::ColumnWriter;
+    use parquet::data_type::ByteArray;
+    use parquet::file::properties::WriterProperties;
+    use parquet::file::writer::SerializedFileWriter;
+    use parquet::schema::parser::parse_message_type;
+    use std::sync::Arc;
+
+    /// Writes each chunk of rows as a Parquet row group into memory. Parquet
+    /// keeps its index at the end of the file, so nothing can be sent until
+    /// every row has been written.
+    pub struct ParquetEncoder {
+        writer: SerializedFileWriter<Vec<u8>>,
+    }
+
+    impl ParquetEncoder {
+        pub fn new(units: Units) -> anyhow::Result<Self> {
+            let message = format!(
+                "message thermostats {{
+                    required binary name (UTF8);
+                    required int64 time_utc (TIMESTAMP_MICROS);
+                    required binary time_local (UTF8);
+                    required boolean is_hygrostat;
+                    optional double {};
+                    optional int32 relative_humidity_pct;
+                }}",
+                units.temperature_column()
+            );
+            let schema = Arc::new(parse_message_type(&message)?);
+            let properties = Arc::new(WriterProperties::builder().build());
+            let writer = SerializedFileWriter::new(Vec::new(), schema, properties)?;
+            Ok(Self { writer })
+        }
+
+        pub fn row_group(&mut self, rows: &[ExportRow]) -> anyhow::Result<()> {
+            if rows.is_empty() {
+                return Ok(());
+            }
+            let mut row_group = self.writer.next_row_group()?;
+            let mut index = 0;
+            while let Some(mut column) = row_group.next_column()? {
+                match (index, column.untyped()) {
+                    (0, ColumnWriter::ByteArrayColumnWriter(writer)) => {
+                        writer.write_batch(&strings(rows, |row| &row.name), None, None)?;
+                    }
+                    (1, ColumnWriter::Int64ColumnWriter(writer)) => {
+                        let values: Vec<i64> = rows.iter().map(|row| row.micros).collect();
+                        writer.write_batch(&values, None, None)?;
+                    }
+                    (2, ColumnWriter::ByteArrayColumnWriter(writer)) => {
+                        writer.write_batch(&strings(rows, |row| &row.time_local), None, None)?;
+                    }
+                    (3, ColumnWriter::BoolColumnWriter(writer)) => {
+                        let values: Vec<bool> = rows.iter().map(|row| row.is_hygrostat).collect();
+                        writer.write_batch(&values, None, None)?;
+                    }
+                    (4, ColumnWriter::DoubleColumnWriter(writer)) => {
+                        let (values, levels) = optionals(rows, |row| row.temperature);
+                        writer.write_batch(&values, Some(&levels), None)?;
+                    }
+                    (5, ColumnWriter::Int32ColumnWriter(writer)) => {
+                        let (values, levels) = optionals(rows, |row| row.relative_humidity_pct);
+                        writer.write_batch(&values, Some(&levels), None)?;
+                    }
+                    _ => return Err(anyhow::anyhow!("unexpected Parquet column {}", index)),
+                }
+                column.close()?;
+                index += 1;
+            }
+            row_group.close()?;
+            Ok(())
+        }
+
+        pub fn finish(self) -> anyhow::Result<Vec<u8>> {
+            Ok(self.writer.into_inner()?)
+        }
+    }
+
+    fn strings<F>(rows: &[ExportRow], field: F) -> Vec<ByteArray>
+    where
+        F: Fn(&ExportRow) -> &String,
+    {
+        rows.iter()
+            .map(|row| ByteArray::from(field(row).as_str()))
+            .collect()
+    }
+
+    /// Splits optional values into the present values and definition levels.
+    fn optionals<T, F>(rows: &[ExportRow], field: F) -> (Vec<T>, Vec<i16>)
+    where
+        F: Fn(&ExportRow) -> Option<T>,
+    {
+        let mut values = Vec::new();
+        let mut levels = Vec::new();
+        for row in rows {
+            match field(row) {
+                Some(value) => {
+                    values.push(value);
+                    levels.push(1);
+                }
+                None => levels.push(0),
+            }
+        }
+        (values, levels)
+    }
+}
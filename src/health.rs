@@ -0,0 +1,223 @@
+use crate::db::{self, PoolStats};
+use chrono::{DateTime, Duration, Utc};
+use diesel_migrations::MigrationConnection;
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, RwLock};
+
+// this file covers tracking the health of therm_hub's subsystems for `/readyz`
+
+pub const ECOBEE: &str = "ecobee";
+pub const WEATHER: &str = "weather";
+
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Ok,
+    Failing,
+    /// The subsystem has not been set up yet (e.g. the Ecobee install has not
+    /// been completed). This does not count against readiness.
+    NotInstalled,
+    /// The subsystem is turned off in the configuration.
+    Disabled,
+}
+
+#[derive(Serialize)]
+pub struct Component {
+    status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_success: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct Readiness {
+    pub status: Status,
+    components: BTreeMap<&'static str, Component>,
+    pool: PoolStats,
+}
+
+#[derive(Clone, Default)]
+struct PollState {
+    last_success: Option<DateTime<Utc>>,
+    last_error: Option<String>,
+    not_installed: bool,
+}
+
+type StsPolls = Arc<RwLock<HashMap<&'static str, PollState>>>;
+type StsBool = Arc<RwLock<bool>>;
+lazy_static! {
+    static ref POLLS: StsPolls = Arc::new(RwLock::new(HashMap::new()));
+    static ref MIGRATED: StsBool = Arc::new(RwLock::new(false));
+}
+
+fn update_poll<F: FnOnce(&mut PollState)>(poll: &'static str, f: F) {
+    if let Ok(mut polls) = POLLS.write() {
+        f(polls.entry(poll).or_default());
+    }
+}
+
+/// # Succeeded
+/// Records a successful poll of a remote API.
+pub fn succeeded(poll: &'static str) {
+    update_poll(poll, |state| {
+        state.last_success = Some(Utc::now());
+        state.last_error = None;
+        state.not_installed = false;
+    });
+}
+
+/// # Failed
+/// Records a failed poll of a remote API, and why it failed.
+pub fn failed(poll: &'static str, reason: &str) {
+    update_poll(poll, |state| {
+        state.last_error = Some(reason.to_string());
+        state.not_installed = false;
+    });
+}
+
+/// # Not Installed
+/// Records that a remote API was not polled because it is not set up yet.
+pub fn not_installed(poll: &'static str) {
+    update_poll(poll, |state| state.not_installed = true);
+}
+
+/// # Migrated
+/// Records that the startup migrations ran successfully.
+pub fn migrated() {
+    if let Ok(mut migrated) = MIGRATED.write() {
+        *migrated = true;
+    }
+}
+
+fn max_poll_age() -> Duration {
+    let missed = crate::config::get().health.ready_max_missed_polls;
+    Duration::seconds(missed * crate::worker::INTERVAL)
+}
+
+fn poll_component(poll: &'static str) -> Component {
+    if poll == ECOBEE && crate::config::get().ecobee.is_none() {
+        return Component {
+            status: Status::Disabled,
+            last_success: None,
+            detail: None,
+        };
+    }
+    let state = match POLLS.read() {
+        Ok(polls) => polls.get(poll).cloned().unwrap_or_default(),
+        Err(_) => PollState::default(),
+    };
+    if state.not_installed {
+        return Component {
+            status: Status::NotInstalled,
+            last_success: state.last_success,
+            detail: None,
+        };
+    }
+    let fresh = match state.last_success {
+        Some(last_success) => Utc::now() - last_success <= max_poll_age(),
+        None => false,
+    };
+    Component {
+        status: if fresh { Status::Ok } else { Status::Failing },
+        last_success: state.last_success,
+        detail: if fresh {
+            None
+        } else {
+            Some(
+                state
+                    .last_error
+                    .unwrap_or_else(|| String::from("no recent successful poll")),
+            )
+        },
+    }
+}
+
+fn database_components() -> (Component, Component) {
+    let connection = match db::connection() {
+        Ok(connection) => connection,
+        Err(err) => {
+            let database = Component {
+                status: Status::Failing,
+                last_success: None,
+                detail: Some(err.to_string()),
+            };
+            let migrations = Component {
+                status: Status::Failing,
+                last_success: None,
+                detail: Some(String::from("database unreachable")),
+            };
+            return (database, migrations);
+        }
+    };
+    let database = Component {
+        status: Status::Ok,
+        last_success: None,
+        detail: None,
+    };
+    let migrated = MIGRATED.read().map(|migrated| *migrated).unwrap_or(false);
+    let migrations = match connection.latest_run_migration_version() {
+        Ok(Some(version)) if migrated => Component {
+            status: Status::Ok,
+            last_success: None,
+            detail: Some(version),
+        },
+        Ok(_) => Component {
+            status: Status::Failing,
+            last_success: None,
+            detail: Some(String::from("migrations have not been run")),
+        },
+        Err(err) => Component {
+            status: Status::Failing,
+            last_success: None,
+            detail: Some(err.to_string()),
+        },
+    };
+    (database, migrations)
+}
+
+/// # Readiness
+/// Checks every subsystem. The database is checked live, the remote APIs by
+/// how long ago the worker last polled them successfully. Also reports how
+/// busy the connection pool is. Blocks on the database.
+pub fn readiness() -> Readiness {
+    let (database, migrations) = database_components();
+    let mut components = BTreeMap::new();
+    components.insert("database", database);
+    components.insert("migrations", migrations);
+    components.insert(ECOBEE, poll_component(ECOBEE));
+    components.insert(WEATHER, poll_component(WEATHER));
+    let status = if components.values().any(|c| c.status == Status::Failing) {
+        Status::Failing
+    } else {
+        Status::Ok
+    };
+    Readiness {
+        status,
+        components,
+        pool: db::stats(),
+    }
+}
+
+/// # Startup Problems
+/// Describes every remote API the worker could not poll, for reporting why
+/// the server refuses to start.
+pub fn startup_problems() -> Vec<String> {
+    [ECOBEE, WEATHER]
+        .iter()
+        .filter_map(|poll| {
+            let component = poll_component(poll);
+            if component.status == Status::Failing {
+                Some(format!(
+                    "{}: {}",
+                    poll,
+                    component.detail.unwrap_or_default()
+                ))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,259 @@
+use crate::db::DbConnection;
+use crate::export::{Format, Units};
+use crate::Thermostat;
+use chrono::{DateTime, Duration, Utc};
+use std::collections::HashSet;
+use std::io::{BufRead, BufReader, Read};
+
+// this file covers importing readings from the files `therm_hub export` writes
+
+/// Readings inserted per transaction.
+const CHUNK: usize = 1000;
+
+/// # Import Row
+/// One reading from a file, with the temperature already in 1/10 degrees F.
+struct ImportRow {
+    name: String,
+    time: DateTime<Utc>,
+    is_hygrostat: bool,
+    temperature: i32,
+    relative_humidity: i32,
+}
+
+impl ImportRow {
+    fn into_thermostat(self) -> Thermostat {
+        Thermostat::new2(
+            self.name,
+            self.time,
+            self.is_hygrostat,
+            self.temperature,
+            self.relative_humidity,
+        )
+    }
+}
+
+/// Converts an exported temperature back to 1/10 degrees F. An empty
+/// temperature is a humidity-only sensor.
+pub fn temperature(degrees: Option<f64>, units: Units) -> i32 {
+    match degrees {
+        Some(degrees) => {
+            let fahrenheit = match units {
+                Units::F => degrees,
+                Units::C => degrees * 9.0 / 5.0 + 32.0,
+            };
+            (fahrenheit * 10.0).round() as i32
+        }
+        None => -10000,
+    }
+}
+
+/// Splits a CSV line, honoring quoted fields.
+fn csv_fields(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            _ => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+/// # Parse CSV
+/// Reads a file with the header `therm_hub export` writes. Columns are found
+/// by name, so `time_local` may be missing and the temperature column says
+/// which units it is in.
+fn parse_csv(input: &mut dyn BufRead) -> anyhow::Result<Vec<ImportRow>> {
+    let mut lines = input.lines();
+    let header = match lines.next() {
+        Some(header) => csv_fields(header?.trim_end()),
+        None => return Ok(Vec::new()),
+    };
+    let column = |name: &str| header.iter().position(|column| column == name);
+    let required = |name: &str| column(name).ok_or_else(|| anyhow::anyhow!("no {} column", name));
+    let name = required("name")?;
+    let time = required("time_utc")?;
+    let is_hygrostat = required("is_hygrostat")?;
+    let (temperature_column, units) = match (column("temperature_f"), column("temperature_c")) {
+        (Some(index), _) => (index, Units::F),
+        (None, Some(index)) => (index, Units::C),
+        (None, None) => anyhow::bail!("no temperature_f or temperature_c column"),
+    };
+    let relative_humidity = required("relative_humidity_pct")?;
+
+    let mut rows = Vec::new();
+    for (number, line) in lines.enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let fields = csv_fields(line.trim_end());
+        let field = |index: usize| fields.get(index).map(String::as_str).unwrap_or("");
+        let row = (|| -> anyhow::Result<ImportRow> {
+            let degrees = match field(temperature_column) {
+                "" => None,
+                degrees => Some(degrees.parse()?),
+            };
+            Ok(ImportRow {
+                name: field(name).to_string(),
+                time: DateTime::parse_from_rfc3339(field(time))?.with_timezone(&Utc),
+                is_hygrostat: field(is_hygrostat).parse()?,
+                temperature: temperature(degrees, units),
+                relative_humidity: match field(relative_humidity) {
+                    "" => 0,
+                    humidity => humidity.parse()?,
+                },
+            })
+        })();
+        // The header is line 1.
+        rows.push(row.map_err(|err| anyhow::anyhow!("line {}: {}", number + 2, err))?);
+    }
+    Ok(rows)
+}
+
+/// # Parse NDJSON
+/// Reads one exported reading per line.
+fn parse_ndjson(input: &mut dyn BufRead) -> anyhow::Result<Vec<ImportRow>> {
+    let mut rows = Vec::new();
+    for (number, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let row = (|| -> anyhow::Result<ImportRow> {
+            let value: serde_json::Value = serde_json::from_str(&line)?;
+            let text = |key: &str| {
+                value[key]
+                    .as_str()
+                    .ok_or_else(|| anyhow::anyhow!("{} is missing", key))
+            };
+            let temperature = match (&value["temperature_f"], &value["temperature_c"]) {
+                (f, _) if f.is_number() => temperature(f.as_f64(), Units::F),
+                (_, c) if c.is_number() => temperature(c.as_f64(), Units::C),
+                _ => temperature(None, Units::F),
+            };
+            Ok(ImportRow {
+                name: text("name")?.to_string(),
+                time: DateTime::parse_from_rfc3339(text("time_utc")?)?.with_timezone(&Utc),
+                is_hygrostat: value["is_hygrostat"]
+                    .as_bool()
+                    .ok_or_else(|| anyhow::anyhow!("is_hygrostat is missing"))?,
+                temperature,
+                relative_humidity: value["relative_humidity_pct"].as_i64().unwrap_or(0) as i32,
+            })
+        })();
+        rows.push(row.map_err(|err| anyhow::anyhow!("line {}: {}", number + 1, err))?);
+    }
+    Ok(rows)
+}
+
+/// # Import
+/// Reads readings from `input` and inserts the ones that are not in the
+/// database yet. A reading is already there when a sensor with the same
+/// name has one at the same time, so a file can be imported twice. The
+/// hourly and daily summaries are brought up to date from the earliest new
+/// reading. Returns how many were inserted and how many were skipped.
+pub fn import(
+    connection: &DbConnection,
+    format: Format,
+    input: &mut dyn Read,
+) -> anyhow::Result<(usize, usize)> {
+    let mut input = BufReader::new(input);
+    let rows = match format {
+        Format::Csv => parse_csv(&mut input)?,
+        Format::Ndjson => parse_ndjson(&mut input)?,
+        Format::Parquet => anyhow::bail!("Parquet files cannot be imported"),
+    };
+    let (start_date, end_date) = match (
+        rows.iter().map(|row| row.time).min(),
+        rows.iter().map(|row| row.time).max(),
+    ) {
+        (Some(start_date), Some(end_date)) => (start_date, end_date),
+        _ => return Ok((0, 0)),
+    };
+    // Exports are to the second, so readings are compared to the second. The
+    // end is pushed out to catch stored readings with fractional seconds.
+    let end_date = end_date + Duration::seconds(1);
+    let mut existing: HashSet<(String, i64)> =
+        Thermostat::query_dates(connection, &start_date, &end_date)?
+            .into_iter()
+            .map(|thermostat| {
+                let time = thermostat.time().timestamp();
+                (thermostat.name, time)
+            })
+            .collect();
+
+    let total = rows.len();
+    let new_rows: Vec<Thermostat> = rows
+        .into_iter()
+        .filter(|row| existing.insert((row.name.clone(), row.time.timestamp())))
+        .map(ImportRow::into_thermostat)
+        .collect();
+    for chunk in new_rows.chunks(CHUNK) {
+        Thermostat::insert_all(connection, chunk)?;
+    }
+    // The worker only summarizes forward from its latest summary, so older
+    // readings would never reach the hourly and daily summaries.
+    if let Some(earliest) = new_rows.iter().map(Thermostat::time).min() {
+        crate::rollup::summarize_since(connection, earliest.naive_utc())?;
+    }
+    Ok((new_rows.len(), total - new_rows.len()))
+}
+
+/// # Run CLI
+/// Handles `therm_hub import`. Reads `--input` or stdin.
+///
+/// therm_hub import [--input file.csv] [--format csv|ndjson]
+pub fn run_cli(args: &[String]) -> anyhow::Result<()> {
+    let mut format = None;
+    let mut input = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .cloned()
+                .ok_or_else(|| anyhow::anyhow!("{} needs a value", arg))
+        };
+        match arg.as_str() {
+            "--format" => {
+                let name = value()?;
+                format = Some(
+                    Format::from_name(&name)
+                        .ok_or_else(|| anyhow::anyhow!("unknown format {}", name))?,
+                );
+            }
+            "--input" => input = Some(value()?),
+            other => return Err(anyhow::anyhow!("unknown argument {}", other)),
+        }
+    }
+    // Guess the format from the input file name if it was not given.
+    let format = format
+        .or_else(|| {
+            input
+                .as_ref()
+                .and_then(|path| path.rsplit('.').next())
+                .and_then(Format::from_name)
+        })
+        .unwrap_or(Format::Csv);
+
+    let connection = crate::establish_connection();
+    let (inserted, skipped) = match input {
+        Some(path) => import(&connection, format, &mut std::fs::File::open(&path)?)?,
+        None => import(&connection, format, &mut std::io::stdin())?,
+    };
+    eprintln!(
+        "Imported {} readings, skipped {} already stored",
+        inserted, skipped
+    );
+    Ok(())
+}
@@ -0,0 +1,399 @@
+use crate::db::DbConnection;
+use crate::export::Units;
+use crate::import::temperature;
+use crate::Thermostat;
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use serde::Deserialize;
+
+// this file covers readings pushed by other devices to `POST /readings`, as
+// JSON or InfluxDB line protocol
+
+/// Readings accepted per request.
+pub const MAX_READINGS: usize = 1000;
+/// Longest sensor name accepted.
+const MAX_NAME: usize = 64;
+/// How far ahead of the server's clock a reading may be, for devices whose
+/// clocks run a little fast.
+const MAX_AHEAD_SECONDS: i64 = 300;
+/// How old a reading may be. Older readings can be loaded with `therm_hub
+/// import`.
+const MAX_AGE_DAYS: i64 = 7;
+/// Temperatures outside this range, in degrees F, are a broken sensor.
+const MIN_FAHRENHEIT: f64 = -100.0;
+const MAX_FAHRENHEIT: f64 = 200.0;
+
+/// # Reading Input
+/// One reading, as JSON. `temperature` is in the request's units and
+/// `humidity` in percent; a reading needs at least one of them. `time`
+/// defaults to now.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReadingInput {
+    name: String,
+    temperature: Option<f64>,
+    humidity: Option<f64>,
+    time: Option<DateTime<Utc>>,
+}
+
+/// # Precision
+/// The unit of line protocol timestamps.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Precision {
+    #[default]
+    Ns,
+    Us,
+    Ms,
+    S,
+}
+
+impl Precision {
+    fn time(self, timestamp: i64) -> Option<DateTime<Utc>> {
+        let (seconds, nanoseconds) = match self {
+            Precision::Ns => (
+                timestamp.div_euclid(1_000_000_000),
+                timestamp.rem_euclid(1_000_000_000),
+            ),
+            Precision::Us => (
+                timestamp.div_euclid(1_000_000),
+                timestamp.rem_euclid(1_000_000) * 1000,
+            ),
+            Precision::Ms => (
+                timestamp.div_euclid(1000),
+                timestamp.rem_euclid(1000) * 1_000_000,
+            ),
+            Precision::S => (timestamp, 0),
+        };
+        Utc.timestamp_opt(seconds, nanoseconds as u32).single()
+    }
+}
+
+impl ReadingInput {
+    /// # Into Thermostat
+    /// Checks the reading, explaining what is wrong with it.
+    fn into_thermostat(self, units: Units, now: DateTime<Utc>) -> Result<Thermostat, String> {
+        let name = self.name.trim();
+        if name.is_empty() || name.len() > MAX_NAME {
+            return Err(format!("name must be 1 to {} characters", MAX_NAME));
+        }
+        if name == "weather.gov" {
+            return Err(String::from("weather.gov is reserved for the forecast"));
+        }
+        if self.temperature.is_none() && self.humidity.is_none() {
+            return Err(format!("{} has no temperature or humidity", name));
+        }
+        let tenths = temperature(self.temperature, units);
+        if let Some(degrees) = self.temperature {
+            let fahrenheit = f64::from(tenths) / 10.0;
+            if !degrees.is_finite() || !(MIN_FAHRENHEIT..=MAX_FAHRENHEIT).contains(&fahrenheit) {
+                return Err(format!(
+                    "{}'s temperature {} is out of range",
+                    name, degrees
+                ));
+            }
+        }
+        let humidity = match self.humidity {
+            Some(humidity) if (0.0..=100.0).contains(&humidity) => Some(humidity.round() as i32),
+            Some(humidity) => {
+                return Err(format!("{}'s humidity {} is not 0 to 100", name, humidity))
+            }
+            None => None,
+        };
+        let time = self.time.unwrap_or(now);
+        if time > now + Duration::seconds(MAX_AHEAD_SECONDS) {
+            return Err(format!("{}'s time {} is in the future", name, time));
+        }
+        if time < now - Duration::days(MAX_AGE_DAYS) {
+            return Err(format!(
+                "{}'s time {} is more than {} days ago",
+                name, time, MAX_AGE_DAYS
+            ));
+        }
+        Ok(Thermostat::new2(
+            name.to_string(),
+            time,
+            humidity.is_some(),
+            tenths,
+            humidity.unwrap_or(0),
+        ))
+    }
+}
+
+/// # Parse JSON
+/// Reads one reading, or an array of them.
+pub fn parse_json(body: &[u8]) -> Result<Vec<ReadingInput>, String> {
+    let is_batch = body.iter().find(|byte| !byte.is_ascii_whitespace()) == Some(&b'[');
+    let result = if is_batch {
+        serde_json::from_slice(body)
+    } else {
+        serde_json::from_slice(body).map(|reading| vec![reading])
+    };
+    result.map_err(|err| err.to_string())
+}
+
+/// Splits on `separator` where it is not escaped with `\` or inside double
+/// quotes. The pieces keep their escapes.
+fn split_unescaped(text: &str, separator: char) -> Vec<&str> {
+    let mut pieces = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+    let mut quoted = false;
+    for (index, c) in text.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => quoted = !quoted,
+            _ if c == separator && !quoted => {
+                pieces.push(&text[start..index]);
+                start = index + c.len_utf8();
+            }
+            _ => (),
+        }
+    }
+    pieces.push(&text[start..]);
+    pieces
+}
+
+/// Removes the `\` from escaped characters.
+fn unescape(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unescaped.extend(chars.next()),
+            _ => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+/// Reads a line protocol number: a float, or an integer ending in `i` or `u`.
+fn number(value: &str) -> Option<f64> {
+    value
+        .strip_suffix('i')
+        .or_else(|| value.strip_suffix('u'))
+        .unwrap_or(value)
+        .parse()
+        .ok()
+}
+
+/// # Parse Line
+/// Reads one line of line protocol, `measurement[,tags] fields [timestamp]`.
+/// The sensor is the `sensor` or `name` tag, or else the measurement. The
+/// `temperature` and `humidity` fields are read; other fields are ignored.
+fn parse_line(line: &str, precision: Precision) -> Result<ReadingInput, String> {
+    let sections: Vec<&str> = split_unescaped(line, ' ')
+        .into_iter()
+        .filter(|section| !section.is_empty())
+        .collect();
+    let (series, fields, timestamp) = match sections.as_slice() {
+        [series, fields] => (*series, *fields, None),
+        [series, fields, timestamp] => (*series, *fields, Some(*timestamp)),
+        _ => {
+            return Err(String::from(
+                "expected a measurement, fields and a timestamp",
+            ))
+        }
+    };
+
+    let mut series = split_unescaped(series, ',').into_iter();
+    let mut name = unescape(series.next().unwrap_or(""));
+    for tag in series {
+        match split_unescaped(tag, '=').as_slice() {
+            [key, value] if *key == "sensor" || *key == "name" => name = unescape(value),
+            [_, _] => (),
+            _ => return Err(format!("tag {} is not key=value", tag)),
+        }
+    }
+
+    let mut temperature = None;
+    let mut humidity = None;
+    for field in split_unescaped(fields, ',') {
+        let (key, value) = match split_unescaped(field, '=').as_slice() {
+            [key, value] => (unescape(key), *value),
+            _ => return Err(format!("field {} is not key=value", field)),
+        };
+        let slot = match key.as_str() {
+            "temperature" => &mut temperature,
+            "humidity" => &mut humidity,
+            _ => continue,
+        };
+        *slot = Some(number(value).ok_or_else(|| format!("{} is not a number", key))?);
+    }
+
+    let time = match timestamp {
+        Some(timestamp) => {
+            let timestamp = timestamp
+                .parse()
+                .map_err(|_| format!("timestamp {} is not a number", timestamp))?;
+            Some(
+                precision
+                    .time(timestamp)
+                    .ok_or_else(|| format!("timestamp {} is out of range", timestamp))?,
+            )
+        }
+        None => None,
+    };
+    Ok(ReadingInput {
+        name,
+        temperature,
+        humidity,
+        time,
+    })
+}
+
+/// # Parse Line Protocol
+/// Reads InfluxDB line protocol, one reading per line. Blank lines and
+/// `#` comments are skipped.
+pub fn parse_line_protocol(body: &str, precision: Precision) -> Result<Vec<ReadingInput>, String> {
+    let mut readings = Vec::new();
+    for (number, line) in body.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let reading =
+            parse_line(line, precision).map_err(|err| format!("line {}: {}", number + 1, err))?;
+        readings.push(reading);
+    }
+    Ok(readings)
+}
+
+/// # Validate
+/// Turns readings into thermostats, explaining the first one that is not
+/// valid. Nothing is accepted unless every reading is.
+pub fn validate(readings: Vec<ReadingInput>, units: Units) -> Result<Vec<Thermostat>, String> {
+    if readings.is_empty() {
+        return Err(String::from("there are no readings"));
+    }
+    if readings.len() > MAX_READINGS {
+        return Err(format!("more than {} readings", MAX_READINGS));
+    }
+    let now = Utc::now();
+    readings
+        .into_iter()
+        .map(|reading| reading.into_thermostat(units, now))
+        .collect()
+}
+
+/// # Ingest
+/// Saves readings the way the worker does, summarizes the hours and days
+/// they fall in again, and shows them in `/now` straight away.
+pub fn ingest(connection: &DbConnection, readings: &[Thermostat]) -> anyhow::Result<()> {
+    Thermostat::insert_all(connection, readings)?;
+    if let Some(earliest) = readings.iter().map(Thermostat::time).min() {
+        crate::rollup::summarize_since(connection, earliest.naive_utc())?;
+    }
+    crate::worker::add_readings(readings);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_with_escapes_and_an_integer_field() {
+        let reading = parse_line(
+            r"climate,sensor=Living\ Room,floor=1 temperature=71.5,humidity=45i 1598119200",
+            Precision::S,
+        )
+        .unwrap();
+        assert_eq!(reading.name, "Living Room");
+        assert_eq!(reading.temperature, Some(71.5));
+        assert_eq!(reading.humidity, Some(45.0));
+        assert_eq!(reading.time, Utc.timestamp_opt(1_598_119_200, 0).single());
+    }
+
+    #[test]
+    fn measurement_is_the_name_without_a_sensor_tag() {
+        let reading = parse_line(
+            r#"Garage\,\ North status="a b",temperature=51"#,
+            Precision::Ns,
+        )
+        .unwrap();
+        assert_eq!(reading.name, "Garage, North");
+        assert_eq!(reading.temperature, Some(51.0));
+        assert_eq!(reading.humidity, None);
+        assert_eq!(reading.time, None);
+    }
+
+    #[test]
+    fn bad_lines_are_refused() {
+        assert!(parse_line("climate", Precision::Ns).is_err());
+        assert!(parse_line("climate temperature=warm", Precision::Ns).is_err());
+        assert!(parse_line("climate temperature=70 soon", Precision::Ns).is_err());
+        assert!(parse_line("climate,sensor temperature=70", Precision::Ns).is_err());
+        assert!(parse_line("climate temperature=70 99999999999999999", Precision::S).is_err());
+    }
+
+    #[test]
+    fn precisions() {
+        let time = Utc.timestamp_opt(1_598_119_200, 123_000_000).single();
+        assert_eq!(Precision::Ns.time(1_598_119_200_123_000_000), time);
+        assert_eq!(Precision::Us.time(1_598_119_200_123_000), time);
+        assert_eq!(Precision::Ms.time(1_598_119_200_123), time);
+        assert_eq!(
+            Precision::S.time(1_598_119_200),
+            Utc.timestamp_opt(1_598_119_200, 0).single()
+        );
+        assert_eq!(
+            Precision::Ms.time(-1),
+            Utc.timestamp_opt(-1, 999_000_000).single()
+        );
+    }
+
+    #[test]
+    fn comments_and_blank_lines_are_skipped() {
+        let readings = parse_line_protocol(
+            "# garage\n\nclimate,sensor=a temperature=70\nclimate,sensor=b humidity=40u\n",
+            Precision::Ns,
+        )
+        .unwrap();
+        assert_eq!(readings.len(), 2);
+        let err = parse_line_protocol("climate temperature=70\nclimate", Precision::Ns)
+            .err()
+            .unwrap();
+        assert!(err.starts_with("line 2:"), "{}", err);
+    }
+
+    fn reading(temperature: Option<f64>, humidity: Option<f64>) -> ReadingInput {
+        ReadingInput {
+            name: String::from("Attic"),
+            temperature,
+            humidity,
+            time: None,
+        }
+    }
+
+    #[test]
+    fn readings_are_stored_in_tenths_of_fahrenheit() {
+        let now = Utc::now();
+        let attic = reading(Some(20.0), Some(44.6))
+            .into_thermostat(Units::C, now)
+            .unwrap();
+        assert_eq!(attic.temperature, 680);
+        assert_eq!(attic.relative_humidity, 45);
+        assert!(attic.is_hygrostat);
+        assert_eq!(attic.time(), now);
+    }
+
+    #[test]
+    fn readings_out_of_range_are_refused() {
+        let now = Utc::now();
+        let check = |reading: ReadingInput| reading.into_thermostat(Units::F, now).is_err();
+        assert!(check(reading(Some(250.0), None)));
+        assert!(check(reading(Some(f64::NAN), None)));
+        assert!(check(reading(None, Some(101.0))));
+        assert!(check(reading(None, None)));
+        let mut late = reading(Some(70.0), None);
+        late.time = Some(now - Duration::days(MAX_AGE_DAYS + 1));
+        assert!(check(late));
+        let mut early = reading(Some(70.0), None);
+        early.time = Some(now + Duration::seconds(MAX_AHEAD_SECONDS + 60));
+        assert!(check(early));
+        let mut forecast = reading(Some(70.0), None);
+        forecast.name = String::from("weather.gov");
+        assert!(check(forecast));
+    }
+}
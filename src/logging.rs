@@ -0,0 +1,107 @@
+use crate::config::{LogFormat, Logging};
+use lazy_static::lazy_static;
+use std::sync::{Arc, RwLock};
+use tracing::Subscriber;
+use tracing_subscriber::fmt::time::ChronoUtc;
+use tracing_subscriber::layer::Layer;
+use tracing_subscriber::{reload, EnvFilter};
+
+// this file covers setting up logging, and changing which messages are logged
+// while therm_hub runs
+
+type Reload = Box<dyn Fn(EnvFilter) -> Result<(), String> + Send + Sync>;
+type StsFilter = Arc<RwLock<String>>;
+type StsReload = Arc<RwLock<Option<Reload>>>;
+lazy_static! {
+    /// The filter in use, as it was written.
+    static ref FILTER: StsFilter = Arc::new(RwLock::new(String::new()));
+    static ref RELOAD: StsReload = Arc::new(RwLock::new(None));
+}
+
+/// # Check Filter
+/// Makes sure a filter like `info,therm_hub::web=debug,sql=debug` parses.
+pub fn check_filter(filter: &str) -> Result<(), String> {
+    match EnvFilter::try_new(filter) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(format!("is not a valid filter: {}", err)),
+    }
+}
+
+/// # Init
+/// Sends log messages to stderr, which systemd passes on to the journal, as
+/// text or as JSON. Messages from crates that use `log` are included.
+pub fn init(logging: &Logging) {
+    // The filter was checked with the rest of the config.
+    let filter = EnvFilter::try_new(&logging.filter).unwrap_or_else(|_| EnvFilter::new("info"));
+    let result = match logging.format {
+        LogFormat::Text => {
+            let builder = tracing_subscriber::fmt()
+                .with_timer(ChronoUtc::rfc3339())
+                .with_ansi(false)
+                .with_env_filter(filter)
+                .with_writer(std::io::stderr)
+                .with_filter_reloading();
+            keep_handle(builder.reload_handle());
+            builder.try_init()
+        }
+        LogFormat::Json => {
+            let builder = tracing_subscriber::fmt()
+                .with_timer(ChronoUtc::rfc3339())
+                .json()
+                .flatten_event(true)
+                .with_current_span(true)
+                .with_span_list(false)
+                .with_env_filter(filter)
+                .with_writer(std::io::stderr)
+                .with_filter_reloading();
+            keep_handle(builder.reload_handle());
+            builder.try_init()
+        }
+    };
+    match result {
+        Ok(_) => set_current(&logging.filter),
+        Err(err) => eprintln!("Could not set up logging: {}", err),
+    }
+}
+
+fn keep_handle<S>(handle: reload::Handle<EnvFilter, S>)
+where
+    S: Subscriber + Send + Sync + 'static,
+    EnvFilter: Layer<S>,
+{
+    let reload: Reload =
+        Box::new(move |filter| handle.reload(filter).map_err(|err| err.to_string()));
+    if let Ok(mut current) = RELOAD.write() {
+        *current = Some(reload);
+    }
+}
+
+fn set_current(filter: &str) {
+    if let Ok(mut current) = FILTER.write() {
+        *current = filter.to_string();
+    }
+}
+
+/// # Filter
+/// The filter in use.
+pub fn filter() -> String {
+    match FILTER.read() {
+        Ok(filter) => filter.clone(),
+        Err(_) => String::new(),
+    }
+}
+
+/// # Set Filter
+/// Swaps the filter without a restart. The change lasts until therm_hub
+/// restarts; the config file is not touched.
+pub fn set_filter(filter: &str) -> Result<(), String> {
+    let parsed = EnvFilter::try_new(filter).map_err(|err| err.to_string())?;
+    let reload = RELOAD.read().map_err(|err| err.to_string())?;
+    match reload.as_ref() {
+        Some(reload) => reload(parsed)?,
+        None => return Err(String::from("logging is not set up")),
+    }
+    set_current(filter);
+    tracing::info!(filter, "Log filter changed");
+    Ok(())
+}
@@ -3,7 +3,6 @@ extern crate diesel;
 #[macro_use]
 extern crate diesel_migrations;
 
-use diesel::prelude::*;
 use diesel_migrations::*;
 use dotenv::dotenv;
 use lazy_static::lazy_static;
@@ -11,21 +10,39 @@ use serde::Serialize;
 use std::env;
 use std::sync::{Arc, RwLock};
 use therm::Thermostat;
+use web::CachedBody;
 use worker::{DailyCondition, HourlyCondition};
 
+mod alert;
+mod analytics;
+mod cli;
+mod comfort;
+mod config;
+mod db;
 mod ecobee;
+mod export;
+mod health;
+mod import;
+mod ingest;
+mod logging;
+mod metrics;
+mod mqtt;
+mod rollup;
 mod web;
 mod schema;
 mod therm;
+mod tsdb;
+mod webhook;
 mod worker;
 
 static VERSION: u32 = 20200911;
 
 /// Set up in-memory data cache for web server. We want to keep track of:
-/// 1. The entire string repsonse for "/now" requests, since it only changes
+/// 1. The entire repsonse body for "/now" requests, since it only changes
 ///    when the data model changes, and allows crazy fast response times.
-///    We need to keep additional data in memory to construct this from partial
-///    responses.
+///    It is kept pre-compressed alongside its ETag, so conditional and
+///    compressed requests are just as fast. We need to keep additional data
+///    in memory to construct this from partial responses.
 /// 2. A vector of thermostat readings. This is kept in cache to allow the
 ///    weather virtual thermostat to be pushed to the collection independently
 ///    of Ecobee thermostat readings.
@@ -34,10 +51,10 @@ static VERSION: u32 = 20200911;
 /// This utilizes the lazy_static crate to enable a simpler syntax for creating
 /// static variables that require runtime initialization, for example calling
 /// the new function.
-type StsString = Arc<RwLock<String>>;
+type StsCachedBody = Arc<RwLock<CachedBody>>;
 type StsNowResponse = Arc<RwLock<NowResponse>>;
 lazy_static! {
-    pub static ref NOW_STR: StsString = Arc::new(RwLock::new(String::new()));
+    pub static ref NOW_BODY: StsCachedBody = Arc::new(RwLock::new(CachedBody::default()));
     static ref NOW_RES: StsNowResponse = Arc::new(RwLock::new(NowResponse::default()));
     pub static ref REQWEST: reqwest::Client = reqwest::Client::new();
 }
@@ -47,6 +64,7 @@ struct NowResponse {
     forecast_daily: Vec<DailyCondition>,
     forecast_hourly: Vec<HourlyCondition>,
     thermostats: Vec<Thermostat>,
+    mold_risk: Vec<comfort::MoldRisk>,
 }
 
 impl Default for NowResponse {
@@ -55,6 +73,7 @@ impl Default for NowResponse {
             forecast_daily: vec![],
             forecast_hourly: vec![],
             thermostats: vec![],
+            mold_risk: vec![],
         }
     }
 }
@@ -79,66 +98,113 @@ impl Default for NowResponse {
 /// 3. Diesel - for storing data in the database and retrieving it.
 /// 4. Hyper - for HTTP server implementation.
 /// 5. Chrono - for date and time operations.
+/// 6. Tracing - for logging.
 fn main() {
+    dotenv().ok();
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let wants_help = args.first().map(String::as_str) == Some("help");
+    if wants_help || args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        print!("{}", cli::USAGE);
+        return;
+    }
+    let config_path = take_option(&mut args, "--config");
+    let config = match config::load(config_path.as_deref()) {
+        Ok(config) => config,
+        Err(errors) => {
+            // Logging is set up from the config, so these go straight to
+            // stderr.
+            eprintln!("Configuration is not valid:");
+            for error in errors {
+                eprintln!("  {}", error);
+            }
+            std::process::exit(1);
+        }
+    };
+    if args.iter().any(|arg| arg == "--print-config") {
+        print!("{}", config.redacted().to_toml());
+        return;
+    }
+    logging::init(&config.logging);
+    config::set(config);
+    match args.first().map(String::as_str) {
+        None | Some("serve") => serve(),
+        Some(_) => {
+            if let Err(err) = cli::run(&args) {
+                tracing::error!("{:#}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+/// # Serve
+/// Runs the migrations and one round of the worker, then starts the worker
+/// thread and the web server. Exits if any of that fails.
+fn serve() {
     if cfg!(feature = "offline") {
-        log_message("Starting in offline mode...");
+        tracing::info!("Starting in offline mode...");
     }
-    if check_env() && run_migrations() && worker::check() {
+    if run_migrations() && worker::check() {
         worker::start();
+        webhook::start();
         web::start();
+    } else {
+        // Exit with an error so systemd knows to restart us.
+        std::process::exit(1);
     }
 }
 
-/// # Check Environment
-/// Checks to see if all environment variables are set before starting
-/// the server so that way we don't have some threads panic and some not.
-/// Panics the main thread if any environment vars are missing.
-fn check_env() -> bool {
-    log_message("Checking env...");
-    dotenv().ok();
-    env::var("LISTEN_PORT").expect("LISTEN_PORT must be set");
-    env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    env::var("WEATHER_URL_DAILY").expect("WEATHER_URL_DAILY must be set");
-    env::var("WEATHER_URL_HOURLY").expect("WEATHER_URL_HOURLY must be set");
-    env::var("CORS_HOST").expect("CORS_HOST must be set");
-    env::var("ECOBEE_CLIENT_ID").expect("ECOBEE_CLIENT_ID must be set");
-    env::var("SHARED_ALBUM_ID").expect("SHARED_ALBUM_ID must be set");
-    env::var("PHOTO_CACHE_DIR").expect("PHOTO_CACHE_DIR must be set");
-    env::var("SHARED_SECRET").expect("SHARED_SECRET must be set");
-    true
+/// # Take Option
+/// Removes `--name value` (or `--name=value`) from the arguments and returns
+/// the value.
+fn take_option(args: &mut Vec<String>, name: &str) -> Option<String> {
+    let prefix = format!("{}=", name);
+    let index = args
+        .iter()
+        .position(|arg| arg == name || arg.starts_with(&prefix))?;
+    let arg = args.remove(index);
+    match arg.strip_prefix(&prefix) {
+        Some(value) => Some(value.to_string()),
+        None if index < args.len() => Some(args.remove(index)),
+        None => None,
+    }
 }
 
 /// # Run Database Migrations
 /// Updates the database with the latest table defintions. Returns `true`
 /// if it worked and `false` if it failed.
 fn run_migrations() -> bool {
-    log_message("Running migrations...");
+    tracing::info!("Running migrations...");
+    #[cfg(not(feature = "sqlite"))]
     embed_migrations!();
+    #[cfg(feature = "sqlite")]
+    embed_migrations!("migrations_sqlite");
     let connection = establish_connection();
     match embedded_migrations::run(&connection) {
-        Ok(_) => true,
+        Ok(_) => {
+            health::migrated();
+            true
+        }
         Err(message) => {
-            log_error(&format!("Migrations failed! {:?}", message));
+            tracing::error!("Migrations failed! {:?}", message);
             false
         }
     }
 }
 
 /// # Establish Connection
-/// Returns a database connection from a connection string in an
-/// environment variable. Diesel crate boilerplate code.
-fn establish_connection() -> PgConnection {
-    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+/// Returns a database connection from the pool, retrying a few times before
+/// giving up. For the worker and the command line, which cannot do anything
+/// without the database; the web server uses `db::run` instead.
+fn establish_connection() -> db::Connection {
     for _ in 1..5 {
-        let connection_result = PgConnection::establish(&database_url);
-        if let Ok(connection) = connection_result {
-            return connection;
-        } else if let Err(err) = connection_result {
-            log_error(&format!("{:?}", err));
+        match db::connection() {
+            Ok(connection) => return connection,
+            Err(err) => tracing::warn!("Could not connect to the database: {:?}", err),
         }
         std::thread::sleep(std::time::Duration::from_secs(2));
     }
-    panic!("Error connecting to {}", database_url);
+    panic!("Error connecting to the database");
 }
 
 /// # Parse JSON
@@ -151,22 +217,8 @@ where
     match result {
         Ok(data) => Ok(data),
         Err(err) => {
-            log_error(data);
-            log_error(&format!("{}", err));
+            tracing::error!(data, "Could not parse JSON: {}", err);
             Err(err.into())
         }
     }
 }
-
-/// # Log Message
-/// Logs a message to the journal.
-pub fn log_message(message: &str) {
-    println!("msg [{}] {}", chrono::Utc::now(), message);
-}
-
-/// # Log Error
-/// Just a convenience method to make all error logs go through one method. This makes all log
-/// entries have the date prepended to them.
-pub fn log_error(message: &str) {
-    eprintln!("err [{}] {}", chrono::Utc::now(), message);
-}
@@ -0,0 +1,233 @@
+use lazy_static::lazy_static;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+// this file covers the metrics served on `/metrics` in Prometheus' text format
+
+pub const HTTP_REQUESTS: &str = "therm_hub_http_requests_total";
+pub const HTTP_REQUEST_DURATION: &str = "therm_hub_http_request_duration_seconds";
+pub const JOB_DURATION: &str = "therm_hub_worker_job_duration_seconds";
+pub const JOB_FAILURES: &str = "therm_hub_worker_job_failures_total";
+pub const TOKEN_REFRESHES: &str = "therm_hub_ecobee_token_refreshes_total";
+pub const WEATHER_FETCH_DURATION: &str = "therm_hub_weather_fetch_duration_seconds";
+pub const DB_CONNECTION_ERRORS: &str = "therm_hub_db_connection_errors_total";
+pub const ALERT_NOTIFICATIONS: &str = "therm_hub_alert_notifications_total";
+pub const WEBHOOK_DELIVERIES: &str = "therm_hub_webhook_deliveries_total";
+pub const TSDB_WRITES: &str = "therm_hub_tsdb_writes_total";
+pub const TEMPERATURE: &str = "therm_hub_temperature_fahrenheit";
+pub const HUMIDITY: &str = "therm_hub_relative_humidity_percent";
+
+/// Upper bounds of the histogram buckets, in seconds. Covers quick HTTP
+/// requests through to slow worker cycles.
+const BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+];
+
+/// Name, type and help text of every metric, in the order they are rendered.
+const DEFINITIONS: &[(&str, &str, &str)] = &[
+    (
+        TEMPERATURE,
+        "gauge",
+        "Latest temperature reported by each sensor.",
+    ),
+    (
+        HUMIDITY,
+        "gauge",
+        "Latest relative humidity reported by each hygrostat.",
+    ),
+    (
+        HTTP_REQUESTS,
+        "counter",
+        "HTTP requests served, by route and status.",
+    ),
+    (
+        HTTP_REQUEST_DURATION,
+        "histogram",
+        "Time spent serving HTTP requests, by route.",
+    ),
+    (JOB_DURATION, "histogram", "Time spent on each worker job."),
+    (JOB_FAILURES, "counter", "Worker jobs that failed, by job."),
+    (
+        TOKEN_REFRESHES,
+        "counter",
+        "Ecobee access token refreshes, by result.",
+    ),
+    (
+        WEATHER_FETCH_DURATION,
+        "histogram",
+        "Time spent fetching forecasts from weather.gov.",
+    ),
+    (
+        DB_CONNECTION_ERRORS,
+        "counter",
+        "Failed attempts to connect to the database.",
+    ),
+    (
+        ALERT_NOTIFICATIONS,
+        "counter",
+        "Alert notifications sent, by channel and result.",
+    ),
+    (
+        WEBHOOK_DELIVERIES,
+        "counter",
+        "Webhook delivery attempts, by event and result.",
+    ),
+    (
+        TSDB_WRITES,
+        "counter",
+        "Batches of readings written to InfluxDB or Prometheus, by target and result.",
+    ),
+];
+
+type Key = (&'static str, Vec<(String, String)>);
+
+struct Histogram {
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+struct Registry {
+    counters: BTreeMap<Key, f64>,
+    histograms: BTreeMap<Key, Histogram>,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        let mut counters = BTreeMap::new();
+        // Unlabelled counters start at zero, so they show up before anything goes wrong.
+        counters.insert((DB_CONNECTION_ERRORS, vec![]), 0.0);
+        Self {
+            counters,
+            histograms: BTreeMap::new(),
+        }
+    }
+}
+
+type StsRegistry = Arc<Mutex<Registry>>;
+lazy_static! {
+    static ref REGISTRY: StsRegistry = Arc::new(Mutex::new(Registry::default()));
+}
+
+fn key(name: &'static str, labels: &[(&str, &str)]) -> Key {
+    let labels = labels
+        .iter()
+        .map(|(label, value)| (label.to_string(), value.to_string()))
+        .collect();
+    (name, labels)
+}
+
+/// # Increment
+/// Adds one to a counter.
+pub fn inc(name: &'static str, labels: &[(&str, &str)]) {
+    if let Ok(mut registry) = REGISTRY.lock() {
+        *registry.counters.entry(key(name, labels)).or_insert(0.0) += 1.0;
+    }
+}
+
+/// # Observe
+/// Records a duration, in seconds, in a histogram.
+pub fn observe(name: &'static str, labels: &[(&str, &str)], seconds: f64) {
+    if let Ok(mut registry) = REGISTRY.lock() {
+        let histogram = registry
+            .histograms
+            .entry(key(name, labels))
+            .or_insert_with(|| Histogram {
+                buckets: vec![0; BUCKETS.len()],
+                sum: 0.0,
+                count: 0,
+            });
+        for (i, bound) in BUCKETS.iter().enumerate() {
+            if seconds <= *bound {
+                histogram.buckets[i] += 1;
+            }
+        }
+        histogram.sum += seconds;
+        histogram.count += 1;
+    }
+}
+
+/// # Observe Since
+/// Records the time since `start` in a histogram.
+pub fn observe_since(name: &'static str, labels: &[(&str, &str)], start: Instant) {
+    observe(name, labels, start.elapsed().as_secs_f64());
+}
+
+/// # Render
+/// Renders every metric in the Prometheus text exposition format. Sensor
+/// gauges are read from the latest `/now` data rather than being recorded.
+pub fn render() -> String {
+    let mut out = String::new();
+    let registry = match REGISTRY.lock() {
+        Ok(registry) => registry,
+        Err(_) => return out,
+    };
+    for (name, kind, help) in DEFINITIONS {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        if *name == TEMPERATURE || *name == HUMIDITY {
+            render_sensors(&mut out, name);
+        }
+        for ((_, labels), value) in registry.counters.iter().filter(|((n, _), _)| n == name) {
+            let _ = writeln!(out, "{}{} {}", name, format_labels(labels, None), value);
+        }
+        for ((_, labels), histogram) in registry.histograms.iter().filter(|((n, _), _)| n == name) {
+            for (bound, count) in BUCKETS.iter().zip(&histogram.buckets) {
+                let le = bound.to_string();
+                let labels = format_labels(labels, Some(&le));
+                let _ = writeln!(out, "{}_bucket{} {}", name, labels, count);
+            }
+            let labels_inf = format_labels(labels, Some("+Inf"));
+            let labels = format_labels(labels, None);
+            let _ = writeln!(out, "{}_bucket{} {}", name, labels_inf, histogram.count);
+            let _ = writeln!(out, "{}_sum{} {}", name, labels, histogram.sum);
+            let _ = writeln!(out, "{}_count{} {}", name, labels, histogram.count);
+        }
+    }
+    out
+}
+
+/// The current reading of every sensor, in degrees F or percent. Every
+/// sensor is stored in 1/10 degrees, weather.gov included since the
+/// `weather_gov_tenths` migration, so `fahrenheit()` is right for all of them.
+fn render_sensors(out: &mut String, name: &str) {
+    let now_res = match crate::NOW_RES.read() {
+        Ok(now_res) => now_res,
+        Err(_) => return,
+    };
+    for thermostat in &now_res.thermostats {
+        let labels = format_labels(&[("sensor".to_string(), thermostat.name.clone())], None);
+        let value = if name == TEMPERATURE {
+            thermostat.fahrenheit()
+        } else {
+            thermostat.humidity().map(f64::from)
+        };
+        if let Some(value) = value {
+            let _ = writeln!(out, "{}{} {}", name, labels, value);
+        }
+    }
+}
+
+fn format_labels(labels: &[(String, String)], le: Option<&str>) -> String {
+    let mut pairs: Vec<String> = labels
+        .iter()
+        .map(|(label, value)| format!("{}=\"{}\"", label, escape(value)))
+        .collect();
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{}\"", le));
+    }
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,169 @@
+use crate::config::Mqtt;
+use native_tls::TlsConnector;
+use std::convert::TryFrom;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+// this file covers the little of MQTT 3.1.1 that publishing needs: connect,
+// publish retained messages at QoS 1 and disconnect
+
+/// How long to wait on the broker before giving up.
+const TIMEOUT: Duration = Duration::from_secs(30);
+/// Seconds the broker waits to hear from us. Publishing takes far less.
+const KEEP_ALIVE: u16 = 60;
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const DISCONNECT: u8 = 0xe0;
+/// PUBLISH flags: QoS 1 and retained.
+const QOS_1: u8 = 0x02;
+const RETAIN: u8 = 0x01;
+
+trait Stream: Read + Write {}
+impl<T: Read + Write> Stream for T {}
+
+/// # Client
+/// A connection to the broker.
+pub struct Client {
+    stream: Box<dyn Stream>,
+    next_id: u16,
+}
+
+/// Appends a length-prefixed string.
+fn string(body: &mut Vec<u8>, value: &str) -> anyhow::Result<()> {
+    let length = u16::try_from(value.len())
+        .map_err(|_| anyhow::anyhow!("a {} byte string is too long for MQTT", value.len()))?;
+    body.extend_from_slice(&length.to_be_bytes());
+    body.extend_from_slice(value.as_bytes());
+    Ok(())
+}
+
+/// Why the broker refused to connect, from CONNACK's return code.
+fn refusal(code: u8) -> &'static str {
+    match code {
+        1 => "it does not speak MQTT 3.1.1",
+        2 => "it rejected the client id",
+        3 => "it is unavailable",
+        4 => "the username or password is wrong",
+        5 => "we are not authorized",
+        _ => "for an unknown reason",
+    }
+}
+
+impl Client {
+    /// # Connect
+    /// Connects and logs in with a clean session.
+    pub fn connect(settings: &Mqtt) -> anyhow::Result<Self> {
+        let tcp = TcpStream::connect((settings.host.as_str(), settings.port))?;
+        tcp.set_read_timeout(Some(TIMEOUT))?;
+        tcp.set_write_timeout(Some(TIMEOUT))?;
+        let stream: Box<dyn Stream> = if settings.tls {
+            Box::new(
+                TlsConnector::new()?
+                    .connect(&settings.host, tcp)
+                    .map_err(|err| anyhow::anyhow!("TLS with {} failed: {}", settings.host, err))?,
+            )
+        } else {
+            Box::new(tcp)
+        };
+        let mut client = Self { stream, next_id: 0 };
+
+        let mut body = Vec::new();
+        string(&mut body, "MQTT")?;
+        // Protocol level 4 is MQTT 3.1.1.
+        body.push(4);
+        let mut flags = 0x02; // clean session
+        if settings.username.is_some() {
+            flags |= 0x80;
+        }
+        if settings.password.is_some() {
+            flags |= 0x40;
+        }
+        body.push(flags);
+        body.extend_from_slice(&KEEP_ALIVE.to_be_bytes());
+        string(&mut body, &settings.client_id)?;
+        if let Some(username) = &settings.username {
+            string(&mut body, username)?;
+        }
+        if let Some(password) = &settings.password {
+            string(&mut body, password)?;
+        }
+        client.send(CONNECT, &body)?;
+
+        let (kind, body) = client.receive()?;
+        if kind != CONNACK || body.len() != 2 {
+            anyhow::bail!("the broker did not acknowledge the connection");
+        }
+        match body[1] {
+            0 => Ok(client),
+            code => anyhow::bail!("the broker refused the connection: {}", refusal(code)),
+        }
+    }
+
+    /// # Publish
+    /// Publishes a retained message and waits for the broker to take it.
+    pub fn publish(&mut self, topic: &str, payload: &[u8]) -> anyhow::Result<()> {
+        // Packet ids must not be 0.
+        self.next_id = self.next_id.checked_add(1).unwrap_or(1);
+        let id = self.next_id.to_be_bytes();
+        let mut body = Vec::new();
+        string(&mut body, topic)?;
+        body.extend_from_slice(&id);
+        body.extend_from_slice(payload);
+        self.send(PUBLISH | QOS_1 | RETAIN, &body)?;
+        loop {
+            // Nothing is subscribed to, so anything but our PUBACK is skipped.
+            let (kind, body) = self.receive()?;
+            if kind == PUBACK && body.get(..2) == Some(&id[..]) {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Says goodbye. The broker hanging up first does not matter.
+    pub fn disconnect(mut self) {
+        self.send(DISCONNECT, &[]).ok();
+    }
+
+    fn send(&mut self, header: u8, body: &[u8]) -> anyhow::Result<()> {
+        let mut packet = vec![header];
+        // The remaining length, 7 bits at a time, lowest first.
+        let mut length = body.len();
+        loop {
+            let mut byte = (length % 128) as u8;
+            length /= 128;
+            if length > 0 {
+                byte |= 0x80;
+            }
+            packet.push(byte);
+            if length == 0 {
+                break;
+            }
+        }
+        packet.extend_from_slice(body);
+        self.stream.write_all(&packet)?;
+        self.stream.flush()?;
+        Ok(())
+    }
+
+    /// Reads a packet: its type (with the flags cleared) and its body.
+    fn receive(&mut self) -> anyhow::Result<(u8, Vec<u8>)> {
+        let mut byte = [0u8];
+        self.stream.read_exact(&mut byte)?;
+        let kind = byte[0] & 0xf0;
+        let mut length = 0usize;
+        for shift in 0..4 {
+            self.stream.read_exact(&mut byte)?;
+            length |= usize::from(byte[0] & 0x7f) << (7 * shift);
+            if byte[0] & 0x80 == 0 {
+                let mut body = vec![0u8; length];
+                self.stream.read_exact(&mut body)?;
+                return Ok((kind, body));
+            }
+        }
+        anyhow::bail!("the broker sent a packet that is too long")
+    }
+}
@@ -0,0 +1,207 @@
+use crate::config::Mqtt;
+use crate::worker::{DailyCondition, HourlyCondition, INTERVAL};
+use crate::Thermostat;
+use chrono::{DateTime, Utc};
+use client::Client;
+use serde::Serialize;
+
+mod client;
+
+// this file covers publishing readings and the forecast to an MQTT broker,
+// with the Home Assistant discovery configs that make them show up as
+// sensors
+
+/// Ecobee readings and the weather.gov reading are told apart by name.
+const WEATHER_GOV: &str = "weather.gov";
+
+/// # State
+/// What is published for a sensor, at `<topic_prefix>/sensor/<id>/state`.
+#[derive(Serialize)]
+struct State<'a> {
+    name: &'a str,
+    /// In degrees F. Missing for sensors that only measure humidity.
+    temperature: Option<f64>,
+    /// In percent. Missing for sensors that do not measure it.
+    humidity: Option<i32>,
+    time: DateTime<Utc>,
+}
+
+/// # Forecast State
+/// What is published at `<topic_prefix>/forecast`.
+#[derive(Serialize)]
+struct ForecastState<'a> {
+    /// The hourly forecast's condition for now, like "Partly Sunny".
+    condition: Option<&'a str>,
+    forecast_hourly: &'a [HourlyCondition],
+    forecast_daily: &'a [DailyCondition],
+}
+
+/// # Discovery
+/// A Home Assistant MQTT discovery config for one sensor.
+#[derive(Serialize)]
+struct Discovery<'a> {
+    name: &'a str,
+    unique_id: String,
+    object_id: String,
+    state_topic: &'a str,
+    value_template: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    json_attributes_topic: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_class: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unit_of_measurement: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    state_class: Option<&'a str>,
+    /// Seconds without a new state before Home Assistant shows the sensor as
+    /// unavailable.
+    expire_after: i64,
+    device: Device<'a>,
+}
+
+#[derive(Serialize)]
+struct Device<'a> {
+    identifiers: [String; 1],
+    name: &'a str,
+    manufacturer: &'a str,
+    model: &'a str,
+}
+
+/// # Slug
+/// A sensor's name as a topic level and id: lowercase letters, digits and
+/// underscores.
+fn slug(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn device(name: &str) -> Device<'_> {
+    if name == WEATHER_GOV {
+        Device {
+            identifiers: [String::from("therm_hub_weather_gov")],
+            name,
+            manufacturer: "weather.gov",
+            model: "Forecast",
+        }
+    } else {
+        Device {
+            identifiers: [format!("therm_hub_{}", slug(name))],
+            name,
+            manufacturer: "Ecobee",
+            model: "Sensor",
+        }
+    }
+}
+
+/// # Publish
+/// Publishes this round's readings and the forecast as retained messages,
+/// with discovery configs for Home Assistant unless they are turned off.
+/// Does nothing when MQTT is not configured.
+pub fn publish(
+    readings: &[Thermostat],
+    condition: Option<&str>,
+    forecast_hourly: &[HourlyCondition],
+    forecast_daily: &[DailyCondition],
+) -> anyhow::Result<()> {
+    let config = crate::config::get();
+    let settings = match &config.mqtt {
+        Some(settings) => settings,
+        None => return Ok(()),
+    };
+    let mut client = Client::connect(settings)?;
+    for reading in readings {
+        publish_reading(&mut client, settings, reading)?;
+    }
+    let topic = format!("{}/forecast", settings.topic_prefix);
+    let forecast = ForecastState {
+        condition,
+        forecast_hourly,
+        forecast_daily,
+    };
+    client.publish(&topic, &serde_json::to_vec(&forecast)?)?;
+    if settings.discovery {
+        let config = Discovery {
+            name: "Forecast",
+            unique_id: String::from("therm_hub_forecast"),
+            object_id: String::from("therm_hub_forecast"),
+            state_topic: &topic,
+            value_template: "{{ value_json.condition }}",
+            json_attributes_topic: Some(&topic),
+            device_class: None,
+            unit_of_measurement: None,
+            state_class: None,
+            expire_after: 3 * INTERVAL,
+            device: device(WEATHER_GOV),
+        };
+        client.publish(
+            &format!(
+                "{}/sensor/therm_hub_forecast/config",
+                settings.discovery_prefix
+            ),
+            &serde_json::to_vec(&config)?,
+        )?;
+    }
+    client.disconnect();
+    tracing::debug!("Published {} readings to MQTT", readings.len());
+    Ok(())
+}
+
+fn publish_reading(
+    client: &mut Client,
+    settings: &Mqtt,
+    reading: &Thermostat,
+) -> anyhow::Result<()> {
+    let id = slug(&reading.name);
+    let topic = format!("{}/sensor/{}/state", settings.topic_prefix, id);
+    let state = State {
+        name: &reading.name,
+        temperature: reading.fahrenheit(),
+        humidity: reading.humidity(),
+        time: reading.time(),
+    };
+    client.publish(&topic, &serde_json::to_vec(&state)?)?;
+    if !settings.discovery {
+        return Ok(());
+    }
+
+    let mut sensors = Vec::new();
+    if state.temperature.is_some() {
+        sensors.push(("temperature", "Temperature", "°F"));
+    }
+    if state.humidity.is_some() {
+        sensors.push(("humidity", "Humidity", "%"));
+    }
+    for (metric, label, unit) in sensors {
+        let name = format!("{} {}", reading.name, label);
+        let value_template = format!("{{{{ value_json.{} }}}}", metric);
+        let unique_id = format!("therm_hub_{}_{}", id, metric);
+        let config = Discovery {
+            name: &name,
+            object_id: unique_id.clone(),
+            unique_id,
+            state_topic: &topic,
+            value_template: &value_template,
+            json_attributes_topic: None,
+            device_class: Some(metric),
+            unit_of_measurement: Some(unit),
+            state_class: Some("measurement"),
+            expire_after: 3 * INTERVAL,
+            device: device(&reading.name),
+        };
+        client.publish(
+            &format!(
+                "{}/sensor/{}/config",
+                settings.discovery_prefix, config.unique_id
+            ),
+            &serde_json::to_vec(&config)?,
+        )?;
+    }
+    Ok(())
+}
@@ -0,0 +1,384 @@
+use super::schema::thermostats_hourly;
+use crate::comfort::Comfort;
+use crate::db::DbConnection;
+use crate::therm::NO_TEMPERATURE;
+use chrono::{DateTime, Duration, NaiveDateTime, NaiveTime, TimeZone, Timelike, Utc};
+use diesel::prelude::*;
+use diesel::sql_types::{Int4, Int8, Nullable, Text, Timestamp};
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+
+// this file covers rolling readings up into hourly and daily summaries, and
+// deleting old readings once they are summarized
+
+/// `auto` uses raw readings for ranges up to this many days...
+const AUTO_RAW_DAYS: i64 = 3;
+/// ...and hourly summaries up to this many, then daily summaries.
+const AUTO_HOURLY_DAYS: i64 = 90;
+
+/// # Resolution
+/// Which table readings are read from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Resolution {
+    Raw,
+    Hourly,
+    Daily,
+}
+
+impl Resolution {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "raw" => Some(Resolution::Raw),
+            "hourly" => Some(Resolution::Hourly),
+            "daily" => Some(Resolution::Daily),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Resolution::Raw => "raw",
+            Resolution::Hourly => "hourly",
+            Resolution::Daily => "daily",
+        }
+    }
+
+    fn table(self) -> &'static str {
+        match self {
+            Resolution::Raw => "thermostats",
+            Resolution::Hourly => "thermostats_hourly",
+            Resolution::Daily => "thermostats_daily",
+        }
+    }
+
+    /// # Choose
+    /// Picks the resolution for a range: raw readings for a few days, hourly
+    /// summaries for a few months and daily summaries beyond that. A
+    /// resolution is skipped when retention has already deleted part of the
+    /// range from it.
+    pub fn choose(start_date: &DateTime<Utc>, end_date: &DateTime<Utc>) -> Self {
+        let days = (*end_date - *start_date).num_days();
+        let mut resolution = if days <= AUTO_RAW_DAYS {
+            Resolution::Raw
+        } else if days <= AUTO_HOURLY_DAYS {
+            Resolution::Hourly
+        } else {
+            Resolution::Daily
+        };
+        if resolution == Resolution::Raw && is_expired(Resolution::Raw, start_date) {
+            resolution = Resolution::Hourly;
+        }
+        if resolution == Resolution::Hourly && is_expired(Resolution::Hourly, start_date) {
+            resolution = Resolution::Daily;
+        }
+        resolution
+    }
+}
+
+/// How many days a table keeps rows for, from `[retention]`. Unset or `0`
+/// keeps them forever. Daily summaries are always kept.
+fn retention(resolution: Resolution) -> Option<i64> {
+    let retention = &crate::config::get().retention;
+    match resolution {
+        Resolution::Raw => retention.raw_days,
+        Resolution::Hourly => retention.hourly_days,
+        Resolution::Daily => None,
+    }
+    .filter(|days| *days > 0)
+}
+
+/// # Kept Since
+/// How far back a table still has rows, or `None` if it keeps them forever.
+pub fn kept_since(resolution: Resolution) -> Option<DateTime<Utc>> {
+    retention(resolution).map(|days| Utc::now() - Duration::days(days))
+}
+
+fn is_expired(resolution: Resolution, date: &DateTime<Utc>) -> bool {
+    match kept_since(resolution) {
+        Some(since) => *date < since,
+        None => false,
+    }
+}
+
+/// # Rollup
+/// A summary of one sensor's readings over an hour or a day. `temperature`
+/// and `relative_humidity` are the averages, so a rollup reads like a
+/// reading. Humidity-only sensors keep the -10000 temperature.
+#[derive(Clone, Debug, QueryableByName)]
+#[table_name = "thermostats_hourly"]
+pub struct Rollup {
+    pub id: i32,
+    pub name: String,
+    /// The start of the hour or day, in UTC.
+    time: NaiveDateTime,
+    pub is_hygrostat: bool,
+    pub samples: i32,
+    pub temperature: i32,
+    pub temperature_min: i32,
+    pub temperature_max: i32,
+    pub relative_humidity: i32,
+    pub relative_humidity_min: i32,
+    pub relative_humidity_max: i32,
+}
+
+#[derive(QueryableByName)]
+struct Latest {
+    #[sql_type = "Nullable<Timestamp>"]
+    time: Option<NaiveDateTime>,
+}
+
+// The few spots where Postgres and SQLite spell things differently.
+#[cfg(not(feature = "sqlite"))]
+const HOUR: &str = "date_trunc('hour', time)";
+#[cfg(not(feature = "sqlite"))]
+const DAY: &str = "date_trunc('day', time)";
+#[cfg(not(feature = "sqlite"))]
+const ANY: &str = "bool_or";
+#[cfg(feature = "sqlite")]
+const HOUR: &str = "strftime('%Y-%m-%d %H:00:00', time)";
+#[cfg(feature = "sqlite")]
+const DAY: &str = "strftime('%Y-%m-%d 00:00:00', time)";
+#[cfg(feature = "sqlite")]
+const ANY: &str = "MAX";
+
+// Hand written so hygrostats get `comfort`, worked out from the averages.
+impl Serialize for Rollup {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("Rollup", 12)?;
+        state.serialize_field("id", &self.id)?;
+        state.serialize_field("name", &self.name)?;
+        state.serialize_field("time", &self.time)?;
+        state.serialize_field("is_hygrostat", &self.is_hygrostat)?;
+        state.serialize_field("samples", &self.samples)?;
+        state.serialize_field("temperature", &self.temperature)?;
+        state.serialize_field("temperature_min", &self.temperature_min)?;
+        state.serialize_field("temperature_max", &self.temperature_max)?;
+        state.serialize_field("relative_humidity", &self.relative_humidity)?;
+        state.serialize_field("relative_humidity_min", &self.relative_humidity_min)?;
+        state.serialize_field("relative_humidity_max", &self.relative_humidity_max)?;
+        match Comfort::new(self.fahrenheit(), self.humidity()) {
+            Some(comfort) => state.serialize_field("comfort", &comfort)?,
+            None => state.skip_field("comfort")?,
+        }
+        state.end()
+    }
+}
+
+impl Rollup {
+    pub fn time(&self) -> DateTime<Utc> {
+        Utc.from_utc_datetime(&self.time)
+    }
+
+    /// # Fahrenheit
+    /// The average temperature in degrees F, or `None` for sensors that only
+    /// measure humidity.
+    pub fn fahrenheit(&self) -> Option<f64> {
+        if self.temperature > NO_TEMPERATURE {
+            Some(f64::from(self.temperature) / 10.0)
+        } else {
+            None
+        }
+    }
+
+    /// # Humidity
+    /// The average relative humidity in percent, or `None` for sensors that
+    /// do not measure it.
+    pub fn humidity(&self) -> Option<i32> {
+        if self.is_hygrostat {
+            Some(self.relative_humidity)
+        } else {
+            None
+        }
+    }
+
+    /// # Query Page
+    /// Like `Thermostat::query_page`, for the hourly or daily table.
+    pub fn query_page(
+        connection: &DbConnection,
+        resolution: Resolution,
+        start_date: &DateTime<Utc>,
+        end_date: &DateTime<Utc>,
+        after: Option<(DateTime<Utc>, i32)>,
+        limit: i64,
+    ) -> QueryResult<Vec<Self>> {
+        // Ids start at 1, so this is the same as no cursor.
+        let (after_time, after_id) = after.unwrap_or((*start_date, 0));
+        let query = diesel::sql_query(format!(
+            "SELECT * FROM {} WHERE time >= $1 AND time <= $2 AND (time, id) > ($3, $4) \
+             ORDER BY time, id LIMIT $5",
+            resolution.table()
+        ))
+        .bind::<Timestamp, _>(start_date.naive_utc())
+        .bind::<Timestamp, _>(end_date.naive_utc())
+        .bind::<Timestamp, _>(after_time.naive_utc())
+        .bind::<Int4, _>(after_id)
+        .bind::<Int8, _>(limit);
+        crate::db::log_query(&query);
+        query.load(connection)
+    }
+
+    /// # Query Sensor
+    /// One sensor's summaries from `start_date` up to, but not including,
+    /// `end_date`, ordered by time.
+    pub fn query_sensor(
+        connection: &DbConnection,
+        resolution: Resolution,
+        name: &str,
+        start_date: &DateTime<Utc>,
+        end_date: &DateTime<Utc>,
+    ) -> QueryResult<Vec<Self>> {
+        let query = diesel::sql_query(format!(
+            "SELECT * FROM {} WHERE name = $1 AND time >= $2 AND time < $3 ORDER BY time",
+            resolution.table()
+        ))
+        .bind::<Text, _>(name)
+        .bind::<Timestamp, _>(start_date.naive_utc())
+        .bind::<Timestamp, _>(end_date.naive_utc());
+        crate::db::log_query(&query);
+        query.load(connection)
+    }
+}
+
+/// The start of the newest bucket in a summary table. That bucket may have
+/// been summarized before the hour or day was over.
+fn latest(connection: &DbConnection, resolution: Resolution) -> QueryResult<Option<NaiveDateTime>> {
+    let query = diesel::sql_query(format!(
+        "SELECT MAX(time) AS time FROM {}",
+        resolution.table()
+    ));
+    crate::db::log_query(&query);
+    Ok(query.get_result::<Latest>(connection)?.time)
+}
+
+/// The start of the newest bucket in a summary table, or the epoch if it is
+/// empty.
+fn latest_or_epoch(
+    connection: &DbConnection,
+    resolution: Resolution,
+) -> QueryResult<NaiveDateTime> {
+    Ok(latest(connection, resolution)?.unwrap_or(DateTime::UNIX_EPOCH.naive_utc()))
+}
+
+/// # Roll Up Hours
+/// Summarizes raw readings from the hourly bucket starting at `since`
+/// onward.
+fn roll_up_hours(connection: &DbConnection, since: NaiveDateTime) -> QueryResult<usize> {
+    let query = diesel::sql_query(format!(
+        "INSERT INTO thermostats_hourly (name, time, is_hygrostat, samples, \
+           temperature, temperature_min, temperature_max, \
+           relative_humidity, relative_humidity_min, relative_humidity_max) \
+         SELECT name, {hour}, {any}(is_hygrostat), COUNT(*), \
+           COALESCE(ROUND(AVG(temperature) FILTER (WHERE temperature > -1000)), -10000), \
+           COALESCE(MIN(temperature) FILTER (WHERE temperature > -1000), -10000), \
+           COALESCE(MAX(temperature) FILTER (WHERE temperature > -1000), -10000), \
+           ROUND(AVG(relative_humidity)), MIN(relative_humidity), MAX(relative_humidity) \
+         FROM thermostats WHERE time >= $1 \
+         GROUP BY name, {hour} \
+         ON CONFLICT (name, time) DO UPDATE SET \
+           is_hygrostat = EXCLUDED.is_hygrostat, samples = EXCLUDED.samples, \
+           temperature = EXCLUDED.temperature, \
+           temperature_min = EXCLUDED.temperature_min, \
+           temperature_max = EXCLUDED.temperature_max, \
+           relative_humidity = EXCLUDED.relative_humidity, \
+           relative_humidity_min = EXCLUDED.relative_humidity_min, \
+           relative_humidity_max = EXCLUDED.relative_humidity_max",
+        hour = HOUR,
+        any = ANY,
+    ))
+    .bind::<Timestamp, _>(since);
+    crate::db::log_query(&query);
+    query.execute(connection)
+}
+
+/// # Roll Up Days
+/// Summarizes hourly buckets from the daily bucket starting at `since`
+/// onward. Averages are weighted by how many readings each hour had.
+fn roll_up_days(connection: &DbConnection, since: NaiveDateTime) -> QueryResult<usize> {
+    let query = diesel::sql_query(format!(
+        "INSERT INTO thermostats_daily (name, time, is_hygrostat, samples, \
+           temperature, temperature_min, temperature_max, \
+           relative_humidity, relative_humidity_min, relative_humidity_max) \
+         SELECT name, {day}, {any}(is_hygrostat), SUM(samples), \
+           COALESCE(ROUND(SUM(CAST(temperature AS DOUBLE PRECISION) * samples) \
+             FILTER (WHERE temperature > -1000) \
+             / SUM(samples) FILTER (WHERE temperature > -1000)), -10000), \
+           COALESCE(MIN(temperature_min) FILTER (WHERE temperature > -1000), -10000), \
+           COALESCE(MAX(temperature_max) FILTER (WHERE temperature > -1000), -10000), \
+           ROUND(SUM(CAST(relative_humidity AS DOUBLE PRECISION) * samples) / SUM(samples)), \
+           MIN(relative_humidity_min), MAX(relative_humidity_max) \
+         FROM thermostats_hourly WHERE time >= $1 \
+         GROUP BY name, {day} \
+         ON CONFLICT (name, time) DO UPDATE SET \
+           is_hygrostat = EXCLUDED.is_hygrostat, samples = EXCLUDED.samples, \
+           temperature = EXCLUDED.temperature, \
+           temperature_min = EXCLUDED.temperature_min, \
+           temperature_max = EXCLUDED.temperature_max, \
+           relative_humidity = EXCLUDED.relative_humidity, \
+           relative_humidity_min = EXCLUDED.relative_humidity_min, \
+           relative_humidity_max = EXCLUDED.relative_humidity_max",
+        day = DAY,
+        any = ANY,
+    ))
+    .bind::<Timestamp, _>(since);
+    crate::db::log_query(&query);
+    query.execute(connection)
+}
+
+/// # Expire
+/// Deletes rows older than the table's retention, but never rows that are
+/// not summarized in the next table yet. Returns how many were deleted.
+fn expire(
+    connection: &DbConnection,
+    resolution: Resolution,
+    summary: Resolution,
+) -> QueryResult<usize> {
+    let days = match retention(resolution) {
+        Some(days) => days,
+        None => return Ok(0),
+    };
+    let summarized = match latest(connection, summary)? {
+        Some(summarized) => summarized,
+        None => return Ok(0),
+    };
+    let cutoff = (Utc::now() - Duration::days(days))
+        .naive_utc()
+        .min(summarized);
+    let query = diesel::sql_query(format!(
+        "DELETE FROM {} WHERE time < $1",
+        resolution.table()
+    ))
+    .bind::<Timestamp, _>(cutoff);
+    crate::db::log_query(&query);
+    query.execute(connection)
+}
+
+/// # Summarize Since
+/// Summarizes again every hour and day from the one `since` falls in, for
+/// readings that arrive after their hour was summarized.
+pub fn summarize_since(connection: &DbConnection, since: NaiveDateTime) -> QueryResult<()> {
+    let day = since.date().and_time(NaiveTime::MIN);
+    roll_up_hours(connection, day + Duration::hours(i64::from(since.hour())))?;
+    roll_up_days(connection, day)?;
+    Ok(())
+}
+
+/// # Compact
+/// The worker's rollup job: brings the hourly and daily summaries up to
+/// date, then applies `RETAIN_RAW_DAYS` and `RETAIN_HOURLY_DAYS`. Daily
+/// summaries are kept forever.
+pub fn compact(connection: &DbConnection) -> QueryResult<()> {
+    roll_up_hours(connection, latest_or_epoch(connection, Resolution::Hourly)?)?;
+    roll_up_days(connection, latest_or_epoch(connection, Resolution::Daily)?)?;
+    let raw = expire(connection, Resolution::Raw, Resolution::Hourly)?;
+    let hourly = expire(connection, Resolution::Hourly, Resolution::Daily)?;
+    if raw + hourly > 0 {
+        tracing::info!(
+            "Deleted {} raw readings and {} hourly summaries past retention",
+            raw,
+            hourly
+        );
+    }
+    Ok(())
+}
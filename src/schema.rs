@@ -1,3 +1,43 @@
+table! {
+    alert_history (id) {
+        id -> Int4,
+        rule_id -> Int4,
+        rule_name -> Varchar,
+        sensor -> Varchar,
+        state -> Varchar,
+        value -> Nullable<Float8>,
+        message -> Varchar,
+        time -> Timestamp,
+    }
+}
+
+table! {
+    alert_rules (id) {
+        id -> Int4,
+        name -> Varchar,
+        sensor -> Varchar,
+        condition -> Varchar,
+        metric -> Varchar,
+        threshold -> Float8,
+        hysteresis -> Float8,
+        duration_seconds -> Int4,
+        webhook_url -> Nullable<Varchar>,
+        email -> Nullable<Varchar>,
+        enabled -> Bool,
+        updated -> Timestamp,
+    }
+}
+
+table! {
+    alert_states (rule_id, sensor) {
+        rule_id -> Int4,
+        sensor -> Varchar,
+        state -> Varchar,
+        since -> Timestamp,
+        value -> Nullable<Float8>,
+    }
+}
+
 table! {
     ecobee_token (id) {
         id -> Int4,
@@ -7,6 +47,41 @@ table! {
     }
 }
 
+table! {
+    export_cursors (target) {
+        target -> Varchar,
+        last_id -> Int4,
+        updated -> Timestamp,
+    }
+}
+
+table! {
+    photos (id) {
+        id -> Int4,
+        source -> Varchar,
+        guid -> Varchar,
+        file_id -> Varchar,
+        checksum -> Varchar,
+        taken_at -> Nullable<Timestamp>,
+        orientation -> Int2,
+        caption -> Nullable<Varchar>,
+        width -> Int4,
+        height -> Int4,
+        added -> Timestamp,
+    }
+}
+
+table! {
+    playlists (id) {
+        id -> Int4,
+        display -> Varchar,
+        interval_seconds -> Int4,
+        shuffle -> Bool,
+        rule -> Varchar,
+        updated -> Timestamp,
+    }
+}
+
 table! {
     thermostats (id) {
         id -> Int4,
@@ -18,4 +93,80 @@ table! {
     }
 }
 
-allow_tables_to_appear_in_same_query!(ecobee_token, thermostats,);
+table! {
+    thermostats_daily (id) {
+        id -> Int4,
+        name -> Varchar,
+        time -> Timestamp,
+        is_hygrostat -> Bool,
+        samples -> Int4,
+        temperature -> Int4,
+        temperature_min -> Int4,
+        temperature_max -> Int4,
+        relative_humidity -> Int4,
+        relative_humidity_min -> Int4,
+        relative_humidity_max -> Int4,
+    }
+}
+
+table! {
+    thermostats_hourly (id) {
+        id -> Int4,
+        name -> Varchar,
+        time -> Timestamp,
+        is_hygrostat -> Bool,
+        samples -> Int4,
+        temperature -> Int4,
+        temperature_min -> Int4,
+        temperature_max -> Int4,
+        relative_humidity -> Int4,
+        relative_humidity_min -> Int4,
+        relative_humidity_max -> Int4,
+    }
+}
+
+table! {
+    webhook_deliveries (id) {
+        id -> Int4,
+        webhook_id -> Int4,
+        event -> Varchar,
+        payload -> Text,
+        status -> Varchar,
+        attempts -> Int4,
+        next_attempt -> Nullable<Timestamp>,
+        response_status -> Nullable<Int4>,
+        error -> Nullable<Varchar>,
+        created -> Timestamp,
+        delivered -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    webhooks (id) {
+        id -> Int4,
+        url -> Varchar,
+        events -> Varchar,
+        secret -> Varchar,
+        enabled -> Bool,
+        created -> Timestamp,
+        updated -> Timestamp,
+    }
+}
+
+joinable!(alert_states -> alert_rules (rule_id));
+joinable!(webhook_deliveries -> webhooks (webhook_id));
+
+allow_tables_to_appear_in_same_query!(
+    alert_history,
+    alert_rules,
+    alert_states,
+    ecobee_token,
+    export_cursors,
+    photos,
+    playlists,
+    thermostats,
+    thermostats_daily,
+    thermostats_hourly,
+    webhook_deliveries,
+    webhooks,
+);
@@ -1,10 +1,15 @@
 use super::schema::thermostats;
-use chrono::{DateTime, NaiveDateTime, Utc};
+use crate::db::DbConnection;
+use crate::comfort::Comfort;
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
 use diesel::prelude::*;
-use diesel::PgConnection;
-use serde::Serialize;
+use serde::ser::{Serialize, SerializeStruct, Serializer};
 
-#[derive(Debug, Serialize, Clone, Queryable)]
+/// Temperatures at or below this mean the sensor does not measure
+/// temperature. Humidity-only readings are saved with -10000.
+pub const NO_TEMPERATURE: i32 = -1000;
+
+#[derive(Debug, Clone, Queryable)]
 pub struct Thermostat {
     pub id: i32,
     pub name: String,
@@ -14,6 +19,28 @@ pub struct Thermostat {
     pub relative_humidity: i32,
 }
 
+// Hand written so hygrostats get `comfort`, which is worked out from the
+// reading instead of being stored.
+impl Serialize for Thermostat {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("Thermostat", 7)?;
+        state.serialize_field("id", &self.id)?;
+        state.serialize_field("name", &self.name)?;
+        state.serialize_field("time", &self.time)?;
+        state.serialize_field("is_hygrostat", &self.is_hygrostat)?;
+        state.serialize_field("temperature", &self.temperature)?;
+        state.serialize_field("relative_humidity", &self.relative_humidity)?;
+        match Comfort::new(self.fahrenheit(), self.humidity()) {
+            Some(comfort) => state.serialize_field("comfort", &comfort)?,
+            None => state.skip_field("comfort")?,
+        }
+        state.end()
+    }
+}
+
 #[derive(Insertable)]
 #[table_name = "thermostats"]
 struct NewThermostat {
@@ -25,11 +52,31 @@ struct NewThermostat {
 }
 
 impl Thermostat {
-    // RK: Commented out because it is never used, but I'm keeping it in case I need to use `.time`
-    //     (which is private since it is naive) in the future.
-    // pub fn time(&self) -> DateTime<Utc> {
-    //     DateTime::<Utc>::from_utc(self.time, Utc)
-    // }
+    pub fn time(&self) -> DateTime<Utc> {
+        Utc.from_utc_datetime(&self.time)
+    }
+
+    /// # Fahrenheit
+    /// The temperature in degrees F, or `None` for sensors that only measure
+    /// humidity.
+    pub fn fahrenheit(&self) -> Option<f64> {
+        if self.temperature > NO_TEMPERATURE {
+            Some(f64::from(self.temperature) / 10.0)
+        } else {
+            None
+        }
+    }
+
+    /// # Humidity
+    /// The relative humidity in percent, or `None` for sensors that do not
+    /// measure it.
+    pub fn humidity(&self) -> Option<i32> {
+        if self.is_hygrostat {
+            Some(self.relative_humidity)
+        } else {
+            None
+        }
+    }
 
     pub fn new(name: String, time: DateTime<Utc>, temp: i32) -> Self {
         Self {
@@ -59,24 +106,57 @@ impl Thermostat {
         }
     }
 
-    pub fn insert(&self, connection: &PgConnection) -> Self {
-        let new_thermostat = NewThermostat {
+    fn to_new(&self) -> NewThermostat {
+        NewThermostat {
             name: self.name.clone(),
             time: self.time,
             is_hygrostat: self.is_hygrostat,
             temperature: self.temperature,
             relative_humidity: self.relative_humidity,
-        };
+        }
+    }
+
+    pub fn insert(&self, connection: &DbConnection) -> QueryResult<usize> {
+        let new_thermostat = self.to_new();
         let insert = diesel::insert_into(thermostats::table).values(&new_thermostat);
 
-        if cfg!(feature = "queries") {
-            crate::log_message(&diesel::debug_query::<diesel::pg::Pg, _>(&insert).to_string());
-        }
-        insert.get_result(connection).expect("Whoopsie-doodles")
+        crate::db::log_query(&insert);
+        insert.execute(connection)
+    }
+
+    /// # Insert All
+    /// Inserts many readings in one transaction.
+    pub fn insert_all(connection: &DbConnection, thermostats: &[Self]) -> QueryResult<usize> {
+        let new_thermostats: Vec<NewThermostat> = thermostats.iter().map(Self::to_new).collect();
+        connection.transaction(|| {
+            diesel::insert_into(thermostats::table)
+                .values(&new_thermostats)
+                .execute(connection)
+        })
+    }
+
+    /// # Query After
+    /// Up to `limit` readings saved after the one with id `after`, in the
+    /// order they were saved, skipping any taken before `since`.
+    pub fn query_after(
+        connection: &DbConnection,
+        after: i32,
+        since: &DateTime<Utc>,
+        limit: i64,
+    ) -> QueryResult<Vec<Self>> {
+        use thermostats::dsl;
+        let query = dsl::thermostats
+            .filter(dsl::id.gt(after))
+            .filter(dsl::time.ge(since.naive_utc()))
+            .order(dsl::id)
+            .limit(limit);
+
+        crate::db::log_query(&query);
+        query.load::<Thermostat>(connection)
     }
 
     pub fn query_dates(
-        connection: &PgConnection,
+        connection: &DbConnection,
         start_date: &DateTime<Utc>,
         end_date: &DateTime<Utc>,
     ) -> Result<Vec<Self>, diesel::result::Error> {
@@ -87,9 +167,45 @@ impl Thermostat {
             .filter(dsl::time.ge(start_date))
             .filter(dsl::time.le(end_date));
 
-        if cfg!(feature = "queries") {
-            crate::log_message(&diesel::debug_query::<diesel::pg::Pg, _>(&query).to_string());
+        crate::db::log_query(&query);
+        query.load::<Thermostat>(connection)
+    }
+
+    /// # Query Page
+    /// Like `query_dates`, but returns at most `limit` rows ordered by time
+    /// (then id), starting after the row identified by `after`. Paging on
+    /// `(time, id)` instead of an offset keeps deep pages just as cheap as
+    /// the first one. Only thermostats in `names` are returned, if given.
+    pub fn query_page(
+        connection: &DbConnection,
+        start_date: &DateTime<Utc>,
+        end_date: &DateTime<Utc>,
+        names: Option<&[String]>,
+        after: Option<(DateTime<Utc>, i32)>,
+        limit: i64,
+    ) -> Result<Vec<Self>, diesel::result::Error> {
+        use thermostats::dsl;
+        let start_date = start_date.naive_utc();
+        let end_date = end_date.naive_utc();
+        let mut query = dsl::thermostats
+            .filter(dsl::time.ge(start_date))
+            .filter(dsl::time.le(end_date))
+            .order((dsl::time, dsl::id))
+            .limit(limit)
+            .into_boxed();
+        if let Some((time, id)) = after {
+            let time = time.naive_utc();
+            query = query.filter(
+                dsl::time
+                    .gt(time)
+                    .or(dsl::time.eq(time).and(dsl::id.gt(id))),
+            );
         }
+        if let Some(names) = names {
+            query = query.filter(dsl::name.eq_any(names));
+        }
+
+        crate::db::log_query(&query);
         query.load::<Thermostat>(connection)
     }
 }
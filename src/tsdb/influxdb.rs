@@ -0,0 +1,71 @@
+use super::{send, Failure};
+use crate::config::Influxdb;
+use crate::Thermostat;
+use std::fmt::Write;
+
+// this file covers writing readings to InfluxDB 2's HTTP API as line
+// protocol, which `POST /readings` reads back in too
+
+/// Escapes the characters line protocol gives meaning to in measurements,
+/// tag keys and tag values.
+fn escape(text: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c == '\\' || special.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// # Line
+/// One reading as `<measurement>,sensor=<name> temperature=..,humidity=..i
+/// <milliseconds>`. Temperatures are in degrees F. Readings with neither
+/// field have no line.
+fn line(out: &mut String, measurement: &str, reading: &Thermostat) {
+    let mut fields = Vec::new();
+    if let Some(fahrenheit) = reading.fahrenheit() {
+        fields.push(format!("temperature={}", fahrenheit));
+    }
+    if let Some(humidity) = reading.humidity() {
+        fields.push(format!("humidity={}i", humidity));
+    }
+    if fields.is_empty() {
+        return;
+    }
+    let _ = writeln!(
+        out,
+        "{},sensor={} {} {}",
+        escape(measurement, &[',', ' ']),
+        escape(&reading.name, &[',', '=', ' ']),
+        fields.join(","),
+        reading.time().timestamp_millis()
+    );
+}
+
+/// # Write
+/// Writes readings to the bucket with `/api/v2/write`.
+pub fn write(settings: &Influxdb, readings: &[Thermostat]) -> Result<(), Failure> {
+    let mut body = String::new();
+    for reading in readings {
+        line(&mut body, &settings.measurement, reading);
+    }
+    if body.is_empty() {
+        return Ok(());
+    }
+    let url = format!("{}/api/v2/write", settings.url.trim_end_matches('/'));
+    let mut request = crate::REQWEST
+        .post(&url)
+        .query(&[
+            ("org", settings.org.as_str()),
+            ("bucket", settings.bucket.as_str()),
+            ("precision", "ms"),
+        ])
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .body(body);
+    if let Some(token) = &settings.token {
+        request = request.header("Authorization", format!("Token {}", token));
+    }
+    send(request)
+}
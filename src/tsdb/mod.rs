@@ -0,0 +1,183 @@
+use crate::db::DbConnection;
+use crate::schema::export_cursors;
+use crate::Thermostat;
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::prelude::*;
+
+mod influxdb;
+mod remote_write;
+
+// this file covers copying readings to time series databases after each
+// worker run. Each target remembers the last reading it was sent, so readings
+// that could not be sent stay in `thermostats` and go out with the next run.
+
+pub const INFLUXDB: &str = "influxdb";
+pub const REMOTE_WRITE: &str = "remote_write";
+
+/// Readings sent per request.
+const BATCH: i64 = 5000;
+/// Longest part of an error answer that is kept.
+const MAX_ERROR: usize = 200;
+/// Requests per target per run, so catching up after a long outage is spread
+/// over a few runs instead of holding up one.
+const MAX_BATCHES: usize = 20;
+/// Servers that take longer than this to answer have failed.
+const TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+/// Answers that mean the server will never take the batch, like Prometheus
+/// refusing out-of-order samples. The batch is skipped instead of being sent
+/// again every run.
+const REJECTED: &[u16] = &[400, 413, 422];
+
+/// # Failure
+/// The HTTP status, if there was an answer, and why a write failed.
+type Failure = (Option<u16>, String);
+
+/// # Export Cursor
+/// The id of the last reading sent to a target.
+#[derive(Insertable, Queryable)]
+#[table_name = "export_cursors"]
+struct ExportCursor {
+    target: String,
+    last_id: i32,
+    updated: NaiveDateTime,
+}
+
+impl ExportCursor {
+    fn last_id(connection: &DbConnection, target: &str) -> QueryResult<i32> {
+        let select = export_cursors::table
+            .filter(export_cursors::target.eq(target))
+            .limit(1);
+        crate::db::log_query(&select);
+        Ok(select
+            .load::<Self>(connection)?
+            .into_iter()
+            .next()
+            .map_or(0, |cursor| cursor.last_id))
+    }
+
+    /// Writes the cursor, replacing the target's old one.
+    fn save(&self, connection: &DbConnection) -> QueryResult<()> {
+        connection.transaction(|| {
+            let delete = diesel::delete(
+                export_cursors::table.filter(export_cursors::target.eq(&self.target)),
+            );
+            crate::db::log_query(&delete);
+            delete.execute(connection)?;
+            let insert = diesel::insert_into(export_cursors::table).values(self);
+            crate::db::log_query(&insert);
+            insert.execute(connection)?;
+            Ok(())
+        })
+    }
+}
+
+/// # Export
+/// Sends every target the readings saved since its last successful write,
+/// including any it missed while it was down. Does nothing for targets that
+/// are not configured.
+pub fn export(connection: &DbConnection) -> anyhow::Result<()> {
+    let config = crate::config::get();
+    let mut failures = Vec::new();
+    if let Some(settings) = &config.influxdb {
+        if let Err(err) = catch_up(connection, INFLUXDB, settings.backfill_days, |readings| {
+            influxdb::write(settings, readings)
+        }) {
+            failures.push(format!("{:#}", err));
+        }
+    }
+    if let Some(settings) = &config.remote_write {
+        if let Err(err) = catch_up(
+            connection,
+            REMOTE_WRITE,
+            settings.backfill_days,
+            |readings| remote_write::write(settings, readings),
+        ) {
+            failures.push(format!("{:#}", err));
+        }
+    }
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(failures.join("; ")))
+    }
+}
+
+/// # Catch Up
+/// Sends a target the readings after its cursor in batches, moving the
+/// cursor after each one, until it is up to date or a write fails.
+fn catch_up<F>(
+    connection: &DbConnection,
+    target: &str,
+    backfill_days: i64,
+    write: F,
+) -> anyhow::Result<()>
+where
+    F: Fn(&[Thermostat]) -> Result<(), Failure>,
+{
+    let since = Utc::now() - Duration::days(backfill_days);
+    let mut last_id = ExportCursor::last_id(connection, target)?;
+    for _ in 0..MAX_BATCHES {
+        let readings = Thermostat::query_after(connection, last_id, &since, BATCH)?;
+        let last = match readings.last() {
+            Some(last) => last.id,
+            None => break,
+        };
+        let result = match write(&readings) {
+            Ok(()) => {
+                tracing::debug!(export = target, "Wrote {} readings", readings.len());
+                "ok"
+            }
+            Err((Some(status), error)) if REJECTED.contains(&status) => {
+                tracing::warn!(
+                    export = target,
+                    "Skipped {} readings that were refused: {}",
+                    readings.len(),
+                    error
+                );
+                "rejected"
+            }
+            Err((_, error)) => {
+                crate::metrics::inc(
+                    crate::metrics::TSDB_WRITES,
+                    &[("target", target), ("result", "failed")],
+                );
+                anyhow::bail!("could not write to {}: {}", target, error);
+            }
+        };
+        crate::metrics::inc(
+            crate::metrics::TSDB_WRITES,
+            &[("target", target), ("result", result)],
+        );
+        last_id = last;
+        ExportCursor {
+            target: target.to_string(),
+            last_id,
+            updated: Utc::now().naive_utc(),
+        }
+        .save(connection)?;
+        if readings.len() < BATCH as usize {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// # Send
+/// Sends a request built by a target. Anything but a 2xx answer is a
+/// failure.
+#[tokio::main]
+async fn send(request: reqwest::RequestBuilder) -> Result<(), Failure> {
+    let response = request
+        .header("User-Agent", "github.com/ryanknu/therm_hub")
+        .timeout(TIMEOUT)
+        .send()
+        .await
+        .map_err(|err| (None, err.to_string()))?;
+    let status = response.status();
+    if status.is_success() {
+        return Ok(());
+    }
+    let body = response.text().await.unwrap_or_default();
+    let body: String = body.trim().chars().take(MAX_ERROR).collect();
+    Err((Some(status.as_u16()), format!("HTTP {}: {}", status, body)))
+}
@@ -0,0 +1,115 @@
+use super::{send, Failure};
+use crate::config::RemoteWrite;
+use crate::metrics::{HUMIDITY, TEMPERATURE};
+use crate::Thermostat;
+use std::collections::BTreeMap;
+
+// this file covers Prometheus remote-write: a snappy compressed protobuf
+// `WriteRequest`, encoded by hand since it only has four small messages
+
+/// Protobuf wire types.
+const VARINT: u64 = 0;
+const FIXED_64: u64 = 1;
+const LENGTH_DELIMITED: u64 = 2;
+
+fn varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn key(out: &mut Vec<u8>, field: u64, wire_type: u64) {
+    varint(out, field << 3 | wire_type);
+}
+
+fn bytes(out: &mut Vec<u8>, field: u64, value: &[u8]) {
+    key(out, field, LENGTH_DELIMITED);
+    varint(out, value.len() as u64);
+    out.extend_from_slice(value);
+}
+
+/// `Label { string name = 1; string value = 2; }`
+fn label(name: &str, value: &str) -> Vec<u8> {
+    let mut out = Vec::new();
+    bytes(&mut out, 1, name.as_bytes());
+    bytes(&mut out, 2, value.as_bytes());
+    out
+}
+
+/// `Sample { double value = 1; int64 timestamp = 2; }`, with the timestamp
+/// in milliseconds.
+fn sample(value: f64, timestamp: i64) -> Vec<u8> {
+    let mut out = Vec::new();
+    key(&mut out, 1, FIXED_64);
+    out.extend_from_slice(&value.to_le_bytes());
+    key(&mut out, 2, VARINT);
+    varint(&mut out, timestamp as u64);
+    out
+}
+
+/// # Write Request
+/// `WriteRequest { repeated TimeSeries timeseries = 1; }`, where
+/// `TimeSeries { repeated Label labels = 1; repeated Sample samples = 2; }`.
+/// There is a series per metric and sensor, named like the gauges on
+/// `/metrics` and labelled with `sensor` and `job="therm_hub"`. Labels are
+/// sorted by name and samples by time, as Prometheus expects.
+fn write_request(readings: &[Thermostat]) -> Vec<u8> {
+    let mut series: BTreeMap<(&str, &str), Vec<(i64, f64)>> = BTreeMap::new();
+    for reading in readings {
+        let timestamp = reading.time().timestamp_millis();
+        if let Some(fahrenheit) = reading.fahrenheit() {
+            series
+                .entry((TEMPERATURE, &reading.name))
+                .or_default()
+                .push((timestamp, fahrenheit));
+        }
+        if let Some(humidity) = reading.humidity() {
+            series
+                .entry((HUMIDITY, &reading.name))
+                .or_default()
+                .push((timestamp, f64::from(humidity)));
+        }
+    }
+
+    let mut out = Vec::new();
+    for ((name, sensor), mut samples) in series {
+        samples.sort_by_key(|(timestamp, _)| *timestamp);
+        // Prometheus refuses two samples with the same time.
+        samples.dedup_by_key(|(timestamp, _)| *timestamp);
+        let mut time_series = Vec::new();
+        for (label_name, value) in &[("__name__", name), ("job", "therm_hub"), ("sensor", sensor)] {
+            bytes(&mut time_series, 1, &label(label_name, value));
+        }
+        for (timestamp, value) in samples {
+            bytes(&mut time_series, 2, &sample(value, timestamp));
+        }
+        bytes(&mut out, 1, &time_series);
+    }
+    out
+}
+
+/// # Write
+/// Sends readings to the remote-write endpoint.
+pub fn write(settings: &RemoteWrite, readings: &[Thermostat]) -> Result<(), Failure> {
+    let body = write_request(readings);
+    if body.is_empty() {
+        return Ok(());
+    }
+    let body = snap::raw::Encoder::new()
+        .compress_vec(&body)
+        .map_err(|err| (None, err.to_string()))?;
+    let mut request = crate::REQWEST
+        .post(&settings.url)
+        .header("Content-Type", "application/x-protobuf")
+        .header("Content-Encoding", "snappy")
+        .header("X-Prometheus-Remote-Write-Version", "0.1.0")
+        .body(body);
+    if let Some(username) = &settings.username {
+        request = request.basic_auth(username, settings.password.as_ref());
+    } else if let Some(token) = &settings.bearer_token {
+        request = request.bearer_auth(token);
+    }
+    send(request)
+}
@@ -0,0 +1,265 @@
+use super::cache::json_response;
+use super::{
+    bad_request, internal_server_error, method_not_allowed, not_found, query_parameters,
+    status_only,
+};
+use crate::alert::{
+    AlertEvent, AlertRule, AlertState, Condition, Metric, NewAlertRule, ANY_SENSOR,
+};
+use chrono::Utc;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// this file covers the alert endpoints: rules (`/alerts/rules`), what is
+// firing now (`/alerts`) and what fired before (`/alerts/history`)
+
+const DEFAULT_HISTORY: i64 = 100;
+const MAX_HISTORY: i64 = 1000;
+
+/// # Rule Input
+/// A whole rule, for `POST /alerts/rules` and `PUT /alerts/rules/{id}`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleInput {
+    name: String,
+    sensor: String,
+    condition: Condition,
+    metric: Option<Metric>,
+    /// Required for `above` and `below`.
+    threshold: Option<f64>,
+    #[serde(default)]
+    hysteresis: f64,
+    #[serde(default)]
+    duration_seconds: i32,
+    webhook_url: Option<String>,
+    email: Option<String>,
+    enabled: Option<bool>,
+}
+
+impl RuleInput {
+    /// Checks the input, explaining what is wrong with it.
+    fn into_rule(self) -> Result<NewAlertRule, String> {
+        if self.name.trim().is_empty() {
+            return Err(String::from("name must not be empty"));
+        }
+        if self.sensor.is_empty() {
+            return Err(format!("sensor must be a sensor's name or {}", ANY_SENSOR));
+        }
+        let threshold = match (self.condition, self.threshold) {
+            (Condition::Stale, _) => 0.0,
+            (_, Some(threshold)) if threshold.is_finite() => threshold,
+            _ => return Err(String::from("threshold is required for above and below")),
+        };
+        if self.condition == Condition::Stale && self.duration_seconds <= 0 {
+            return Err(String::from("duration_seconds is required for stale"));
+        }
+        if self.duration_seconds < 0 || !self.hysteresis.is_finite() || self.hysteresis < 0.0 {
+            return Err(String::from(
+                "duration_seconds and hysteresis must not be negative",
+            ));
+        }
+        if let Some(url) = &self.webhook_url {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                return Err(String::from("webhook_url must be an http(s) URL"));
+            }
+        }
+        if self.email.is_some() && crate::config::get().smtp.is_none() {
+            return Err(String::from("email needs [smtp] to be configured"));
+        }
+        Ok(NewAlertRule {
+            name: self.name.trim().to_string(),
+            sensor: self.sensor,
+            condition: String::from(self.condition.name()),
+            metric: String::from(self.metric.unwrap_or(Metric::Temperature).name()),
+            threshold,
+            hysteresis: self.hysteresis,
+            duration_seconds: self.duration_seconds,
+            webhook_url: self.webhook_url,
+            email: self.email,
+            enabled: self.enabled.unwrap_or(true),
+            updated: Utc::now().naive_utc(),
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct HistoryInput {
+    rule_id: Option<i32>,
+    limit: Option<i64>,
+}
+
+/// # Active Alert
+/// A pending or firing alert, with its rule's name.
+#[derive(Serialize)]
+struct ActiveAlert {
+    rule: String,
+    #[serde(flatten)]
+    state: AlertState,
+}
+
+enum Outcome<T> {
+    Found(T),
+    Created(T),
+    Deleted(bool),
+    Conflict,
+    Missing,
+}
+
+/// # Rules
+/// - `GET /alerts/rules` lists every rule.
+/// - `POST /alerts/rules` adds a rule from a `RuleInput` and returns it (201
+///   Created), or 409 Conflict when the name is taken.
+/// - `GET /alerts/rules/{id}` returns a rule.
+/// - `PUT /alerts/rules/{id}` replaces a rule. Its alerts start over.
+/// - `DELETE /alerts/rules/{id}` removes a rule. Its history is kept.
+pub async fn rules(mut req: Request<Body>, id: Option<String>) -> Response<Body> {
+    let id = match id.as_deref().map(str::parse::<i32>) {
+        None => None,
+        Some(Ok(id)) => Some(id),
+        Some(Err(_)) => return not_found(),
+    };
+    let method = req.method().clone();
+    let input = if method == Method::POST || method == Method::PUT {
+        let body =
+            match hyper::body::to_bytes(std::mem::replace(req.body_mut(), Body::empty())).await {
+                Ok(body) => body,
+                Err(_) => return bad_request(),
+            };
+        let input = match serde_json::from_slice::<RuleInput>(&body) {
+            Ok(input) => input,
+            Err(err) => {
+                tracing::warn!("Could not decode alert rule {:?}", err);
+                return bad_request();
+            }
+        };
+        match input.into_rule() {
+            Ok(rule) => Some(rule),
+            Err(reason) => {
+                tracing::warn!("Alert rule is not valid: {}", reason);
+                return bad_request();
+            }
+        }
+    } else {
+        None
+    };
+
+    let result = crate::db::run(move |connection| {
+        let outcome = match (method, id, input) {
+            (Method::GET, None, _) => Outcome::Found(AlertRule::all(connection)?),
+            (Method::POST, None, Some(rule)) => match AlertRule::create(connection, &rule)? {
+                Some(rule) => Outcome::Created(vec![rule]),
+                None => Outcome::Conflict,
+            },
+            (Method::GET, Some(id), _) => match AlertRule::by_id(connection, id)? {
+                Some(rule) => Outcome::Found(vec![rule]),
+                None => Outcome::Missing,
+            },
+            (Method::PUT, Some(id), Some(rule)) => match AlertRule::update(connection, id, &rule) {
+                Ok(Some(rule)) => Outcome::Found(vec![rule]),
+                Ok(None) => Outcome::Missing,
+                // The only way an update fails on its own is a duplicate name.
+                Err(diesel::result::Error::DatabaseError(
+                    diesel::result::DatabaseErrorKind::UniqueViolation,
+                    _,
+                )) => Outcome::Conflict,
+                Err(err) => return Err(err),
+            },
+            (Method::DELETE, Some(id), _) => Outcome::Deleted(AlertRule::delete(connection, id)?),
+            _ => return Ok(None),
+        };
+        Ok::<_, diesel::result::Error>(Some(outcome))
+    })
+    .await;
+    let (status, rules) = match result {
+        Ok(Ok(Some(Outcome::Found(rules)))) => (StatusCode::OK, rules),
+        Ok(Ok(Some(Outcome::Created(rules)))) => (StatusCode::CREATED, rules),
+        Ok(Ok(Some(Outcome::Deleted(true)))) => return status_only(StatusCode::NO_CONTENT),
+        Ok(Ok(Some(Outcome::Deleted(false)))) | Ok(Ok(Some(Outcome::Missing))) => {
+            return not_found()
+        }
+        Ok(Ok(Some(Outcome::Conflict))) => return status_only(StatusCode::CONFLICT),
+        Ok(Ok(None)) => return method_not_allowed(),
+        Ok(Err(err)) => {
+            tracing::error!("Could not save alert rules: {:?}", err);
+            return internal_server_error();
+        }
+        Err(_) => return internal_server_error(),
+    };
+    // One rule unless the whole list was asked for.
+    let body = if id.is_some() || status == StatusCode::CREATED {
+        rules.first().map(serde_json::to_string)
+    } else {
+        Some(serde_json::to_string(&rules))
+    };
+    match body {
+        Some(Ok(body)) => {
+            let mut response = json_response(&req, body);
+            *response.status_mut() = status;
+            response
+        }
+        _ => internal_server_error(),
+    }
+}
+
+/// # Active
+/// `GET /alerts` lists the alerts that are pending or firing now.
+pub async fn active(req: Request<Body>) -> Response<Body> {
+    if !Method::GET.eq(req.method()) {
+        return method_not_allowed();
+    }
+    let result = crate::db::run(|connection| {
+        let names: HashMap<i32, String> = AlertRule::all(connection)?
+            .into_iter()
+            .map(|rule| (rule.id, rule.name))
+            .collect();
+        let alerts: Vec<ActiveAlert> = AlertState::all(connection)?
+            .into_iter()
+            .map(|state| ActiveAlert {
+                rule: names.get(&state.rule_id).cloned().unwrap_or_default(),
+                state,
+            })
+            .collect();
+        Ok::<_, diesel::result::Error>(alerts)
+    })
+    .await;
+    match result {
+        Ok(Ok(alerts)) => match serde_json::to_string(&alerts) {
+            Ok(body) => json_response(&req, body),
+            Err(_) => internal_server_error(),
+        },
+        Ok(Err(err)) => {
+            tracing::error!("Could not read alerts: {:?}", err);
+            internal_server_error()
+        }
+        Err(_) => internal_server_error(),
+    }
+}
+
+/// # History
+/// `GET /alerts/history` lists alerts starting and stopping firing, newest
+/// first. Takes `rule_id` and `limit` (100 by default, up to 1000).
+pub async fn history(req: Request<Body>) -> Response<Body> {
+    if !Method::GET.eq(req.method()) {
+        return method_not_allowed();
+    }
+    let input: HistoryInput = match query_parameters(&req) {
+        Some(input) => input,
+        None => return bad_request(),
+    };
+    let limit = input.limit.unwrap_or(DEFAULT_HISTORY).clamp(1, MAX_HISTORY);
+    let rule_id = input.rule_id;
+    let result =
+        crate::db::run(move |connection| AlertEvent::recent(connection, rule_id, limit)).await;
+    match result {
+        Ok(Ok(events)) => match serde_json::to_string(&events) {
+            Ok(body) => json_response(&req, body),
+            Err(_) => internal_server_error(),
+        },
+        Ok(Err(err)) => {
+            tracing::error!("Could not read alert history: {:?}", err);
+            internal_server_error()
+        }
+        Err(_) => internal_server_error(),
+    }
+}
@@ -0,0 +1,77 @@
+use super::cache::json_response;
+use super::{bad_request, internal_server_error, query_parameters};
+use crate::analytics::{degree_days, DegreeDayQuery, DEFAULT_BASE_F};
+use crate::export::Units;
+use chrono::{Datelike, Local, NaiveDate};
+use hyper::{Body, Request, Response};
+use serde::Deserialize;
+
+// this file covers the analytics endpoints for energy reports
+
+/// The longest range, in days, and the most earlier years to compare with.
+const MAX_DAYS: i64 = 3660;
+const MAX_COMPARE: i32 = 10;
+
+#[derive(Deserialize)]
+struct DegreeDaysInput {
+    start_date: Option<NaiveDate>,
+    end_date: Option<NaiveDate>,
+    units: Option<Units>,
+    base: Option<f64>,
+    heating_base: Option<f64>,
+    cooling_base: Option<f64>,
+    compare: Option<i32>,
+}
+
+/// # Degree Days
+/// `GET /analytics/degree-days` returns heating and cooling degree-days from
+/// `start_date` to `end_date` (inclusive dates, in the server's time zone),
+/// per day, month and July-June season, with the same dates in the
+/// `compare` previous years (1 by default). Defaults to this year so far.
+/// `base` sets both base temperatures, or `heating_base` and `cooling_base`
+/// set one each; they are in `units` (`f` or `c`) and default to 65°F.
+pub async fn degree_days_report(req: Request<Body>) -> Response<Body> {
+    let input: DegreeDaysInput = match query_parameters(&req) {
+        Some(input) => input,
+        None => return bad_request(),
+    };
+    let today = Local::now().date_naive();
+    let end_date = input.end_date.unwrap_or(today);
+    let start_date = input
+        .start_date
+        .unwrap_or_else(|| NaiveDate::from_ymd_opt(end_date.year(), 1, 1).unwrap_or(end_date));
+    let compare = input.compare.unwrap_or(1);
+    let units = input.units.unwrap_or_default();
+    let base = input
+        .base
+        .unwrap_or_else(|| (units.convert(DEFAULT_BASE_F) * 10.0).round() / 10.0);
+    let query = DegreeDayQuery {
+        start_date,
+        end_date,
+        units,
+        heating_base: input.heating_base.unwrap_or(base),
+        cooling_base: input.cooling_base.unwrap_or(base),
+        compare,
+    };
+    let days = (end_date - start_date).num_days();
+    if !(0..MAX_DAYS).contains(&days)
+        || !(0..=MAX_COMPARE).contains(&compare)
+        || !query.heating_base.is_finite()
+        || !query.cooling_base.is_finite()
+    {
+        return bad_request();
+    }
+
+    let result = crate::db::run(move |connection| degree_days(connection, &query)).await;
+    match result {
+        Ok(Ok(report)) => match serde_json::to_string(&report) {
+            Ok(body) => json_response(&req, body),
+            Err(_) => internal_server_error(),
+        },
+        Ok(Err(err)) => {
+            tracing::error!("Could not work out degree-days: {}", err);
+            internal_server_error()
+        }
+        Err(_) => internal_server_error(),
+    }
+}
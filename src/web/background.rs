@@ -0,0 +1,446 @@
+use super::cache::{self, etag_matches, json_response, not_modified};
+use super::multipart::Multipart;
+use super::photo::{
+    checksum, job, metadata, photo_paths, records, start_fetching_backgrounds, Trigger,
+};
+use super::rendition::{rendition_path, Fit, Rendition};
+use super::{bad_request, internal_server_error, method_not_allowed, not_found, query_parameters};
+use chrono::{DateTime, Utc};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+// this file covers serving the background photos (`/background-photos`)
+
+#[derive(Default, Deserialize)]
+struct BackgroundPhotosInput {
+    format: Option<String>,
+    width: Option<u32>,
+    height: Option<u32>,
+    #[serde(default)]
+    fit: Fit,
+}
+
+impl BackgroundPhotosInput {
+    /// The multipart bundle is always JPEG, and the old 800x480 crop unless
+    /// a size is asked for.
+    fn rendition(&self) -> Rendition {
+        if self.width.is_none() && self.height.is_none() {
+            return Rendition::LEGACY;
+        }
+        Rendition {
+            width: self.width,
+            height: self.height,
+            fit: self.fit,
+            ..Rendition::LEGACY
+        }
+    }
+}
+
+#[derive(Clone, Serialize)]
+struct PhotoInfo {
+    id: String,
+    width: u32,
+    height: u32,
+    size: u64,
+    taken_at: Option<DateTime<Utc>>,
+    caption: Option<String>,
+    source: Option<String>,
+    checksum: String,
+}
+
+/// Photo metadata keyed by path, along with the size and modification time
+/// it was read at. Hashing every photo on every listing would be slow.
+type StsPhotoInfo = Arc<RwLock<HashMap<PathBuf, (u64, SystemTime, PhotoInfo)>>>;
+lazy_static! {
+    static ref PHOTO_INFO: StsPhotoInfo = Arc::new(RwLock::new(HashMap::new()));
+}
+
+/// # Background Photos
+/// Lists the background photos as JSON, returning a `Vec<PhotoInfo>` in a
+/// response body. With `format=multipart` (or `Accept: multipart/form-data`)
+/// every photo is returned in one multipart response instead, sized by
+/// `width`, `height` and `fit`.
+///
+/// The ETag is built from the names, sizes and modification times of the
+/// photos on disk, so a 304 can be returned without reading any of them.
+pub async fn background_photos(req: Request<Body>) -> Response<Body> {
+    let input: BackgroundPhotosInput = match query_parameters(&req) {
+        Some(input) => input,
+        None => return bad_request(),
+    };
+    let rendition = input.rendition();
+    if !rendition.is_valid() {
+        return bad_request();
+    }
+    let paths = photo_paths();
+    let multipart = wants_multipart(&req, &input);
+    let etag = photos_etag(&paths, if multipart { Some(&rendition) } else { None });
+    if etag_matches(&req, &etag) {
+        let vary = if multipart { None } else { Some("Accept-Encoding") };
+        return not_modified(&etag, "no-cache", vary);
+    }
+    let mut response = if multipart {
+        let bundle_etag = etag.clone();
+        match tokio::task::spawn_blocking(move || bundle(&paths, &bundle_etag, &rendition)).await {
+            Ok(response) => response,
+            Err(_) => internal_server_error(),
+        }
+    } else {
+        // Photos not seen before are read and hashed, so keep that off the
+        // async threads.
+        let listing = tokio::task::spawn_blocking(move || {
+            let records = records();
+            paths
+                .iter()
+                .filter_map(|path| photo_info(path))
+                .map(|mut info| {
+                    // The source knows more than the file's EXIF data.
+                    if let Some(record) = records.get(&info.id) {
+                        info.source = Some(record.source.clone());
+                        info.taken_at = record.taken_at().or(info.taken_at);
+                        info.caption = record.caption.clone().or(info.caption);
+                    }
+                    info
+                })
+                .collect::<Vec<PhotoInfo>>()
+        })
+        .await
+        .unwrap_or_default();
+        match serde_json::to_string(&listing) {
+            Ok(body) => json_response(&req, body),
+            Err(_) => internal_server_error(),
+        }
+    };
+    if response.status() == StatusCode::OK {
+        let headers = response.headers_mut();
+        if let Ok(etag) = etag.parse() {
+            headers.insert("ETag", etag);
+        }
+        headers.insert("Cache-Control", "no-cache".parse().unwrap());
+    }
+    response
+}
+
+/// # Background Photo
+/// Returns a single photo by the `id` from the listing. Without query
+/// parameters the original is sent. `width`, `height`, `fit` (`cover`,
+/// `contain` or `fill`) and `format` (`jpeg`, `webp` or `png`) ask for a
+/// rendition instead, which is made the first time it is asked for and kept
+/// in `PHOTO_CACHE_DIR/renditions`.
+///
+/// Supports `Range` requests (a single range), `If-Range` and
+/// `If-None-Match` against an ETag made from the photo's checksum.
+pub async fn background_photo(req: Request<Body>, id: String) -> Response<Body> {
+    let rendition: Rendition = match query_parameters(&req) {
+        Some(rendition) => rendition,
+        None => return bad_request(),
+    };
+    if !rendition.is_valid() {
+        return bad_request();
+    }
+    let path = match photo_paths()
+        .into_iter()
+        .find(|path| photo_id(path).as_deref() == Some(id.as_str()))
+    {
+        Some(path) => path,
+        None => return not_found(),
+    };
+    let info_path = path.clone();
+    let info = match tokio::task::spawn_blocking(move || photo_info(&info_path)).await {
+        Ok(Some(info)) => info,
+        _ => return internal_server_error(),
+    };
+    if rendition.is_original() {
+        let etag = format!("\"{}\"", info.checksum);
+        return serve_file(&req, &path, &etag, "image/jpeg");
+    }
+
+    let etag = format!(
+        "\"{}-{}.{}\"",
+        info.checksum,
+        rendition.key(),
+        rendition.format().extension()
+    );
+    if etag_matches(&req, &etag) {
+        return not_modified(&etag, "no-cache", None);
+    }
+    let result = tokio::task::spawn_blocking(move || rendition_path(&path, &id, &rendition)).await;
+    match result {
+        Ok(Ok(rendition_path)) => serve_file(
+            &req,
+            &rendition_path,
+            &etag,
+            rendition.format().content_type(),
+        ),
+        Ok(Err(err)) => {
+            tracing::error!("Could not make rendition: {:?}", err);
+            internal_server_error()
+        }
+        Err(_) => internal_server_error(),
+    }
+}
+
+/// # Refresh
+/// Starts a refresh of the background photos and returns its `Job` right
+/// away with 202 Accepted. When a refresh is already running, that job is
+/// returned with 200 OK instead. Follow it at `/background-photos/jobs/{id}`.
+pub fn refresh<V>(req: &Request<V>) -> Response<Body> {
+    if !Method::GET.eq(req.method()) && !Method::POST.eq(req.method()) {
+        return method_not_allowed();
+    }
+    if crate::config::get().photos.is_none() {
+        return not_found();
+    }
+    let (job, started) = match start_fetching_backgrounds(Trigger::Api) {
+        Some(result) => result,
+        None => return internal_server_error(),
+    };
+    match serde_json::to_string(&job) {
+        Ok(body) => {
+            let mut response = json_response(req, body);
+            if started {
+                *response.status_mut() = StatusCode::ACCEPTED;
+            }
+            let location = format!("/background-photos/jobs/{}", job.id);
+            if let Ok(location) = location.parse() {
+                response.headers_mut().insert("Location", location);
+            }
+            response
+        }
+        Err(_) => internal_server_error(),
+    }
+}
+
+/// # Refresh Jobs
+/// Returns the recent refreshes, newest first, as a `Vec<Job>`. With an id,
+/// returns just that `Job`. Jobs are kept in memory, so the list starts over
+/// when the server restarts.
+pub fn refresh_jobs<V>(req: &Request<V>, id: Option<&str>) -> Response<Body> {
+    if !Method::GET.eq(req.method()) {
+        return method_not_allowed();
+    }
+    let body = match id {
+        None => serde_json::to_string(&job::all()),
+        Some(id) => match id.parse().ok().and_then(job::get) {
+            Some(job) => serde_json::to_string(&job),
+            None => return not_found(),
+        },
+    };
+    match body {
+        Ok(body) => {
+            let mut response = json_response(req, body);
+            response
+                .headers_mut()
+                .insert("Cache-Control", "no-store".parse().unwrap());
+            response
+        }
+        Err(_) => internal_server_error(),
+    }
+}
+
+/// # Serve File
+/// Sends a file, or the part of it asked for with `Range`.
+fn serve_file<V>(req: &Request<V>, path: &Path, etag: &str, content_type: &str) -> Response<Body> {
+    if etag_matches(req, etag) {
+        return not_modified(etag, "no-cache", None);
+    }
+    let size = match std::fs::metadata(path) {
+        Ok(metadata) => metadata.len(),
+        Err(_) => return internal_server_error(),
+    };
+
+    let range = if if_range_matches(req, etag) {
+        match req.headers().get("range").map(|value| value.to_str()) {
+            Some(Ok(range)) => parse_range(range, size),
+            _ => Ok(None),
+        }
+    } else {
+        Ok(None)
+    };
+    let (status, start, end) = match range {
+        Err(()) => {
+            return Response::builder()
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header("Content-Range", format!("bytes */{}", size))
+                .body(Body::empty())
+                .unwrap_or_else(|_| internal_server_error())
+        }
+        Ok(Some((start, end))) => (StatusCode::PARTIAL_CONTENT, start, end),
+        Ok(None) => (StatusCode::OK, 0, size.saturating_sub(1)),
+    };
+
+    let mut data = Vec::new();
+    let read = File::open(path).and_then(|mut file| {
+        file.seek(SeekFrom::Start(start))?;
+        file.take(end + 1 - start).read_to_end(&mut data)
+    });
+    if read.is_err() {
+        return internal_server_error();
+    }
+
+    let mut response = Response::builder()
+        .status(status)
+        .header("Content-Type", content_type)
+        .header("Accept-Ranges", "bytes")
+        .header("ETag", etag)
+        .header("Cache-Control", "no-cache");
+    if status == StatusCode::PARTIAL_CONTENT {
+        response = response.header("Content-Range", format!("bytes {}-{}/{}", start, end, size));
+    }
+    match response.body(Body::from(data)) {
+        Ok(response) => response,
+        Err(_) => internal_server_error(),
+    }
+}
+
+/// # Bundle
+/// Every photo in one `multipart/form-data` body.
+fn bundle(paths: &[PathBuf], etag: &str, rendition: &Rendition) -> Response<Body> {
+    let mut multipart = Multipart::new();
+    for path in paths {
+        let id = match photo_id(path) {
+            Some(id) => id,
+            None => continue,
+        };
+        let mut data = Vec::new();
+        let read = rendition_path(path, &id, rendition)
+            .and_then(|path| Ok(File::open(path)?.read_to_end(&mut data)?));
+        if let Err(err) = read {
+            tracing::error!("Could not bundle {}: {:?}", id, err);
+            return internal_server_error();
+        }
+        let file_name = format!("{}.{}", id, rendition.format().extension());
+        multipart.part(rendition.format().content_type(), &file_name, data);
+    }
+    let (content_type, body) = multipart.finish(etag);
+    match Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", content_type)
+        .body(Body::from(body))
+    {
+        Ok(response) => response,
+        Err(_) => internal_server_error(),
+    }
+}
+
+fn wants_multipart<V>(req: &Request<V>, input: &BackgroundPhotosInput) -> bool {
+    if let Some(format) = &input.format {
+        return format.eq("multipart");
+    }
+    match req.headers().get("accept") {
+        Some(header_value) => match header_value.to_str() {
+            Ok(accept) => accept.contains("multipart/"),
+            Err(_) => false,
+        },
+        None => false,
+    }
+}
+
+/// # Photos ETag
+/// Builds an ETag for a set of photos from their file metadata. The listing
+/// and each size of multipart bundle get different ETags.
+fn photos_etag(paths: &[PathBuf], bundle: Option<&Rendition>) -> String {
+    let mut fingerprint = String::new();
+    if let Some(rendition) = bundle {
+        fingerprint.push_str(&format!("multipart {}\n", rendition.key()));
+    }
+    for path in paths {
+        fingerprint.push_str(&path.to_string_lossy());
+        if let Ok(metadata) = std::fs::metadata(path) {
+            fingerprint.push_str(&format!(":{}", metadata.len()));
+            if let Ok(modified) = metadata.modified() {
+                fingerprint.push_str(&format!(":{:?}", modified));
+            }
+        }
+        fingerprint.push('\n');
+    }
+    cache::etag(fingerprint.as_bytes())
+}
+
+fn photo_id(path: &Path) -> Option<String> {
+    path.file_stem()
+        .and_then(|stem| stem.to_str())
+        .map(String::from)
+}
+
+/// # Photo Info
+/// Reads the dimensions (once turned upright), checksum, capture date and
+/// caption of a photo, or returns them from `PHOTO_INFO` if the file has not
+/// changed since.
+fn photo_info(path: &Path) -> Option<PhotoInfo> {
+    let metadata = std::fs::metadata(path).ok()?;
+    let modified = metadata.modified().ok()?;
+    let size = metadata.len();
+    if let Ok(cache) = PHOTO_INFO.read() {
+        if let Some((cached_size, cached_modified, info)) = cache.get(path) {
+            if *cached_size == size && *cached_modified == modified {
+                return Some(info.clone());
+            }
+        }
+    }
+
+    let (width, height) = image::image_dimensions(path).ok()?;
+    let mut data = Vec::new();
+    File::open(path).ok()?.read_to_end(&mut data).ok()?;
+    let metadata = metadata::from_bytes(&data);
+    let (width, height) = if metadata.is_sideways() {
+        (height, width)
+    } else {
+        (width, height)
+    };
+    let info = PhotoInfo {
+        id: photo_id(path)?,
+        width,
+        height,
+        size,
+        taken_at: metadata.taken_at,
+        caption: metadata.caption,
+        source: None,
+        checksum: checksum(&data),
+    };
+    if let Ok(mut cache) = PHOTO_INFO.write() {
+        cache.insert(path.to_path_buf(), (size, modified, info.clone()));
+    }
+    Some(info)
+}
+
+fn if_range_matches<V>(req: &Request<V>, etag: &str) -> bool {
+    match req.headers().get("if-range") {
+        None => true,
+        Some(value) => value.to_str().map(|value| value == etag).unwrap_or(false),
+    }
+}
+
+/// # Parse Range
+/// Parses a `Range` header into inclusive byte offsets. Returns `Ok(None)`
+/// when the header should be ignored (other units or several ranges) and
+/// `Err` when the range cannot be satisfied.
+fn parse_range(header: &str, size: u64) -> Result<Option<(u64, u64)>, ()> {
+    let spec = match header.trim().strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return Ok(None),
+    };
+    let (start, end) = match spec.find('-') {
+        Some(dash) => (&spec[..dash], &spec[dash + 1..]),
+        None => return Ok(None),
+    };
+    let (start, end) = match (start.parse::<u64>(), end.parse::<u64>()) {
+        (Ok(start), Ok(end)) if start <= end => (start, end.min(size.saturating_sub(1))),
+        (Ok(start), Err(_)) if end.is_empty() => (start, size.saturating_sub(1)),
+        (Err(_), Ok(suffix)) if start.is_empty() && suffix > 0 => {
+            (size.saturating_sub(suffix), size.saturating_sub(1))
+        }
+        _ => return Ok(None),
+    };
+    if size == 0 || start >= size || start > end {
+        return Err(());
+    }
+    Ok(Some((start, end)))
+}
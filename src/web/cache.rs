@@ -0,0 +1,190 @@
+use brotli::CompressorWriter;
+use chrono::{DateTime, Utc};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use hyper::{Body, Request, Response, StatusCode};
+use sha2::{Digest, Sha256};
+use std::io::Write;
+
+// this file covers conditional requests (ETags) and response compression
+
+/// Brotli quality (0-11). 11 is painfully slow for responses built on the fly.
+const BROTLI_QUALITY: u32 = 6;
+const BROTLI_WINDOW: u32 = 22;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Identity,
+    Gzip,
+    Brotli,
+}
+
+impl Encoding {
+    /// The value for the `Content-Encoding` header, if one should be sent.
+    pub fn header(self) -> Option<&'static str> {
+        match self {
+            Encoding::Identity => None,
+            Encoding::Gzip => Some("gzip"),
+            Encoding::Brotli => Some("br"),
+        }
+    }
+}
+
+/// # Cached Body
+/// A JSON body that is served many times between changes, like the `/now`
+/// response. All of the encodings and the ETag are computed once when the
+/// body changes, so requests just copy bytes.
+pub struct CachedBody {
+    pub identity: Vec<u8>,
+    gzip: Option<Vec<u8>>,
+    brotli: Option<Vec<u8>>,
+    pub etag: String,
+    pub modified: DateTime<Utc>,
+}
+
+impl Default for CachedBody {
+    fn default() -> Self {
+        CachedBody::new(String::new())
+    }
+}
+
+impl CachedBody {
+    pub fn new(body: String) -> Self {
+        let identity = body.into_bytes();
+        Self {
+            gzip: compress(&identity, Encoding::Gzip).ok(),
+            brotli: compress(&identity, Encoding::Brotli).ok(),
+            etag: etag(&identity),
+            modified: Utc::now(),
+            identity,
+        }
+    }
+
+    /// Returns the body in the requested encoding, falling back to identity
+    /// if compressing it failed when the body was cached.
+    pub fn encoded(&self, encoding: Encoding) -> (Encoding, Vec<u8>) {
+        let encoded = match encoding {
+            Encoding::Identity => None,
+            Encoding::Gzip => self.gzip.as_ref(),
+            Encoding::Brotli => self.brotli.as_ref(),
+        };
+        match encoded {
+            Some(bytes) => (encoding, bytes.clone()),
+            None => (Encoding::Identity, self.identity.clone()),
+        }
+    }
+}
+
+/// # ETag
+/// Builds a strong ETag (quotes included) from the bytes of a response: the
+/// first 128 bits of their SHA-256, in hex, so it is the same on every build.
+pub fn etag(data: &[u8]) -> String {
+    let hex: String = Sha256::digest(data)
+        .iter()
+        .take(16)
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!("\"{}\"", hex)
+}
+
+/// # ETag Matches
+/// Checks the `If-None-Match` header of a request against an ETag. Handles
+/// lists of ETags and the `*` wildcard.
+pub fn etag_matches<V>(req: &Request<V>, etag: &str) -> bool {
+    match req.headers().get("if-none-match") {
+        None => false,
+        Some(header_value) => match header_value.to_str() {
+            Err(_) => false,
+            Ok(header_str) => header_str.split(',').map(str::trim).any(|candidate| {
+                candidate == "*" || candidate == etag || candidate.trim_start_matches("W/") == etag
+            }),
+        },
+    }
+}
+
+/// # Negotiate Encoding
+/// Picks the best encoding this server supports from the `Accept-Encoding`
+/// header. Brotli wins over gzip when the client weighs them equally.
+pub fn negotiate<V>(req: &Request<V>) -> Encoding {
+    let header_str = match req.headers().get("accept-encoding") {
+        Some(header_value) => header_value.to_str().unwrap_or(""),
+        None => "",
+    };
+    let mut best = (Encoding::Identity, 0.0);
+    for item in header_str.split(',') {
+        let mut parts = item.split(';').map(str::trim);
+        let coding = parts.next().unwrap_or("").to_lowercase();
+        let quality: f32 = parts
+            .find_map(|param| param.strip_prefix("q="))
+            .and_then(|q| q.parse().ok())
+            .unwrap_or(1.0);
+        let encoding = match coding.as_str() {
+            "br" | "*" => Encoding::Brotli,
+            "gzip" | "x-gzip" => Encoding::Gzip,
+            _ => continue,
+        };
+        let better = quality > best.1 || (quality == best.1 && encoding == Encoding::Brotli);
+        if quality > 0.0 && better {
+            best = (encoding, quality);
+        }
+    }
+    best.0
+}
+
+/// # Compress
+/// Compresses bytes with the given encoding.
+pub fn compress(data: &[u8], encoding: Encoding) -> anyhow::Result<Vec<u8>> {
+    match encoding {
+        Encoding::Identity => Ok(data.to_vec()),
+        Encoding::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(data)?;
+            Ok(encoder.finish()?)
+        }
+        Encoding::Brotli => {
+            let mut writer = CompressorWriter::new(Vec::new(), 4096, BROTLI_QUALITY, BROTLI_WINDOW);
+            writer.write_all(data)?;
+            writer.flush()?;
+            Ok(writer.into_inner())
+        }
+    }
+}
+
+/// # HTTP Date
+/// Formats a time for `Last-Modified` and friends (RFC 7231 IMF-fixdate).
+pub fn http_date(time: &DateTime<Utc>) -> String {
+    time.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// # Not Modified
+/// Returns a 304 response carrying the validators a cache needs to refresh
+/// its copy. `vary` should match the 200 being revalidated, so caches keep
+/// the encodings of a compressed response apart.
+pub fn not_modified(etag: &str, cache_control: &str, vary: Option<&str>) -> Response<Body> {
+    let mut response = Response::builder()
+        .status(StatusCode::NOT_MODIFIED)
+        .header("ETag", etag)
+        .header("Cache-Control", cache_control);
+    if let Some(vary) = vary {
+        response = response.header("Vary", vary);
+    }
+    response.body(Body::empty()).unwrap()
+}
+
+/// # JSON Response
+/// Builds a `200 OK` JSON response, compressed with whatever encoding the
+/// client prefers.
+pub fn json_response<V>(req: &Request<V>, body: String) -> Response<Body> {
+    let encoding = negotiate(req);
+    let (encoding, bytes) = match compress(body.as_bytes(), encoding) {
+        Ok(bytes) => (encoding, bytes),
+        Err(_) => (Encoding::Identity, body.into_bytes()),
+    };
+    let mut response = Response::builder()
+        .header("Content-Type", "application/json")
+        .header("Vary", "Accept-Encoding");
+    if let Some(content_encoding) = encoding.header() {
+        response = response.header("Content-Encoding", content_encoding);
+    }
+    response.body(Body::from(bytes)).unwrap()
+}
@@ -0,0 +1,321 @@
+use image::{DynamicImage, GrayImage, Luma, Rgb, RgbImage};
+use serde::Deserialize;
+
+// this file covers reducing photos to the few colors an e-ink panel can show
+
+/// The 7 colors of an ACeP panel, in the order their drivers number them.
+const ACEP: &[[u8; 3]] = &[
+    [0, 0, 0],
+    [255, 255, 255],
+    [0, 255, 0],
+    [0, 0, 255],
+    [255, 0, 0],
+    [255, 255, 0],
+    [255, 128, 0],
+];
+
+/// 8x8 Bayer threshold matrix for ordered dithering.
+const BAYER: [[u8; 8]; 8] = [
+    [0, 32, 8, 40, 2, 34, 10, 42],
+    [48, 16, 56, 24, 50, 18, 58, 26],
+    [12, 44, 4, 36, 14, 46, 6, 38],
+    [60, 28, 52, 20, 62, 30, 54, 22],
+    [3, 35, 11, 43, 1, 33, 9, 41],
+    [51, 19, 59, 27, 49, 17, 57, 25],
+    [15, 47, 7, 39, 13, 45, 5, 37],
+    [63, 31, 55, 23, 61, 29, 53, 21],
+];
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Palette {
+    /// 256 shades of gray; a plain grayscale conversion.
+    Gray,
+    /// Black and white.
+    Gray2,
+    Gray4,
+    Gray16,
+    /// The 7 colors of an Advanced Color ePaper panel.
+    Acep,
+}
+
+impl Palette {
+    fn colors(self) -> Vec<[u8; 3]> {
+        match self {
+            Palette::Acep => ACEP.to_vec(),
+            _ => {
+                let levels = self.levels();
+                (0..levels)
+                    .map(|level| {
+                        let gray = (level * 255 / (levels - 1)) as u8;
+                        [gray, gray, gray]
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    fn levels(self) -> u32 {
+        match self {
+            Palette::Gray => 256,
+            Palette::Gray2 => 2,
+            Palette::Gray4 => 4,
+            Palette::Gray16 => 16,
+            Palette::Acep => ACEP.len() as u32,
+        }
+    }
+
+    /// How far apart neighbouring colors are, which sets how strong ordered
+    /// dithering has to be.
+    fn spread(self) -> f32 {
+        match self {
+            Palette::Acep => 128.0,
+            _ => 255.0 / (self.levels() - 1) as f32,
+        }
+    }
+
+    fn is_gray(self) -> bool {
+        self != Palette::Acep
+    }
+
+    /// # Bits Per Pixel
+    /// How many bits each pixel takes in a packed bitmap.
+    pub fn bits_per_pixel(self) -> u32 {
+        match self.levels() {
+            0..=2 => 1,
+            3..=4 => 2,
+            5..=16 => 4,
+            _ => 8,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Dither {
+    #[default]
+    FloydSteinberg,
+    Ordered,
+    None,
+}
+
+/// # Indexed
+/// An image where every pixel is an index into a palette.
+pub struct Indexed {
+    palette: Palette,
+    width: u32,
+    height: u32,
+    indices: Vec<u8>,
+}
+
+/// # Quantize
+/// Maps every pixel of an image to the nearest color of a palette, spreading
+/// the difference to neighbouring pixels as asked.
+pub fn quantize(image: &DynamicImage, palette: Palette, dither: Dither) -> Indexed {
+    let colors = palette.colors();
+    let channels = if palette.is_gray() { 1 } else { 3 };
+    let (width, height, mut values): (u32, u32, Vec<f32>) = if palette.is_gray() {
+        let gray = image.to_luma();
+        let values = gray.pixels().map(|pixel| f32::from(pixel[0])).collect();
+        (gray.width(), gray.height(), values)
+    } else {
+        let rgb = image.to_rgb();
+        let values = rgb.iter().map(|value| f32::from(*value)).collect();
+        (rgb.width(), rgb.height(), values)
+    };
+    let spread = palette.spread();
+
+    let mut indices = Vec::with_capacity((width * height) as usize);
+    for y in 0..height {
+        for x in 0..width {
+            let offset = ((y * width + x) * channels) as usize;
+            let mut pixel = [0.0; 3];
+            pixel[..channels as usize].copy_from_slice(&values[offset..offset + channels as usize]);
+            if dither == Dither::Ordered {
+                let threshold = f32::from(BAYER[(y % 8) as usize][(x % 8) as usize]) / 64.0 - 0.5;
+                for value in pixel.iter_mut() {
+                    *value += threshold * spread;
+                }
+            }
+            let index = nearest(&colors, palette, &pixel);
+            indices.push(index as u8);
+
+            if dither == Dither::FloydSteinberg {
+                let color = colors[index];
+                for channel in 0..channels as usize {
+                    let error = pixel[channel] - f32::from(color[channel]);
+                    let mut spread_error = |dx: i64, dy: u32, weight: f32| {
+                        let (nx, ny) = (i64::from(x) + dx, y + dy);
+                        if nx >= 0 && nx < i64::from(width) && ny < height {
+                            let neighbour =
+                                ((ny * width + nx as u32) * channels) as usize + channel;
+                            values[neighbour] += error * weight;
+                        }
+                    };
+                    spread_error(1, 0, 7.0 / 16.0);
+                    spread_error(-1, 1, 3.0 / 16.0);
+                    spread_error(0, 1, 5.0 / 16.0);
+                    spread_error(1, 1, 1.0 / 16.0);
+                }
+            }
+        }
+    }
+    Indexed {
+        palette,
+        width,
+        height,
+        indices,
+    }
+}
+
+fn nearest(colors: &[[u8; 3]], palette: Palette, pixel: &[f32; 3]) -> usize {
+    if palette.is_gray() {
+        let levels = palette.levels() as f32 - 1.0;
+        return (pixel[0].clamp(0.0, 255.0) * levels / 255.0).round() as usize;
+    }
+    let distance = |color: &[u8; 3]| -> f32 {
+        color
+            .iter()
+            .zip(pixel.iter())
+            .map(|(color, value)| (f32::from(*color) - value).powi(2))
+            .sum()
+    };
+    let mut best = 0;
+    for (index, color) in colors.iter().enumerate() {
+        if distance(color) < distance(&colors[best]) {
+            best = index;
+        }
+    }
+    best
+}
+
+impl Indexed {
+    /// # To Image
+    /// Turns the palette indices back into pixels, for encoding as a PNG.
+    pub fn to_image(&self) -> DynamicImage {
+        let colors = self.palette.colors();
+        let color = |x: u32, y: u32| colors[self.indices[(y * self.width + x) as usize] as usize];
+        if self.palette.is_gray() {
+            DynamicImage::ImageLuma8(GrayImage::from_fn(self.width, self.height, |x, y| {
+                Luma([color(x, y)[0]])
+            }))
+        } else {
+            DynamicImage::ImageRgb8(RgbImage::from_fn(self.width, self.height, |x, y| {
+                Rgb(color(x, y))
+            }))
+        }
+    }
+
+    /// # Pack
+    /// A raw bitmap: palette indices, row by row, packed most significant
+    /// bits first at `bits_per_pixel`. Every row starts on a new byte.
+    pub fn pack(&self) -> Vec<u8> {
+        let bits = self.palette.bits_per_pixel();
+        let per_byte = 8 / bits;
+        let row_bytes = self.width.div_ceil(per_byte);
+        let mut out = vec![0u8; (row_bytes * self.height) as usize];
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let index = self.indices[(y * self.width + x) as usize];
+                let byte = (y * row_bytes + x / per_byte) as usize;
+                let shift = 8 - bits * (x % per_byte + 1);
+                out[byte] |= index << shift;
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gray(width: u32, height: u32, value: u8) -> DynamicImage {
+        DynamicImage::ImageLuma8(GrayImage::from_pixel(width, height, Luma([value])))
+    }
+
+    #[test]
+    fn gray_palettes_are_evenly_spaced() {
+        assert_eq!(
+            Palette::Gray4.colors(),
+            vec![[0, 0, 0], [85, 85, 85], [170, 170, 170], [255, 255, 255]]
+        );
+        assert_eq!(Palette::Gray2.bits_per_pixel(), 1);
+        assert_eq!(Palette::Gray4.bits_per_pixel(), 2);
+        assert_eq!(Palette::Gray16.bits_per_pixel(), 4);
+        assert_eq!(Palette::Acep.bits_per_pixel(), 4);
+        assert_eq!(Palette::Gray.bits_per_pixel(), 8);
+    }
+
+    #[test]
+    fn acep_picks_the_nearest_color() {
+        let colors = Palette::Acep.colors();
+        assert_eq!(nearest(&colors, Palette::Acep, &[250.0, 10.0, 5.0]), 4);
+        assert_eq!(nearest(&colors, Palette::Acep, &[250.0, 130.0, 10.0]), 6);
+        assert_eq!(nearest(&colors, Palette::Acep, &[20.0, 20.0, 30.0]), 0);
+    }
+
+    #[test]
+    fn without_dithering_pixels_round() {
+        let indexed = quantize(&gray(2, 1, 100), Palette::Gray4, Dither::None);
+        // 100 is nearest 85.
+        assert_eq!(indexed.indices, vec![1, 1]);
+    }
+
+    #[test]
+    fn floyd_steinberg_carries_the_error_right() {
+        // 128 rounds up to white, and the -127 error, 7/16 of it carried on,
+        // pulls the next pixel down to black.
+        let indexed = quantize(&gray(2, 1, 128), Palette::Gray2, Dither::FloydSteinberg);
+        assert_eq!(indexed.indices, vec![1, 0]);
+    }
+
+    #[test]
+    fn floyd_steinberg_keeps_the_average() {
+        let indexed = quantize(&gray(32, 32, 64), Palette::Gray2, Dither::FloydSteinberg);
+        let white = indexed.indices.iter().filter(|index| **index == 1).count();
+        // 64 is a quarter of the way to white.
+        assert!((240..=272).contains(&white), "{} white pixels", white);
+    }
+
+    #[test]
+    fn ordered_dithering_follows_the_bayer_matrix() {
+        let indexed = quantize(&gray(8, 8, 128), Palette::Gray2, Dither::Ordered);
+        // Mid gray turns white where the threshold is 32 or more: half the
+        // matrix.
+        for (i, index) in indexed.indices.iter().enumerate() {
+            let expected = u8::from(BAYER[i / 8][i % 8] >= 32);
+            assert_eq!(*index, expected, "pixel {}", i);
+        }
+    }
+
+    #[test]
+    fn pack_puts_the_first_pixel_in_the_top_bits() {
+        let indexed = Indexed {
+            palette: Palette::Gray2,
+            width: 10,
+            height: 2,
+            indices: vec![1, 0, 1, 1, 0, 0, 0, 0, 1, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 1],
+        };
+        // Each row starts on a new byte.
+        assert_eq!(
+            indexed.pack(),
+            vec![0b1011_0000, 0b1100_0000, 0b0000_0001, 0b0100_0000]
+        );
+
+        let indexed = Indexed {
+            palette: Palette::Gray4,
+            width: 3,
+            height: 1,
+            indices: vec![3, 1, 2],
+        };
+        assert_eq!(indexed.pack(), vec![0b1101_1000]);
+    }
+
+    #[test]
+    fn to_image_gives_the_palette_colors_back() {
+        let indexed = quantize(&gray(3, 1, 90), Palette::Gray4, Dither::None);
+        let image = indexed.to_image().to_luma();
+        assert!(image.pixels().all(|pixel| pixel[0] == 85));
+    }
+}
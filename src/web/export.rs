@@ -0,0 +1,134 @@
+use super::{bad_request, internal_server_error, query_parameters};
+use crate::export::{export, split_sensors, ExportQuery, Format, Units};
+use chrono::{DateTime, Utc};
+use hyper::body::{Bytes, Sender};
+use hyper::{Body, Request, Response, StatusCode};
+use serde::Deserialize;
+use std::io::{BufWriter, Write};
+use tokio::runtime::Handle;
+
+// this file covers the `/export` endpoint
+
+#[derive(Debug, Deserialize)]
+struct ExportInput {
+    end_date: DateTime<Utc>,
+    format: Option<String>,
+    sensors: Option<String>,
+    start_date: DateTime<Utc>,
+    units: Option<Units>,
+}
+
+/// # Export Handler
+/// Exports readings as CSV, NDJSON or Parquet. The format comes from the
+/// `format` query parameter, or the `Accept` header when that is missing.
+///
+/// Sample query string:
+/// end_date=2020-09-01T00:00:00-05:00&start_date=2020-08-01T00:00:00-05:00&sensors=Bedroom,Fridge&units=c
+///
+/// Returns a file download in the chosen format.
+pub fn export_readings(req: Request<Body>) -> Response<Body> {
+    let input: ExportInput = match query_parameters(&req) {
+        None => return bad_request(),
+        Some(input) => input,
+    };
+    let format = match &input.format {
+        Some(name) => match Format::from_name(name) {
+            None => return bad_request(),
+            Some(format) => format,
+        },
+        None => match req.headers().get("accept") {
+            None => Format::Csv,
+            Some(accept) => match Format::from_accept(accept.to_str().unwrap_or("")) {
+                None => return not_acceptable(),
+                Some(format) => format,
+            },
+        },
+    };
+    if format == Format::Parquet && !cfg!(feature = "parquet") {
+        return not_acceptable();
+    }
+    let query = ExportQuery {
+        start_date: input.start_date,
+        end_date: input.end_date,
+        sensors: input
+            .sensors
+            .as_deref()
+            .map(split_sensors)
+            .unwrap_or_default(),
+        units: input.units.unwrap_or_default(),
+    };
+    let file_name = format!(
+        "thermostats-{}-{}.{}",
+        query.start_date.format("%Y%m%d"),
+        query.end_date.format("%Y%m%d"),
+        format.extension()
+    );
+
+    // The export writes to a `std::io::Write`, so it runs on its own thread
+    // and hands each buffer full of output to the response body.
+    let (sender, body) = Body::channel();
+    let handle = Handle::current();
+    std::thread::spawn(move || {
+        let connection = match crate::db::connection() {
+            Ok(connection) => connection,
+            Err(err) => {
+                tracing::error!("Export failed: {:?}", err);
+                sender.abort();
+                return;
+            }
+        };
+        let mut out = BufWriter::with_capacity(64 * 1024, BodyWriter { handle, sender });
+        if let Err(err) = export(&connection, &query, format, &mut out) {
+            tracing::error!("Export failed: {:?}", err);
+            if let Ok(writer) = out.into_inner() {
+                writer.sender.abort();
+            }
+        }
+    });
+
+    match Response::builder()
+        .header("Content-Type", format.content_type())
+        .header(
+            "Content-Disposition",
+            format!("attachment; filename=\"{}\"", file_name),
+        )
+        .body(body)
+    {
+        Ok(response) => response,
+        Err(_) => internal_server_error(),
+    }
+}
+
+/// Adapts a response body channel to `std::io::Write`, blocking the
+/// (non-runtime) thread it is used on until the client takes the data.
+struct BodyWriter {
+    handle: Handle,
+    sender: Sender,
+}
+
+impl Write for BodyWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let chunk = Bytes::copy_from_slice(buf);
+        let sender = &mut self.sender;
+        match self.handle.block_on(sender.send_data(chunk)) {
+            Ok(_) => Ok(buf.len()),
+            Err(err) => Err(std::io::Error::new(std::io::ErrorKind::BrokenPipe, err)),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// # Not Acceptable
+/// Returns a response payload that indicates a 406 not acceptable.
+fn not_acceptable() -> Response<Body> {
+    match Response::builder()
+        .status(StatusCode::NOT_ACCEPTABLE)
+        .body(Body::from("406 Not Acceptable"))
+    {
+        Ok(response) => response,
+        Err(_) => internal_server_error(),
+    }
+}
@@ -0,0 +1,342 @@
+use super::cache::json_response;
+use super::{bad_request, internal_server_error, query_parameters};
+use crate::db::DbConnection;
+use crate::rollup::{Resolution, Rollup};
+use crate::Thermostat;
+use chrono::{DateTime, TimeZone, Utc};
+use hyper::body::Bytes;
+use hyper::{Body, Request, Response};
+use serde::{Deserialize, Serialize};
+
+// this file covers the history (`/past`) endpoint
+
+/// Rows fetched from the database per round trip when streaming.
+const STREAM_CHUNK: i64 = 500;
+/// The most rows a single page may hold.
+const MAX_LIMIT: i64 = 5000;
+
+type PageKey = (DateTime<Utc>, i32);
+
+#[derive(Debug, Deserialize)]
+struct PastInput {
+    cursor: Option<String>,
+    end_date: DateTime<Utc>,
+    format: Option<String>,
+    limit: Option<i64>,
+    resolution: Option<String>,
+    start_date: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+struct PastPage {
+    thermostats: Vec<Row>,
+    next_cursor: Option<String>,
+}
+
+/// A reading, or a summary of readings when the resolution is `hourly` or
+/// `daily`.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Row {
+    Raw(Thermostat),
+    Rollup(Rollup),
+}
+
+impl Row {
+    fn key(&self) -> PageKey {
+        match self {
+            Row::Raw(thermostat) => (thermostat.time(), thermostat.id),
+            Row::Rollup(rollup) => (rollup.time(), rollup.id),
+        }
+    }
+}
+
+/// # Load Page
+/// Reads up to `limit` rows after `after`, ordered by time, from the table
+/// for the resolution.
+fn load_page(
+    connection: &DbConnection,
+    resolution: Resolution,
+    start_date: &DateTime<Utc>,
+    end_date: &DateTime<Utc>,
+    after: Option<PageKey>,
+    limit: i64,
+) -> diesel::QueryResult<Vec<Row>> {
+    Ok(match resolution {
+        Resolution::Raw => {
+            Thermostat::query_page(connection, start_date, end_date, None, after, limit)?
+                .into_iter()
+                .map(Row::Raw)
+                .collect()
+        }
+        _ => Rollup::query_page(connection, resolution, start_date, end_date, after, limit)?
+            .into_iter()
+            .map(Row::Rollup)
+            .collect(),
+    })
+}
+
+/// # Past Handler
+/// Returns a past historical report. This queries data from the database
+/// based on query parameters. `start_date` and `end_date` are mandatory.
+///
+/// Sample query string:
+/// end_date=2020-03-02T00:00:00-05:00&start_date=2020-03-01T00:00:00-05:00
+///
+/// `resolution` is `raw`, `hourly`, `daily` or `auto` (the default), which
+/// picks one from the length of the range and the retention settings. The
+/// `X-Resolution` header says which was used.
+///
+/// There are three ways to get the report:
+/// 1. Without `limit` or `cursor`, returns a `Vec<Therm>` in a response body.
+/// 2. With `limit` (and `cursor` from the previous page), returns a
+///    `PastPage`. Keep following `next_cursor` until it is `null`.
+/// 3. With `format=ndjson` (or `Accept: application/x-ndjson`), streams one
+///    `Therm` per line, reading the database in chunks as the client keeps
+///    up. `limit` caps the total number of rows.
+pub async fn past(req: Request<Body>) -> Response<Body> {
+    let input: PastInput = match query_parameters(&req) {
+        None => return bad_request(),
+        Some(input) => input,
+    };
+    let after = match &input.cursor {
+        None => None,
+        Some(cursor) => match decode_cursor(cursor) {
+            None => return bad_request(),
+            Some(after) => Some(after),
+        },
+    };
+    if let Some(limit) = input.limit {
+        if limit < 1 {
+            return bad_request();
+        }
+    }
+    let resolution = match input.resolution.as_deref() {
+        None | Some("auto") => Resolution::choose(&input.start_date, &input.end_date),
+        Some(name) => match Resolution::parse(name) {
+            Some(resolution) => resolution,
+            None => return bad_request(),
+        },
+    };
+
+    let mut response = if wants_ndjson(&req, &input) {
+        stream(input, resolution, after)
+    } else if input.limit.is_none() && after.is_none() {
+        everything(&req, &input, resolution).await
+    } else {
+        let limit = input.limit.unwrap_or(MAX_LIMIT).min(MAX_LIMIT);
+        page(&req, &input, resolution, after, limit).await
+    };
+    response
+        .headers_mut()
+        .insert("X-Resolution", resolution.name().parse().unwrap());
+    response
+}
+
+/// # Everything
+/// The original `/past` response: every reading in the range in one array.
+async fn everything(
+    req: &Request<Body>,
+    input: &PastInput,
+    resolution: Resolution,
+) -> Response<Body> {
+    let (start_date, end_date) = (input.start_date, input.end_date);
+    let result = crate::db::run(move |connection| match resolution {
+        Resolution::Raw => Thermostat::query_dates(connection, &start_date, &end_date)
+            .map(|thermostats| thermostats.into_iter().map(Row::Raw).collect()),
+        _ => load_page(
+            connection,
+            resolution,
+            &start_date,
+            &end_date,
+            None,
+            i64::MAX,
+        ),
+    })
+    .await;
+    match result {
+        Err(_) | Ok(Err(_)) => internal_server_error(),
+        Ok(Ok(result)) => match serde_json::to_string(&result) {
+            Err(_) => internal_server_error(),
+            Ok(body) => json_response(req, body),
+        },
+    }
+}
+
+/// # Page
+/// Returns one page of readings and the cursor for the next one. There is no
+/// next cursor once a page comes back short.
+async fn page(
+    req: &Request<Body>,
+    input: &PastInput,
+    resolution: Resolution,
+    after: Option<PageKey>,
+    limit: i64,
+) -> Response<Body> {
+    let (start_date, end_date) = (input.start_date, input.end_date);
+    let result = crate::db::run(move |connection| {
+        load_page(connection, resolution, &start_date, &end_date, after, limit)
+    })
+    .await;
+    match result {
+        Err(_) | Ok(Err(_)) => internal_server_error(),
+        Ok(Ok(thermostats)) => {
+            let next_cursor = if thermostats.len() as i64 == limit {
+                thermostats.last().map(|row| encode_cursor(row.key()))
+            } else {
+                None
+            };
+            let page = PastPage {
+                thermostats,
+                next_cursor,
+            };
+            match serde_json::to_string(&page) {
+                Err(_) => internal_server_error(),
+                Ok(body) => json_response(req, body),
+            }
+        }
+    }
+}
+
+/// # Stream
+/// Streams readings as newline delimited JSON. Database reads happen on the
+/// blocking thread pool one chunk at a time, and the next chunk is not read
+/// until the client has taken the previous one, so memory use stays flat no
+/// matter how long the range is.
+fn stream(input: PastInput, resolution: Resolution, mut after: Option<PageKey>) -> Response<Body> {
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        let mut connection: Option<crate::db::Connection> = None;
+        let mut remaining = input.limit.unwrap_or(i64::MAX);
+        while remaining > 0 {
+            let chunk = remaining.min(STREAM_CHUNK);
+            let (start_date, end_date) = (input.start_date, input.end_date);
+            let result = tokio::task::spawn_blocking(move || {
+                // Every chunk is read on the same connection.
+                let connection = match connection {
+                    Some(connection) => connection,
+                    None => crate::db::connection()?,
+                };
+                let rows = load_page(
+                    &connection,
+                    resolution,
+                    &start_date,
+                    &end_date,
+                    after,
+                    chunk,
+                )?;
+                Ok::<_, anyhow::Error>((connection, rows))
+            })
+            .await;
+            let rows = match result {
+                Ok(Ok((db, rows))) => {
+                    connection = Some(db);
+                    rows
+                }
+                Ok(Err(err)) => {
+                    tracing::error!("Streaming /past failed: {:?}", err);
+                    sender.abort();
+                    return;
+                }
+                Err(err) => {
+                    tracing::error!("Streaming /past failed: {:?}", err);
+                    sender.abort();
+                    return;
+                }
+            };
+
+            let mut data = String::new();
+            for row in &rows {
+                if let Ok(line) = serde_json::to_string(row) {
+                    data.push_str(&line);
+                    data.push('\n');
+                }
+            }
+            if sender.send_data(Bytes::from(data)).await.is_err() {
+                // The client went away.
+                return;
+            }
+
+            remaining -= rows.len() as i64;
+            match rows.last() {
+                Some(last) if rows.len() as i64 == chunk => {
+                    after = Some(last.key());
+                }
+                _ => return,
+            }
+        }
+    });
+
+    match Response::builder()
+        .header("Content-Type", "application/x-ndjson")
+        .body(body)
+    {
+        Ok(response) => response,
+        Err(_) => internal_server_error(),
+    }
+}
+
+fn wants_ndjson<V>(req: &Request<V>, input: &PastInput) -> bool {
+    if let Some(format) = &input.format {
+        return format.eq("ndjson");
+    }
+    match req.headers().get("accept") {
+        Some(header_value) => match header_value.to_str() {
+            Ok(accept) => accept.contains("ndjson"),
+            Err(_) => false,
+        },
+        None => false,
+    }
+}
+
+/// # Encode Cursor
+/// Cursors are opaque to clients. Under the hood they are the time (in
+/// microseconds) and id of the last row on a page, in hex.
+fn encode_cursor((time, id): PageKey) -> String {
+    let micros = time.timestamp_micros();
+    format!("{:x}.{:x}", micros as u64, id as u32)
+}
+
+fn decode_cursor(cursor: &str) -> Option<PageKey> {
+    let mut parts = cursor.split('.');
+    let micros = u64::from_str_radix(parts.next()?, 16).ok()? as i64;
+    let id = u32::from_str_radix(parts.next()?, 16).ok()? as i32;
+    if parts.next().is_some() {
+        return None;
+    }
+    let time = Utc
+        .timestamp_opt(
+            micros.div_euclid(1_000_000),
+            (micros.rem_euclid(1_000_000) * 1000) as u32,
+        )
+        .single()?;
+    Some((time, id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursor_round_trips() {
+        let time = Utc.timestamp_opt(1_598_119_200, 123_456_000).unwrap();
+        let cursor = encode_cursor((time, 42));
+        assert_eq!(decode_cursor(&cursor), Some((time, 42)));
+    }
+
+    #[test]
+    fn cursor_before_1970_round_trips() {
+        let time = Utc.timestamp_opt(-86_400, 500_000_000).unwrap();
+        assert_eq!(decode_cursor(&encode_cursor((time, 7))), Some((time, 7)));
+    }
+
+    #[test]
+    fn bad_cursors_are_refused() {
+        assert_eq!(decode_cursor("7fffffffffffffff.1"), None);
+        assert_eq!(decode_cursor("8000000000000000.1"), None);
+        assert_eq!(decode_cursor("10"), None);
+        assert_eq!(decode_cursor("10.1.1"), None);
+        assert_eq!(decode_cursor("zz.1"), None);
+        assert_eq!(decode_cursor(""), None);
+    }
+}
@@ -1,120 +1,319 @@
 use crate::ecobee::{get_token, install, save_token, GRANT_PIN};
-use crate::Thermostat;
-use chrono::{DateTime, Utc};
+use cache::{etag_matches, http_date, negotiate, not_modified};
+use chrono::Utc;
 use hyper::header::HeaderValue;
 use hyper::server::Server;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Method, Request, Response, StatusCode};
-use photo::{photo_paths, start_fetching_backgrounds};
+use lazy_static::lazy_static;
+use photo::{start_scheduling, start_watching};
 use serde::Deserialize;
 use std::convert::Infallible;
-use std::env;
-use std::io::Read;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Instant;
+use tracing::Instrument;
 
+pub use cache::CachedBody;
+pub use photo::job::State as JobState;
+pub use photo::sync_now as sync_photos;
+
+mod alerts;
+mod analytics;
+mod background;
+mod cache;
+mod dither;
+mod export;
+mod history;
+mod multipart;
 mod photo;
+mod readings;
+mod rendition;
+mod slideshow;
+mod webhooks;
+
+/// Paths that do not need the shared secret, so probes from systemd or a load
+/// balancer can reach them.
+const PUBLIC_PATHS: &[&str] = &["/healthz", "/readyz"];
+
+lazy_static! {
+    /// Request ids start with the time the server started, so they do not
+    /// repeat after a restart.
+    static ref STARTED: i64 = Utc::now().timestamp();
+}
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
 
 #[derive(Deserialize)]
-struct InstallTwoInput {
-    code: String,
+struct LoggingInput {
+    filter: String,
 }
 
-#[derive(Debug, Deserialize)]
-struct PastInput {
-    end_date: DateTime<Utc>,
-    start_date: DateTime<Utc>,
+#[derive(Deserialize)]
+struct InstallTwoInput {
+    code: String,
 }
 
 /// # Start Server
 /// Starts the hyper HTTP server. Also contains the routing code.
 #[tokio::main]
 pub async fn start() {
-    let port: u16 = env::var("LISTEN_PORT").unwrap().parse().unwrap();
+    start_watching();
+    start_scheduling();
+    let port = crate::config::get().listen_port;
     let addr = SocketAddr::from(([127, 0, 0, 1], port));
     let server = Server::bind(&addr);
     let server = server.serve(make_service_fn(|_connection| async {
-        Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
-            let path = if req.method().eq(&Method::OPTIONS) {
-                "/options"
-            } else if !is_authorized(&req) {
-                "/forbidden"
-            } else {
-                req.uri().path()
-            };
-            let cors_host = env::var("CORS_HOST").unwrap();
-            let mut response = match path {
-                "/now" => now(),
-                "/past" => past(req),
-                "/time" => time(),
-                "/release-notes" => release_notes(),
-                "/install/1" => install_1(req).await,
-                "/install/2" => install_2(req).await,
-                "/background-photos" => background_photos(),
-                "/background-photos/refresh" => background_photos_refresh(),
-                "/forbidden" => forbidden(),
-                "/options" => Response::new(Body::from("200 OK")),
-                _ => not_found(),
-            };
-            // Add CORS headers
-            response.headers_mut().insert(
-                "Access-Control-Allow-Origin",
-                HeaderValue::from_str(&cors_host).unwrap(),
+        Ok::<_, Infallible>(service_fn(|req: Request<Body>| {
+            let id = request_id(&req);
+            let span = tracing::info_span!(
+                "request",
+                id = %id,
+                method = %req.method(),
+                path = %req.uri().path()
             );
-            response.headers_mut().insert(
-                "Access-Control-Allow-Headers",
-                HeaderValue::from_str("authorization").unwrap(),
-            );
-            Ok::<_, Infallible>(response)
+            route(req, id).instrument(span)
         }))
     }));
 
-    crate::log_message("hyper server started");
+    tracing::info!("hyper server started");
 
     if let Err(e) = server.await {
-        crate::log_error(&format!("hyper server error: {:?}", e));
+        tracing::error!("hyper server error: {:?}", e);
+    }
+}
+
+/// # Route
+/// Handles one request: checks the shared secret, picks the handler for the
+/// path and adds the metrics, CORS headers and request id.
+async fn route(req: Request<Body>, id: String) -> Result<Response<Body>, Infallible> {
+    let path = if req.method().eq(&Method::OPTIONS) {
+        "/options"
+    } else if !is_authorized(&req) && !PUBLIC_PATHS.contains(&req.uri().path()) {
+        "/forbidden"
+    } else {
+        req.uri().path()
+    };
+    let cors_host = crate::config::get().cors_host.clone();
+    let start = Instant::now();
+    let job_id = path.strip_prefix("/background-photos/jobs/");
+    let photo_id = path
+        .strip_prefix("/background-photos/")
+        .filter(|id| *id != "refresh" && *id != "jobs" && job_id.is_none());
+    let display = path.strip_prefix("/playlists/");
+    let rule_id = path.strip_prefix("/alerts/rules/");
+    let webhook_id = path.strip_prefix("/webhooks/");
+    let route = if job_id.is_some() {
+        String::from("/background-photos/jobs/{id}")
+    } else if photo_id.is_some() {
+        String::from("/background-photos/{id}")
+    } else if display.is_some() {
+        String::from("/playlists/{display}")
+    } else if rule_id.is_some() {
+        String::from("/alerts/rules/{id}")
+    } else if matches!(webhook_id, Some(id) if id.ends_with("/deliveries")) {
+        String::from("/webhooks/{id}/deliveries")
+    } else if webhook_id.is_some() {
+        String::from("/webhooks/{id}")
+    } else {
+        path.to_string()
+    };
+    let mut response = match path {
+        "/now" => now(&req),
+        "/past" => history::past(req).await,
+        "/readings" => readings::readings(req).await,
+        "/export" => export::export_readings(req),
+        "/analytics/degree-days" => analytics::degree_days_report(req).await,
+        "/metrics" => metrics(),
+        "/healthz" => healthz(),
+        "/readyz" => readyz().await,
+        "/time" => time(),
+        "/release-notes" => release_notes(),
+        "/logging" => logging(req).await,
+        "/install/1" => install_1(req).await,
+        "/install/2" => install_2(req).await,
+        "/background-photos" => background::background_photos(req).await,
+        "/background-photos/refresh" => background::refresh(&req),
+        "/background-photos/jobs" => background::refresh_jobs(&req, None),
+        _ if job_id.is_some() => background::refresh_jobs(&req, job_id),
+        _ if photo_id.is_some() => {
+            let id = photo_id.unwrap_or_default().to_string();
+            background::background_photo(req, id).await
+        }
+        "/photos/current" => slideshow::current(req).await,
+        "/playlists" => slideshow::playlists(req, None).await,
+        _ if display.is_some() => {
+            let display = display.map(String::from);
+            slideshow::playlists(req, display).await
+        }
+        "/alerts" => alerts::active(req).await,
+        "/alerts/history" => alerts::history(req).await,
+        "/alerts/rules" => alerts::rules(req, None).await,
+        _ if rule_id.is_some() => {
+            let rule_id = rule_id.map(String::from);
+            alerts::rules(req, rule_id).await
+        }
+        "/webhooks" => webhooks::webhooks(req, None).await,
+        _ if webhook_id.is_some() => {
+            let webhook_id = webhook_id.unwrap_or_default().to_string();
+            match webhook_id.strip_suffix("/deliveries") {
+                Some(id) => webhooks::deliveries(req, id).await,
+                None => webhooks::webhooks(req, Some(webhook_id)).await,
+            }
+        }
+        "/forbidden" => forbidden(),
+        "/options" => Response::new(Body::from("200 OK")),
+        _ => not_found(),
+    };
+    // Unmatched paths all share one label, so random URLs cannot
+    // create new time series.
+    let route = if response.status() == StatusCode::NOT_FOUND {
+        "unmatched"
+    } else {
+        &route
+    };
+    let status = response.status().as_u16().to_string();
+    crate::metrics::inc(
+        crate::metrics::HTTP_REQUESTS,
+        &[("route", route), ("status", &status)],
+    );
+    crate::metrics::observe_since(
+        crate::metrics::HTTP_REQUEST_DURATION,
+        &[("route", route)],
+        start,
+    );
+    // Add CORS headers
+    response.headers_mut().insert(
+        "Access-Control-Allow-Origin",
+        HeaderValue::from_str(&cors_host).unwrap(),
+    );
+    response.headers_mut().insert(
+        "Access-Control-Allow-Headers",
+        HeaderValue::from_str("authorization, content-type, if-none-match, if-range, range")
+            .unwrap(),
+    );
+    response.headers_mut().insert(
+        "Access-Control-Allow-Methods",
+        HeaderValue::from_str("GET, POST, PUT, DELETE, OPTIONS").unwrap(),
+    );
+    response
+        .headers_mut()
+        .insert("X-Request-Id", HeaderValue::from_str(&id).unwrap());
+    tracing::info!(
+        status = response.status().as_u16(),
+        elapsed_ms = start.elapsed().as_millis() as u64,
+        "Handled request"
+    );
+    Ok(response)
+}
+
+/// # Request Id
+/// Uses the caller's `X-Request-Id`, so a proxy's id carries through to our
+/// logs, or makes one up.
+fn request_id<V>(req: &Request<V>) -> String {
+    let given = req
+        .headers()
+        .get("X-Request-Id")
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= 64)
+        .filter(|id| id.chars().all(|c| c.is_ascii_graphic()));
+    match given {
+        Some(id) => id.to_string(),
+        None => format!(
+            "{:x}-{:x}",
+            *STARTED,
+            NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed)
+        ),
     }
 }
 
 /// # Now Handler
 /// Returns the current conditions. It does it by reading the static
 /// now response from the crate root and copying it into a request body.
+/// The response may be cached by clients until the worker next runs, and
+/// answers `If-None-Match` with a 304 when the data has not changed.
 ///
 /// Returns a `NowRepsonse` in a response body.
-fn now() -> Response<Body> {
-    let now = Arc::clone(&crate::NOW_STR);
+fn now<V>(req: &Request<V>) -> Response<Body> {
+    let now = Arc::clone(&crate::NOW_BODY);
     let now = now.read();
     match now {
-        Ok(now) => Response::new(Body::from(now.clone())),
+        Ok(now) => {
+            let max_age = (crate::worker::next_run() - Utc::now())
+                .num_seconds()
+                .max(0);
+            let cache_control = format!("public, max-age={}", max_age);
+            if etag_matches(req, &now.etag) {
+                return not_modified(&now.etag, &cache_control, Some("Accept-Encoding"));
+            }
+            let (encoding, body) = now.encoded(negotiate(req));
+            let mut response = Response::builder()
+                .header("Content-Type", "application/json")
+                .header("ETag", &now.etag)
+                .header("Last-Modified", http_date(&now.modified))
+                .header("Cache-Control", cache_control)
+                .header("Vary", "Accept-Encoding");
+            if let Some(content_encoding) = encoding.header() {
+                response = response.header("Content-Encoding", content_encoding);
+            }
+            match response.body(Body::from(body)) {
+                Ok(response) => response,
+                Err(_) => internal_server_error(),
+            }
+        }
         Err(_) => internal_server_error(),
     }
 }
 
-/// # Past Handler
-/// Returns a past historical report. This queries data from the database
-/// based on query parameters. All query parameters are mandatory and must
-/// be sent in alphabetical order.
-///
-/// Sample query string:
-/// end-date=2020-03-02T00:00:00-05:00&start-date=2020-03-01T00:00:00-05:00
+/// # Metrics
+/// Returns metrics for Prometheus to scrape, in its text format.
+fn metrics() -> Response<Body> {
+    match Response::builder()
+        .header("Content-Type", "text/plain; version=0.0.4")
+        .body(Body::from(crate::metrics::render()))
+    {
+        Ok(response) => response,
+        Err(_) => internal_server_error(),
+    }
+}
+
+/// # Health Check
+/// Answers as long as the process is alive and serving requests.
+fn healthz() -> Response<Body> {
+    match Response::builder()
+        .header("Content-Type", "application/json")
+        .body(Body::from("{\"status\":\"ok\"}"))
+    {
+        Ok(response) => response,
+        Err(_) => internal_server_error(),
+    }
+}
+
+/// # Readiness Check
+/// Reports the status of the database, migrations and remote API's. Answers
+/// 503 Service Unavailable if any of them are failing.
 ///
-/// Returns a `Vec<Therm>` in a response body.
-fn past(req: Request<Body>) -> Response<Body> {
-    let query: Option<PastInput> = query_parameters(&req);
-    match query {
-        None => bad_request(),
-        Some(input) => {
-            let connection = crate::establish_connection();
-            let result = Thermostat::query_dates(&connection, &input.start_date, &input.end_date);
-            drop(connection);
-            match result {
-                Err(_) => internal_server_error(),
-                Ok(result) => match serde_json::to_string(&result) {
-                    Err(_) => internal_server_error(),
-                    Ok(body) => Response::new(Body::from(body)),
-                },
-            }
-        }
+/// Returns a `Readiness` in a response body.
+async fn readyz() -> Response<Body> {
+    let readiness = match tokio::task::spawn_blocking(crate::health::readiness).await {
+        Ok(readiness) => readiness,
+        Err(_) => return internal_server_error(),
+    };
+    let status = if readiness.status == crate::health::Status::Ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    match serde_json::to_string(&readiness) {
+        Err(_) => internal_server_error(),
+        Ok(body) => match Response::builder()
+            .status(status)
+            .header("Content-Type", "application/json")
+            .body(Body::from(body))
+        {
+            Ok(response) => response,
+            Err(_) => internal_server_error(),
+        },
     }
 }
 
@@ -133,6 +332,34 @@ fn time() -> Response<Body> {
     }
 }
 
+/// # Logging
+/// `GET /logging` returns the log filter in use. `PUT /logging` with
+/// `{"filter": "info,sql=debug"}` changes it until the next restart.
+async fn logging(mut req: Request<Body>) -> Response<Body> {
+    if Method::PUT.eq(req.method()) {
+        let body =
+            match hyper::body::to_bytes(std::mem::replace(req.body_mut(), Body::empty())).await {
+                Ok(body) => body,
+                Err(_) => return bad_request(),
+            };
+        let input = match serde_json::from_slice::<LoggingInput>(&body) {
+            Ok(input) => input,
+            Err(err) => {
+                tracing::warn!("Could not decode log filter {:?}", err);
+                return bad_request();
+            }
+        };
+        if let Err(err) = crate::logging::set_filter(&input.filter) {
+            tracing::warn!("Could not change log filter: {}", err);
+            return bad_request();
+        }
+    } else if !Method::GET.eq(req.method()) {
+        return method_not_allowed();
+    }
+    let body = serde_json::json!({ "filter": crate::logging::filter() }).to_string();
+    cache::json_response(&req, body)
+}
+
 fn release_notes() -> Response<Body> {
     match Response::builder()
         .header("Content-Type", "text/markdown")
@@ -145,6 +372,9 @@ fn release_notes() -> Response<Body> {
 }
 
 async fn install_1(req: Request<Body>) -> Response<Body> {
+    if crate::config::get().ecobee.is_none() {
+        return not_found();
+    }
     match *req.method() {
         Method::GET => match install().await {
             Ok(install_response) => match serde_json::to_string(&install_response) {
@@ -158,6 +388,9 @@ async fn install_1(req: Request<Body>) -> Response<Body> {
 }
 
 async fn install_2(req: Request<Body>) -> Response<Body> {
+    if crate::config::get().ecobee.is_none() {
+        return not_found();
+    }
     if !Method::GET.eq(req.method()) {
         return method_not_allowed();
     }
@@ -170,12 +403,11 @@ async fn install_2(req: Request<Body>) -> Response<Body> {
                 Err(_) => internal_server_error(),
                 Ok(token_response) => {
                     let token = token_response.to_token();
-                    let db = crate::establish_connection();
-                    let token = save_token(&token, &db);
-                    drop(db);
+                    let token = crate::db::run(move |db| save_token(&token, db)).await;
                     match token {
-                        None => Response::new(Body::from("false")),
-                        Some(_) => Response::new(Body::from("true")),
+                        Err(_) => internal_server_error(),
+                        Ok(None) => Response::new(Body::from("false")),
+                        Ok(Some(_)) => Response::new(Body::from("true")),
                     }
                 }
             }
@@ -183,73 +415,20 @@ async fn install_2(req: Request<Body>) -> Response<Body> {
     }
 }
 
-/// # Background Photos
-/// Returns the background photos for the app in a multi-part response
-fn background_photos() -> Response<Body> {
-    let mut body: Vec<u8> = Vec::new();
-    let paths = photo_paths();
-    let len = paths.len();
-    for (i, path) in paths.into_iter().enumerate() {
-        let file_name = path.file_name().unwrap().to_str().unwrap();
-        body.extend("--e03d07419ce04d4e79a14c76fb6fa7e0".bytes());
-        if i == len - 1 {
-            body.extend("--".bytes());
-        }
-        body.extend("\r\n".bytes());
-        body.extend("Content-Type: image/jpeg\r\n".bytes());
-        body.extend("Content-Disposition: form-data; name=\"".bytes());
-        body.extend(file_name.bytes());
-        body.extend("\"\r\n\r\n".bytes());
-        if std::fs::File::open(path)
-            .unwrap()
-            .read_to_end(&mut body)
-            .is_err()
-        {
-            return internal_server_error();
-        }
-        body.extend("\r\n".bytes());
-    }
-
-    match Response::builder()
-        .status(StatusCode::OK)
-        .header(
-            "Content-Type",
-            "multipart/form-data;boundary=e03d07419ce04d4e79a14c76fb6fa7e0",
-        )
-        .body(Body::from(body))
-    {
-        Ok(response) => response,
-        Err(_) => internal_server_error(),
-    }
-}
-
-/// # Background Photos Refresh
-/// Starts the process of refreshing backgrounds, and immediately returns.
-fn background_photos_refresh() -> Response<Body> {
-    start_fetching_backgrounds();
-    Response::new(Body::from("Refresh started"))
-}
-
 /// # Check Authorization Header
 /// Checks to see if an authorization header contains a bearer token that is
-/// the env var SHARED_SECRET.
+/// the configured shared secret.
 fn is_authorized<V>(req: &Request<V>) -> bool {
-    let shared_secret = std::env::var("SHARED_SECRET");
-    match shared_secret {
-        Err(_) => false,
-        Ok(shared_secret) => {
-            let shared_secret = shared_secret.to_lowercase();
-            match req.headers().get("authorization") {
-                Some(header_value) => match header_value.to_str() {
-                    Ok(header_str) => header_str
-                        .to_lowercase()
-                        .replace("bearer ", "")
-                        .eq(&shared_secret),
-                    Err(_) => false,
-                },
-                None => false,
-            }
-        }
+    let shared_secret = crate::config::get().shared_secret.to_lowercase();
+    match req.headers().get("authorization") {
+        Some(header_value) => match header_value.to_str() {
+            Ok(header_str) => header_str
+                .to_lowercase()
+                .replace("bearer ", "")
+                .eq(&shared_secret),
+            Err(_) => false,
+        },
+        None => false,
     }
 }
 
@@ -267,7 +446,7 @@ where
     match parsed {
         Ok(parsed) => Some(parsed),
         Err(err) => {
-            crate::log_message(&format!("Could not decode query params {:?}", err));
+            tracing::warn!("Could not decode query params {:?}", err);
             None
         }
     }
@@ -285,6 +464,15 @@ fn method_not_allowed() -> Response<Body> {
     }
 }
 
+/// # Status Only
+/// Returns an empty response with a status, like 204 No Content.
+fn status_only(status: StatusCode) -> Response<Body> {
+    match Response::builder().status(status).body(Body::empty()) {
+        Ok(response) => response,
+        Err(_) => internal_server_error(),
+    }
+}
+
 /// # Bad Request
 /// Returns a response payload that indicates a 400 bad request.
 fn bad_request() -> Response<Body> {
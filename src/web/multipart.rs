@@ -0,0 +1,126 @@
+// this file covers writing multipart bodies
+
+/// # Multipart
+/// Collects parts, then writes them out with a boundary that does not appear
+/// in any of them.
+#[derive(Default)]
+pub struct Multipart {
+    parts: Vec<Part>,
+}
+
+struct Part {
+    content_type: String,
+    name: String,
+    data: Vec<u8>,
+}
+
+impl Multipart {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// # Add Part
+    /// Adds a file as a form-data part. `name` is used for both the field
+    /// name and the file name.
+    pub fn part(&mut self, content_type: &str, name: &str, data: Vec<u8>) {
+        self.parts.push(Part {
+            content_type: content_type.to_string(),
+            name: name.replace('"', ""),
+            data,
+        });
+    }
+
+    /// # Finish
+    /// Returns the `Content-Type` header (with the boundary) and the body.
+    pub fn finish(self, seed: &str) -> (String, Vec<u8>) {
+        let boundary = self.boundary(seed);
+        let delimiter = format!("--{}", boundary);
+        let mut body = Vec::new();
+        for part in self.parts {
+            body.extend(delimiter.bytes());
+            body.extend(b"\r\n");
+            body.extend(format!("Content-Type: {}\r\n", part.content_type).bytes());
+            body.extend(
+                format!(
+                    "Content-Disposition: form-data; name=\"{0}\"; filename=\"{0}\"\r\n",
+                    part.name
+                )
+                .bytes(),
+            );
+            body.extend(format!("Content-Length: {}\r\n\r\n", part.data.len()).bytes());
+            body.extend(part.data);
+            body.extend(b"\r\n");
+        }
+        body.extend(delimiter.bytes());
+        body.extend(b"--\r\n");
+        let content_type = format!("multipart/form-data; boundary={}", boundary);
+        (content_type, body)
+    }
+
+    /// # Boundary
+    /// Builds a boundary from `seed`, adding a counter until none of the
+    /// parts contain it.
+    fn boundary(&self, seed: &str) -> String {
+        let seed: String = seed.chars().filter(char::is_ascii_alphanumeric).collect();
+        let mut attempt = 0;
+        loop {
+            let boundary = format!("therm-hub-{}-{}", seed, attempt);
+            let needle = boundary.as_bytes();
+            let clashes = self.parts.iter().any(|part| {
+                part.data
+                    .windows(needle.len())
+                    .any(|window| window == needle)
+            });
+            if !clashes {
+                return boundary;
+            }
+            attempt += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parts_are_framed_by_the_boundary() {
+        let mut multipart = Multipart::new();
+        multipart.part("image/jpeg", "a.jpg", b"AAA".to_vec());
+        multipart.part("image/png", "b\".png", b"BB".to_vec());
+        let (content_type, body) = multipart.finish("\"etag-1\"");
+        assert_eq!(
+            content_type,
+            "multipart/form-data; boundary=therm-hub-etag1-0"
+        );
+        let expected = "--therm-hub-etag1-0\r\n\
+            Content-Type: image/jpeg\r\n\
+            Content-Disposition: form-data; name=\"a.jpg\"; filename=\"a.jpg\"\r\n\
+            Content-Length: 3\r\n\r\n\
+            AAA\r\n\
+            --therm-hub-etag1-0\r\n\
+            Content-Type: image/png\r\n\
+            Content-Disposition: form-data; name=\"b.png\"; filename=\"b.png\"\r\n\
+            Content-Length: 2\r\n\r\n\
+            BB\r\n\
+            --therm-hub-etag1-0--\r\n";
+        assert_eq!(String::from_utf8(body).unwrap(), expected);
+    }
+
+    #[test]
+    fn boundary_avoids_the_parts() {
+        let mut multipart = Multipart::new();
+        multipart.part(
+            "text/plain",
+            "a",
+            b"x therm-hub-seed-0 therm-hub-seed-1 y".to_vec(),
+        );
+        assert_eq!(multipart.boundary("seed"), "therm-hub-seed-2");
+    }
+
+    #[test]
+    fn empty_body_is_just_the_closing_delimiter() {
+        let (_, body) = Multipart::new().finish("seed");
+        assert_eq!(body, b"--therm-hub-seed-0--\r\n");
+    }
+}
@@ -0,0 +1,137 @@
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::{Arc, RwLock};
+
+// this file covers tracking photo refreshes as jobs
+
+/// Finished jobs kept for the status endpoint. Jobs are only kept in memory.
+const MAX_JOBS: usize = 20;
+/// Errors kept per job; the rest are only logged.
+const MAX_ERRORS: usize = 50;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    Running,
+    /// Every source synced. Single photos may still have failed; see
+    /// `failed`.
+    Succeeded,
+    /// A source could not be synced.
+    Failed,
+}
+
+/// What started a refresh.
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Trigger {
+    Api,
+    Cli,
+    Schedule,
+    Watcher,
+}
+
+/// # Job
+/// One refresh of the background photos and how far it has got.
+#[derive(Clone, Debug, Serialize)]
+pub struct Job {
+    pub id: u64,
+    pub trigger: Trigger,
+    pub state: State,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    /// Photos to download, known once every source is listed.
+    pub total: usize,
+    pub downloaded: usize,
+    /// Photos that were already stored.
+    pub skipped: usize,
+    pub failed: usize,
+    pub removed: usize,
+    pub errors: Vec<String>,
+}
+
+type StsJobs = Arc<RwLock<VecDeque<Job>>>;
+lazy_static! {
+    /// Newest first.
+    static ref JOBS: StsJobs = Arc::new(RwLock::new(VecDeque::new()));
+}
+
+/// # Begin
+/// Records a new running job, unless one is already running. Returns the
+/// job and whether it is new.
+pub fn begin(trigger: Trigger) -> Option<(Job, bool)> {
+    let jobs = Arc::clone(&JOBS);
+    let mut jobs = jobs.write().ok()?;
+    if let Some(running) = jobs.iter().find(|job| job.state == State::Running) {
+        return Some((running.clone(), false));
+    }
+    let job = Job {
+        id: jobs.front().map(|job| job.id + 1).unwrap_or(1),
+        trigger,
+        state: State::Running,
+        started_at: Utc::now(),
+        finished_at: None,
+        total: 0,
+        downloaded: 0,
+        skipped: 0,
+        failed: 0,
+        removed: 0,
+        errors: Vec::new(),
+    };
+    jobs.push_front(job.clone());
+    jobs.truncate(MAX_JOBS);
+    Some((job, true))
+}
+
+/// Changes a job in place.
+pub fn update<F: FnOnce(&mut Job)>(id: u64, change: F) {
+    let jobs = Arc::clone(&JOBS);
+    let jobs = jobs.write();
+    if let Ok(mut jobs) = jobs {
+        if let Some(job) = jobs.iter_mut().find(|job| job.id == id) {
+            change(job);
+        }
+    }
+}
+
+/// Logs an error and keeps it with the job.
+pub fn error(id: u64, message: String) {
+    tracing::error!(job = id, "{}", message);
+    update(id, |job| {
+        if job.errors.len() < MAX_ERRORS {
+            job.errors.push(message);
+        }
+    });
+}
+
+pub fn finish(id: u64, result: anyhow::Result<()>) {
+    let state = match result {
+        Ok(_) => State::Succeeded,
+        Err(err) => {
+            error(id, format!("Error updating backgrounds: {:#}", err));
+            State::Failed
+        }
+    };
+    update(id, |job| {
+        job.state = state;
+        job.finished_at = Some(Utc::now());
+    });
+    if let Some(job) = get(id) {
+        crate::webhook::emit(crate::webhook::Event::PhotosSynced, &job);
+    }
+}
+
+/// Every job still kept, newest first.
+pub fn all() -> Vec<Job> {
+    let jobs = Arc::clone(&JOBS);
+    let jobs = jobs.read();
+    match jobs {
+        Ok(jobs) => jobs.iter().cloned().collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+pub fn get(id: u64) -> Option<Job> {
+    all().into_iter().find(|job| job.id == id)
+}
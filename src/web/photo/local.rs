@@ -0,0 +1,111 @@
+use super::source::{BoxFuture, PhotoSource, RemotePhoto};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+// this file covers taking photos from a local directory, like a NAS mount
+
+/// File extensions the `image` crate can open.
+const EXTENSIONS: &[&str] = &["jpg", "jpeg", "png", "gif", "bmp", "tif", "tiff", "webp"];
+
+/// # Local Directory
+/// Every image in `PHOTO_LOCAL_DIR` and the directories under it.
+pub struct LocalDirectory {
+    root: PathBuf,
+}
+
+impl LocalDirectory {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    fn images(&self) -> Vec<PathBuf> {
+        let pattern = format!("{}/**/*", self.root.to_string_lossy());
+        match glob::glob(&pattern) {
+            Ok(paths) => paths
+                .flatten()
+                .filter(|path| path.is_file() && is_image(path))
+                .collect(),
+            Err(err) => {
+                tracing::error!("Bad PHOTO_LOCAL_DIR: {}", err);
+                Vec::new()
+            }
+        }
+    }
+}
+
+impl PhotoSource for LocalDirectory {
+    fn name(&self) -> &'static str {
+        "local"
+    }
+
+    fn list(&self) -> BoxFuture<'_, anyhow::Result<Vec<RemotePhoto>>> {
+        Box::pin(async move {
+            if !self.root.is_dir() {
+                anyhow::bail!("{:?} is not a directory", self.root);
+            }
+            let photos = self
+                .images()
+                .into_iter()
+                .map(|path| {
+                    let relative = path.strip_prefix(&self.root).unwrap_or(&path);
+                    RemotePhoto {
+                        guid: relative.to_string_lossy().to_string(),
+                        location: path.to_string_lossy().to_string(),
+                        caption: None,
+                        taken_at: None,
+                    }
+                })
+                .collect();
+            Ok(photos)
+        })
+    }
+
+    fn fetch<'a>(&'a self, photo: &'a RemotePhoto) -> BoxFuture<'a, anyhow::Result<Vec<u8>>> {
+        Box::pin(async move { Ok(std::fs::read(&photo.location)?) })
+    }
+}
+
+fn is_image(path: &Path) -> bool {
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some(extension) => EXTENSIONS.contains(&extension.to_lowercase().as_str()),
+        None => false,
+    }
+}
+
+/// # Watch
+/// Checks the directory every `interval` seconds (`PHOTO_LOCAL_POLL_SECONDS`) and
+/// starts a refresh of the backgrounds when a file is added, changed or
+/// removed. Polling works on network mounts, where change notifications
+/// usually do not.
+pub fn watch(root: PathBuf, interval: u64) {
+    tracing::info!("Watching {:?} for photos", root);
+    thread::spawn(move || {
+        let directory = LocalDirectory::new(root);
+        let mut last = fingerprint(&directory);
+        loop {
+            thread::sleep(Duration::from_secs(interval));
+            let current = fingerprint(&directory);
+            if current != last {
+                tracing::info!("Local photos changed");
+                super::start_fetching_backgrounds(super::Trigger::Watcher);
+                last = current;
+            }
+        }
+    });
+}
+
+/// The name, size and modification time of every image, so any change to
+/// the directory changes the fingerprint.
+fn fingerprint(directory: &LocalDirectory) -> Vec<String> {
+    directory
+        .images()
+        .into_iter()
+        .map(|path| {
+            let metadata = std::fs::metadata(&path).ok();
+            let size = metadata.as_ref().map(|metadata| metadata.len());
+            let modified = metadata.and_then(|metadata| metadata.modified().ok());
+            format!("{:?}:{:?}:{:?}", path, size, modified)
+        })
+        .collect()
+}
@@ -0,0 +1,93 @@
+use super::source::{BoxFuture, PhotoSource, RemotePhoto};
+use chrono::{DateTime, Utc};
+use reqwest::Url;
+use serde::Deserialize;
+
+// this file covers fetching photos listed in a JSON manifest
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Manifest {
+    Wrapped { photos: Vec<ManifestPhoto> },
+    Bare(Vec<ManifestPhoto>),
+}
+
+#[derive(Deserialize)]
+struct ManifestPhoto {
+    /// Defaults to the URL when missing.
+    id: Option<String>,
+    url: String,
+    caption: Option<String>,
+    taken_at: Option<DateTime<Utc>>,
+}
+
+/// # Manifest Source
+/// A JSON document at `PHOTO_MANIFEST_URL` listing photos, either as
+/// `{"photos": [...]}` or a bare array of `{"id": "...", "url": "..."}`, with
+/// optional `caption` and `taken_at`.
+/// Relative URLs are resolved against the manifest's URL.
+pub struct ManifestSource {
+    url: String,
+}
+
+impl ManifestSource {
+    pub fn new(url: String) -> Self {
+        Self { url }
+    }
+
+    async fn list_photos(&self) -> anyhow::Result<Vec<RemotePhoto>> {
+        let base = Url::parse(&self.url)?;
+        let data = crate::REQWEST
+            .get(base.clone())
+            .header("User-Agent", "github.com/ryanknu/therm_hub")
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        let photos = match serde_json::from_str(&data)? {
+            Manifest::Wrapped { photos } => photos,
+            Manifest::Bare(photos) => photos,
+        };
+        let mut remote_photos = Vec::new();
+        for photo in photos {
+            let location = match base.join(&photo.url) {
+                Ok(location) => location.to_string(),
+                Err(err) => {
+                    tracing::error!("Bad URL {} in photo manifest: {}", photo.url, err);
+                    continue;
+                }
+            };
+            remote_photos.push(RemotePhoto {
+                guid: photo.id.unwrap_or(photo.url),
+                location,
+                caption: photo.caption,
+                taken_at: photo.taken_at,
+            });
+        }
+        Ok(remote_photos)
+    }
+}
+
+impl PhotoSource for ManifestSource {
+    fn name(&self) -> &'static str {
+        "manifest"
+    }
+
+    fn list(&self) -> BoxFuture<'_, anyhow::Result<Vec<RemotePhoto>>> {
+        Box::pin(self.list_photos())
+    }
+
+    fn fetch<'a>(&'a self, photo: &'a RemotePhoto) -> BoxFuture<'a, anyhow::Result<Vec<u8>>> {
+        Box::pin(async move {
+            let bytes = crate::REQWEST
+                .get(&photo.location)
+                .send()
+                .await?
+                .error_for_status()?
+                .bytes()
+                .await?;
+            Ok(bytes.to_vec())
+        })
+    }
+}
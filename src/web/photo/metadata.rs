@@ -0,0 +1,127 @@
+use chrono::{DateTime, FixedOffset, Local, NaiveDate, TimeZone, Utc};
+use image::DynamicImage;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Cursor, Seek};
+use std::path::Path;
+
+// this file covers reading EXIF data from photos
+
+/// # Metadata
+/// What a photo's EXIF data says about it.
+#[derive(Debug, Default)]
+pub struct Metadata {
+    pub taken_at: Option<DateTime<Utc>>,
+    /// EXIF orientation, 1 through 8. 1 means the pixels are already upright.
+    pub orientation: u16,
+    pub caption: Option<String>,
+}
+
+impl Metadata {
+    /// Whether the photo is on its side, so width and height trade places
+    /// once it is turned upright.
+    pub fn is_sideways(&self) -> bool {
+        (5..=8).contains(&self.orientation)
+    }
+}
+
+pub fn from_bytes(bytes: &[u8]) -> Metadata {
+    read(&mut Cursor::new(bytes))
+}
+
+pub fn from_file(path: &Path) -> Metadata {
+    match File::open(path) {
+        Ok(file) => read(&mut BufReader::new(file)),
+        Err(_) => Metadata::default(),
+    }
+}
+
+fn read<R: BufRead + Seek>(reader: &mut R) -> Metadata {
+    let exif = match exif::Reader::new().read_from_container(reader) {
+        Ok(exif) => exif,
+        Err(_) => {
+            return Metadata {
+                orientation: 1,
+                ..Metadata::default()
+            }
+        }
+    };
+    let orientation = exif
+        .get_field(exif::Tag::Orientation, exif::In::PRIMARY)
+        .and_then(|field| field.value.get_uint(0))
+        .filter(|orientation| (1..=8).contains(orientation))
+        .unwrap_or(1) as u16;
+    let caption = exif
+        .get_field(exif::Tag::ImageDescription, exif::In::PRIMARY)
+        .and_then(|field| ascii(&field.value))
+        .map(|caption| caption.trim().to_string())
+        .filter(|caption| !caption.is_empty());
+    Metadata {
+        taken_at: taken_at(&exif),
+        orientation,
+        caption,
+    }
+}
+
+fn ascii(value: &exif::Value) -> Option<String> {
+    match value {
+        exif::Value::Ascii(values) => values
+            .first()
+            .map(|value| String::from_utf8_lossy(value).to_string()),
+        _ => None,
+    }
+}
+
+/// # Taken At
+/// When a photo was taken. Times without an offset are taken to be in the
+/// server's time zone.
+fn taken_at(exif: &exif::Exif) -> Option<DateTime<Utc>> {
+    let field = exif
+        .get_field(exif::Tag::DateTimeOriginal, exif::In::PRIMARY)
+        .or_else(|| exif.get_field(exif::Tag::DateTime, exif::In::PRIMARY))?;
+    let mut date_time = match &field.value {
+        exif::Value::Ascii(values) => exif::DateTime::from_ascii(values.first()?).ok()?,
+        _ => return None,
+    };
+    if let Some(offset) = exif.get_field(exif::Tag::OffsetTimeOriginal, exif::In::PRIMARY) {
+        if let exif::Value::Ascii(values) = &offset.value {
+            if let Some(value) = values.first() {
+                let _ = date_time.parse_offset(value);
+            }
+        }
+    }
+    let naive = NaiveDate::from_ymd_opt(
+        i32::from(date_time.year),
+        u32::from(date_time.month),
+        u32::from(date_time.day),
+    )?
+    .and_hms_opt(
+        u32::from(date_time.hour),
+        u32::from(date_time.minute),
+        u32::from(date_time.second),
+    )?;
+    match date_time.offset {
+        Some(offset) => FixedOffset::east_opt(i32::from(offset) * 60)?
+            .from_local_datetime(&naive)
+            .single()
+            .map(|time| time.with_timezone(&Utc)),
+        None => Local
+            .from_local_datetime(&naive)
+            .earliest()
+            .map(|time| time.with_timezone(&Utc)),
+    }
+}
+
+/// # Orient
+/// Turns an image upright according to its EXIF orientation.
+pub fn orient(image: DynamicImage, orientation: u16) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
@@ -0,0 +1,384 @@
+use image::GenericImageView;
+use record::NewPhoto;
+pub use job::{Job, Trigger};
+pub use playlist::{NewPlaylist, Playlist};
+pub use record::PhotoRecord;
+use sha2::{Digest, Sha256};
+use source::{file_id, PhotoSource, RemotePhoto};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+#[cfg(not(any(test, feature = "offline")))]
+mod icloud;
+pub mod job;
+mod local;
+#[cfg(not(any(test, feature = "offline")))]
+mod manifest;
+pub mod metadata;
+mod playlist;
+mod record;
+mod source;
+
+/// # Sources
+/// Every photo source that is configured. Sources that need the Internet are
+/// left out in offline mode.
+fn sources() -> Vec<Box<dyn PhotoSource>> {
+    let mut sources: Vec<Box<dyn PhotoSource>> = Vec::new();
+    let config = crate::config::get();
+    let photos = match &config.photos {
+        Some(photos) => photos,
+        None => return sources,
+    };
+    #[cfg(not(any(test, feature = "offline")))]
+    {
+        if let Some(album_id) = &photos.shared_album_id {
+            let host = photos.shared_album_host.clone();
+            sources.push(Box::new(icloud::SharedAlbum::new(album_id.clone(), host)));
+        }
+        if let Some(url) = &photos.manifest_url {
+            sources.push(Box::new(manifest::ManifestSource::new(url.clone())));
+        }
+    }
+    if let Some(root) = &photos.local_dir {
+        sources.push(Box::new(local::LocalDirectory::new(PathBuf::from(root))));
+    }
+    sources
+}
+
+/// `PHOTO_CACHE_DIR`, or `None` when photos are not configured.
+fn cache_dir() -> Option<PathBuf> {
+    crate::config::get().photos.as_ref().map(|photos| PathBuf::from(&photos.cache_dir))
+}
+
+fn file_path(cache_dir: &Path, file_id: &str) -> PathBuf {
+    cache_dir.join(format!("{}.jpg", file_id))
+}
+
+/// # Checksum
+/// SHA-256 of a photo, in hex.
+pub fn checksum(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// # Store
+/// Downloads a photo and records it in the `photos` table. A photo with the
+/// same content as one already stored shares its file.
+async fn store(
+    db: &crate::db::DbConnection,
+    cache_dir: &Path,
+    source: &dyn PhotoSource,
+    photo: &RemotePhoto,
+) -> anyhow::Result<()> {
+    let bytes = source.fetch(photo).await?;
+    let checksum = checksum(&bytes);
+    let image = image::load_from_memory(&bytes)?;
+    let mut metadata = metadata::from_bytes(&bytes);
+
+    let file_id = match PhotoRecord::by_checksum(db, &checksum)? {
+        Some(existing) if file_path(cache_dir, &existing.file_id).is_file() => {
+            tracing::info!(
+                "{} {} is a duplicate of {}",
+                source.name(),
+                photo.guid,
+                existing.file_id
+            );
+            existing.file_id
+        }
+        _ => {
+            let file_id = file_id(source.name(), &photo.guid);
+            let path = file_path(cache_dir, &file_id);
+            // The original is kept as is; renditions for each display are
+            // made from it when asked for. Anything that is not a JPEG is
+            // converted, which loses its EXIF data, so it is turned upright
+            // first.
+            if image::guess_format(&bytes)? == image::ImageFormat::Jpeg {
+                std::fs::write(&path, &bytes)?;
+            } else {
+                metadata::orient(image.clone(), metadata.orientation)
+                    .save_with_format(&path, image::ImageFormat::Jpeg)?;
+                metadata.orientation = 1;
+            }
+            tracing::info!("Downloaded image to {}", file_id);
+            file_id
+        }
+    };
+
+    let (width, height) = (image.width() as i32, image.height() as i32);
+    let (width, height) = if metadata.is_sideways() {
+        (height, width)
+    } else {
+        (width, height)
+    };
+    let new_photo = NewPhoto {
+        source: source.name().to_string(),
+        guid: photo.guid.clone(),
+        file_id,
+        checksum,
+        taken_at: metadata
+            .taken_at
+            .or(photo.taken_at)
+            .map(|taken_at| taken_at.naive_utc()),
+        orientation: metadata.orientation as i16,
+        caption: photo.caption.clone().or(metadata.caption),
+        width,
+        height,
+    };
+    PhotoRecord::insert(db, &new_photo)?;
+    Ok(())
+}
+
+/// # Remove
+/// Forgets a photo, and deletes its file unless a duplicate still uses it.
+fn remove(db: &crate::db::DbConnection, cache_dir: &Path, record: &PhotoRecord) -> anyhow::Result<()> {
+    if !record.delete(db)? {
+        remove_file(cache_dir, &record.file_id);
+    }
+    Ok(())
+}
+
+fn remove_file(cache_dir: &Path, file_id: &str) {
+    match std::fs::remove_file(file_path(cache_dir, file_id)) {
+        Ok(_) => tracing::info!("Removed {}", file_id),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+        Err(err) => tracing::error!("Could not remove {}: {}", file_id, err),
+    }
+    super::rendition::remove_renditions(cache_dir, file_id);
+}
+
+/// # Plan Source
+/// Lists a source and removes the photos that are gone from it. Returns the
+/// photos that need downloading: new ones, and ones whose file went missing.
+async fn plan_source(
+    db: &crate::db::DbConnection,
+    cache_dir: &Path,
+    source: &dyn PhotoSource,
+    job: u64,
+) -> anyhow::Result<Vec<RemotePhoto>> {
+    let photos = source.list().await?;
+    let listed: HashSet<&str> = photos.iter().map(|photo| photo.guid.as_str()).collect();
+    let mut known = HashMap::new();
+    for record in PhotoRecord::for_source(db, source.name())? {
+        if listed.contains(record.guid.as_str()) {
+            known.insert(record.guid.clone(), record);
+        } else {
+            remove(db, cache_dir, &record)?;
+            job::update(job, |job| job.removed += 1);
+        }
+    }
+
+    let mut downloads = Vec::new();
+    for photo in photos {
+        if let Some(record) = known.get(&photo.guid) {
+            if file_path(cache_dir, &record.file_id).is_file() {
+                job::update(job, |job| job.skipped += 1);
+                continue;
+            }
+            // The file went missing, so download it again.
+            remove(db, cache_dir, record)?;
+        }
+        downloads.push(photo);
+    }
+    Ok(downloads)
+}
+
+/// # Remove Untracked
+/// Removes photos from sources that are no longer configured, and files in
+/// `PHOTO_CACHE_DIR` that no photo uses (like ones downloaded before photos
+/// were tracked). Returns how many photos were removed.
+fn remove_untracked(
+    db: &crate::db::DbConnection,
+    cache_dir: &Path,
+    sources: &[Box<dyn PhotoSource>],
+) -> anyhow::Result<usize> {
+    let names: HashSet<&str> = sources.iter().map(|source| source.name()).collect();
+    let mut file_ids = HashSet::new();
+    let mut removed = 0;
+    for record in PhotoRecord::all(db)? {
+        if names.contains(record.source.as_str()) {
+            file_ids.insert(record.file_id);
+        } else {
+            remove(db, cache_dir, &record)?;
+            removed += 1;
+        }
+    }
+    for path in photo_paths() {
+        let file_id = match path.file_stem().and_then(|stem| stem.to_str()) {
+            Some(file_id) => file_id.to_string(),
+            None => continue,
+        };
+        if !file_ids.contains(&file_id) {
+            remove_file(cache_dir, &file_id);
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// # Sync Sources
+/// Makes the photos on disk match what every configured source has. Every
+/// source is listed first, so the job knows how many photos there are to
+/// download. One source failing does not stop the others, but untracked files
+/// are only cleaned up when they all worked.
+#[tokio::main]
+async fn sync_sources(job: u64) -> anyhow::Result<()> {
+    let cache_dir = match cache_dir() {
+        Some(cache_dir) => cache_dir,
+        None => anyhow::bail!("photos are not configured"),
+    };
+    let sources = sources();
+    if sources.is_empty() {
+        // Syncing nothing would delete every photo.
+        anyhow::bail!("no photo sources are configured");
+    }
+    let db = crate::establish_connection();
+    let mut failures = Vec::new();
+    let mut plans = Vec::new();
+    for source in &sources {
+        match plan_source(&db, &cache_dir, source.as_ref(), job).await {
+            Ok(downloads) => plans.push((source.as_ref(), downloads)),
+            Err(err) => {
+                job::error(
+                    job,
+                    format!("Could not sync {} photos: {:#}", source.name(), err),
+                );
+                failures.push(source.name());
+            }
+        }
+    }
+    let total = plans.iter().map(|(_, downloads)| downloads.len()).sum();
+    job::update(job, |job| job.total = total);
+
+    for (source, downloads) in plans {
+        for photo in &downloads {
+            match store(&db, &cache_dir, source, photo).await {
+                Ok(_) => job::update(job, |job| job.downloaded += 1),
+                Err(err) => {
+                    job::update(job, |job| job.failed += 1);
+                    job::error(
+                        job,
+                        format!(
+                            "Could not store {} {}: {:#}",
+                            source.name(),
+                            photo.guid,
+                            err
+                        ),
+                    );
+                }
+            }
+        }
+    }
+    if !failures.is_empty() {
+        anyhow::bail!("could not sync photos from {}", failures.join(", "));
+    }
+    let removed = remove_untracked(&db, &cache_dir, &sources)?;
+    job::update(job, |job| job.removed += removed);
+    Ok(())
+}
+
+/// # Records
+/// Every photo in the `photos` table, by file id. Where duplicates share a
+/// file, the first one wins.
+pub fn records() -> HashMap<String, PhotoRecord> {
+    let db = match crate::db::connection() {
+        Ok(db) => db,
+        Err(err) => {
+            tracing::error!("Could not read photos: {:?}", err);
+            return HashMap::new();
+        }
+    };
+    match PhotoRecord::all(&db) {
+        Ok(records) => {
+            let mut by_file_id = HashMap::new();
+            for record in records {
+                by_file_id.entry(record.file_id.clone()).or_insert(record);
+            }
+            by_file_id
+        }
+        Err(err) => {
+            tracing::error!("Could not read photos: {:?}", err);
+            HashMap::new()
+        }
+    }
+}
+
+/// # Photo Paths
+/// Every photo in `PHOTO_CACHE_DIR`.
+pub fn photo_paths() -> Vec<PathBuf> {
+    let cache_dir = match cache_dir() {
+        Some(cache_dir) => cache_dir,
+        None => return Vec::new(),
+    };
+    match glob::glob(&format!("{}/*.jpg", cache_dir.display())) {
+        Ok(paths) => paths.flatten().collect(),
+        Err(err) => {
+            tracing::error!("Failed to read glob pattern: {}", err);
+            Vec::new()
+        }
+    }
+}
+
+/// # Start Fetching Backgrounds
+/// Creates a thread that will populate the backgrounds directory in the
+/// background, tracked as a job. Only one refresh runs at a time: when one is
+/// already running, that job is returned instead, with `false`.
+pub fn start_fetching_backgrounds(trigger: Trigger) -> Option<(Job, bool)> {
+    let (job, started) = job::begin(trigger)?;
+    if !started {
+        tracing::info!("Already fetching photos; stopped");
+        return Some((job, false));
+    }
+    let id = job.id;
+    thread::spawn(move || {
+        let _span = tracing::info_span!("photo_sync", job = id).entered();
+        tracing::info!("Starting update of backgrounds...");
+        let result = sync_sources(id);
+        if result.is_ok() {
+            tracing::info!("Completed update of backgrounds");
+        }
+        job::finish(id, result);
+    });
+    Some((job, true))
+}
+
+/// # Sync Now
+/// Refreshes the backgrounds on this thread, for the command line. Returns
+/// the finished job.
+pub fn sync_now() -> Option<Job> {
+    let (job, _) = job::begin(Trigger::Cli)?;
+    let result = sync_sources(job.id);
+    job::finish(job.id, result);
+    job::get(job.id)
+}
+
+/// # Start Scheduling
+/// Refreshes the backgrounds at startup and then every
+/// `PHOTO_REFRESH_MINUTES` (default 60). `0` turns scheduled refreshes off.
+pub fn start_scheduling() {
+    let minutes = match &crate::config::get().photos {
+        Some(photos) => photos.refresh_minutes,
+        None => return,
+    };
+    if minutes == 0 || sources().is_empty() {
+        return;
+    }
+    tracing::info!("Refreshing photos every {} minutes", minutes);
+    thread::spawn(move || loop {
+        start_fetching_backgrounds(Trigger::Schedule);
+        thread::sleep(Duration::from_secs(minutes * 60));
+    });
+}
+
+/// # Start Watching
+/// Watches the local photo directory, if there is one, for changes.
+pub fn start_watching() {
+    if let Some(photos) = &crate::config::get().photos {
+        if let Some(root) = &photos.local_dir {
+            local::watch(PathBuf::from(root), photos.local_poll_seconds);
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use crate::db::DbConnection;
+use crate::schema::playlists;
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use serde::Serialize;
+
+// this file covers the `playlists` table, which holds each display's slideshow
+// settings
+
+// Rows are loaded whole, though not every column is read yet.
+#[allow(dead_code)]
+#[derive(Clone, Debug, Queryable, Serialize)]
+pub struct Playlist {
+    #[serde(skip)]
+    pub id: i32,
+    pub display: String,
+    pub interval_seconds: i32,
+    pub shuffle: bool,
+    pub rule: String,
+    #[serde(skip)]
+    pub updated: NaiveDateTime,
+}
+
+#[derive(AsChangeset, Insertable)]
+#[table_name = "playlists"]
+pub struct NewPlaylist {
+    pub display: String,
+    pub interval_seconds: i32,
+    pub shuffle: bool,
+    pub rule: String,
+    pub updated: NaiveDateTime,
+}
+
+impl Playlist {
+    pub fn all(connection: &DbConnection) -> QueryResult<Vec<Self>> {
+        let select = playlists::table.order(playlists::display);
+        crate::db::log_query(&select);
+        select.load(connection)
+    }
+
+    pub fn for_display(connection: &DbConnection, display: &str) -> QueryResult<Option<Self>> {
+        let select = playlists::table
+            .filter(playlists::display.eq(display))
+            .limit(1);
+        crate::db::log_query(&select);
+        Ok(select.load(connection)?.into_iter().next())
+    }
+
+    /// Creates the display's playlist, or replaces its settings. Written as an
+    /// update then an insert so it works on SQLite as well as Postgres.
+    pub fn save(connection: &DbConnection, playlist: &NewPlaylist) -> QueryResult<Self> {
+        connection.transaction(|| {
+            let update =
+                diesel::update(playlists::table.filter(playlists::display.eq(&playlist.display)))
+                    .set(playlist);
+            crate::db::log_query(&update);
+            if update.execute(connection)? == 0 {
+                let insert = diesel::insert_into(playlists::table).values(playlist);
+                crate::db::log_query(&insert);
+                insert.execute(connection)?;
+            }
+            Self::for_display(connection, &playlist.display)?.ok_or(diesel::NotFound)
+        })
+    }
+
+    /// Returns whether there was a playlist to delete.
+    pub fn delete(connection: &DbConnection, display: &str) -> QueryResult<bool> {
+        let delete = diesel::delete(playlists::table.filter(playlists::display.eq(display)));
+        crate::db::log_query(&delete);
+        Ok(delete.execute(connection)? > 0)
+    }
+}
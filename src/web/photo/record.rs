@@ -0,0 +1,89 @@
+use crate::db::DbConnection;
+use crate::schema::photos;
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use diesel::prelude::*;
+
+// this file covers the `photos` table, which tracks every downloaded photo
+
+// Rows are loaded whole, though not every column is read yet.
+#[allow(dead_code)]
+#[derive(Clone, Debug, Queryable)]
+pub struct PhotoRecord {
+    pub id: i32,
+    pub source: String,
+    pub guid: String,
+    /// The name of the file in `PHOTO_CACHE_DIR`, without `.jpg`. Photos with
+    /// the same checksum share one file.
+    pub file_id: String,
+    pub checksum: String,
+    pub taken_at: Option<NaiveDateTime>,
+    pub orientation: i16,
+    pub caption: Option<String>,
+    pub width: i32,
+    pub height: i32,
+    pub added: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "photos"]
+pub struct NewPhoto {
+    pub source: String,
+    pub guid: String,
+    pub file_id: String,
+    pub checksum: String,
+    pub taken_at: Option<NaiveDateTime>,
+    pub orientation: i16,
+    pub caption: Option<String>,
+    pub width: i32,
+    pub height: i32,
+}
+
+impl PhotoRecord {
+    pub fn taken_at(&self) -> Option<DateTime<Utc>> {
+        self.taken_at
+            .map(|taken_at| Utc.from_utc_datetime(&taken_at))
+    }
+
+    /// Every photo, oldest first.
+    pub fn all(connection: &DbConnection) -> QueryResult<Vec<Self>> {
+        let select = photos::table.order(photos::id);
+        crate::db::log_query(&select);
+        select.load(connection)
+    }
+
+    pub fn for_source(connection: &DbConnection, source: &str) -> QueryResult<Vec<Self>> {
+        let select = photos::table
+            .filter(photos::source.eq(source))
+            .order(photos::id);
+        crate::db::log_query(&select);
+        select.load(connection)
+    }
+
+    /// A photo with the same content, from any source.
+    pub fn by_checksum(connection: &DbConnection, checksum: &str) -> QueryResult<Option<Self>> {
+        let select = photos::table
+            .filter(photos::checksum.eq(checksum))
+            .order(photos::id)
+            .limit(1);
+        crate::db::log_query(&select);
+        Ok(select.load(connection)?.into_iter().next())
+    }
+
+    pub fn insert(connection: &DbConnection, photo: &NewPhoto) -> QueryResult<usize> {
+        let insert = diesel::insert_into(photos::table).values(photo);
+        crate::db::log_query(&insert);
+        insert.execute(connection)
+    }
+
+    /// Deletes the row. Returns whether any other row still uses its file.
+    pub fn delete(&self, connection: &DbConnection) -> QueryResult<bool> {
+        let delete = diesel::delete(photos::table.filter(photos::id.eq(self.id)));
+        crate::db::log_query(&delete);
+        delete.execute(connection)?;
+        let select = photos::table
+            .filter(photos::file_id.eq(&self.file_id))
+            .count();
+        crate::db::log_query(&select);
+        Ok(select.get_result::<i64>(connection)? > 0)
+    }
+}
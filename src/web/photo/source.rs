@@ -0,0 +1,59 @@
+use chrono::{DateTime, Utc};
+use std::future::Future;
+use std::pin::Pin;
+
+// this file covers the trait every place photos come from implements
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// # Remote Photo
+/// A photo a source has, before it is downloaded.
+#[derive(Clone, Debug)]
+pub struct RemotePhoto {
+    /// Identifies the photo within its source. It does not need to be safe
+    /// to use as a file name; see `file_id`.
+    pub guid: String,
+    /// Where the source fetches it from: a URL or a path.
+    pub location: String,
+    pub caption: Option<String>,
+    /// When the source says the photo was taken. The photo's EXIF data wins
+    /// over this.
+    pub taken_at: Option<DateTime<Utc>>,
+}
+
+/// # Photo Source
+/// Somewhere background photos come from. Sources list what they have, and
+/// the sync downloads whatever is new and removes whatever is gone.
+pub trait PhotoSource: Send + Sync {
+    /// A short name for logs.
+    fn name(&self) -> &'static str;
+
+    /// Lists every photo the source has right now.
+    fn list(&self) -> BoxFuture<'_, anyhow::Result<Vec<RemotePhoto>>>;
+
+    /// Returns the bytes of one photo from `list`.
+    fn fetch<'a>(&'a self, photo: &'a RemotePhoto) -> BoxFuture<'a, anyhow::Result<Vec<u8>>>;
+}
+
+/// # File ID
+/// Turns a guid into an id that is safe to use as a file name, prefixed with
+/// the source so two sources cannot clash.
+pub fn file_id(source: &str, guid: &str) -> String {
+    let id: String = guid
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if id == guid {
+        format!("{}-{}", source, id)
+    } else {
+        // Different guids can clean up to the same id, so tell them apart.
+        let hash = super::checksum(guid.as_bytes());
+        format!("{}-{}-{}", source, id, &hash[..8])
+    }
+}
@@ -0,0 +1,109 @@
+use super::cache::json_response;
+use super::{
+    bad_request, internal_server_error, method_not_allowed, query_parameters, status_only,
+};
+use crate::export::Units;
+use crate::ingest::{ingest, parse_json, parse_line_protocol, validate, Precision};
+use hyper::body::HttpBody;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::Deserialize;
+
+// this file covers `POST /readings`, where other devices push their readings
+
+/// The largest body taken, which is plenty for `MAX_READINGS` readings.
+const MAX_BODY_BYTES: usize = 256 * 1024;
+
+#[derive(Deserialize)]
+struct ReadingsQuery {
+    units: Option<Units>,
+    precision: Option<Precision>,
+}
+
+/// # Readings
+/// `POST /readings` saves one reading or a batch. JSON (`application/json`)
+/// takes a `ReadingInput` or an array of them; InfluxDB line protocol
+/// (`text/plain`) takes one reading per line. Takes `units` (`f` or `c`) for
+/// the temperatures and `precision` (`ns`, `us`, `ms` or `s`) for line
+/// protocol timestamps. The whole request is rejected if any reading is not
+/// valid. Answers 201 Created with how many readings were saved, or 413
+/// Payload Too Large for bodies over `MAX_BODY_BYTES`.
+pub async fn readings(mut req: Request<Body>) -> Response<Body> {
+    if !Method::POST.eq(req.method()) {
+        return method_not_allowed();
+    }
+    let query: ReadingsQuery = match query_parameters(&req) {
+        Some(query) => query,
+        None => return bad_request(),
+    };
+    let content_type = req
+        .headers()
+        .get("Content-Type")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(|value| value.trim().to_lowercase())
+        .unwrap_or_else(|| String::from("application/json"));
+    let content_length = req
+        .headers()
+        .get("Content-Length")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+    if let Some(length) = content_length {
+        if length > MAX_BODY_BYTES {
+            return status_only(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+    }
+    let body = match read_body(req.body_mut()).await {
+        Ok(Some(body)) => body,
+        Ok(None) => return status_only(StatusCode::PAYLOAD_TOO_LARGE),
+        Err(_) => return bad_request(),
+    };
+    let parsed = match content_type.as_str() {
+        "application/json" => parse_json(&body),
+        "text/plain" => match std::str::from_utf8(&body) {
+            Ok(body) => parse_line_protocol(body, query.precision.unwrap_or_default()),
+            Err(_) => Err(String::from("the body is not UTF-8")),
+        },
+        _ => return status_only(StatusCode::UNSUPPORTED_MEDIA_TYPE),
+    };
+    let readings =
+        match parsed.and_then(|readings| validate(readings, query.units.unwrap_or_default())) {
+            Ok(readings) => readings,
+            Err(reason) => {
+                tracing::warn!("Readings are not valid: {}", reason);
+                return bad_request();
+            }
+        };
+
+    let count = readings.len();
+    let result = crate::db::run(move |connection| ingest(connection, &readings)).await;
+    match result {
+        Ok(Ok(())) => {
+            tracing::info!("Saved {} pushed readings", count);
+            let body = serde_json::json!({ "inserted": count }).to_string();
+            let mut response = json_response(&req, body);
+            *response.status_mut() = StatusCode::CREATED;
+            response
+        }
+        Ok(Err(err)) => {
+            tracing::error!("Could not save pushed readings: {:#}", err);
+            internal_server_error()
+        }
+        Err(_) => internal_server_error(),
+    }
+}
+
+/// # Read Body
+/// Reads the body, stopping as soon as it goes over `MAX_BODY_BYTES` so a
+/// body without a `Content-Length` cannot be held in memory either. `None`
+/// when it is too large.
+async fn read_body(body: &mut Body) -> Result<Option<Vec<u8>>, hyper::Error> {
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if bytes.len() + chunk.len() > MAX_BODY_BYTES {
+            return Ok(None);
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(Some(bytes))
+}
@@ -0,0 +1,353 @@
+use super::dither::{quantize, Dither, Palette};
+use super::photo::metadata;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageOutputFormat};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+
+// this file covers resizing background photos for the display asking for them
+
+/// The largest width or height a rendition may be asked for.
+const MAX_DIMENSION: u32 = 4096;
+/// How much disk the renditions may take up together. The ones used least
+/// recently are deleted past this, so walking through sizes cannot fill the
+/// disk.
+const MAX_CACHE_BYTES: u64 = 512 * 1024 * 1024;
+const JPEG_QUALITY: u8 = 85;
+const WEBP_QUALITY: f32 = 80.0;
+
+/// Numbers temporary files, so two requests making the same rendition do not
+/// write to the same file.
+static TEMPORARY_FILES: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Fit {
+    /// Scale to cover the whole box, cropping whatever hangs over.
+    #[default]
+    Cover,
+    /// Scale to fit inside the box, keeping the aspect ratio.
+    Contain,
+    /// Stretch to exactly the box.
+    Fill,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    #[serde(alias = "jpg")]
+    Jpeg,
+    Webp,
+    Png,
+    /// Palette indices packed into a bitmap, for e-ink panels. Needs a
+    /// `palette`.
+    Raw,
+}
+
+impl Format {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Jpeg => "image/jpeg",
+            Format::Webp => "image/webp",
+            Format::Png => "image/png",
+            Format::Raw => "application/octet-stream",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Jpeg => "jpg",
+            Format::Webp => "webp",
+            Format::Png => "png",
+            Format::Raw => "bin",
+        }
+    }
+}
+
+/// # Rendition
+/// How a display wants a photo: its size, how to fit the photo into that
+/// size, which colors it can show and what format to encode it in. Read from
+/// the query string.
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+pub struct Rendition {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    #[serde(default)]
+    pub fit: Fit,
+    pub format: Option<Format>,
+    pub palette: Option<Palette>,
+    #[serde(default)]
+    pub dither: Dither,
+}
+
+impl Rendition {
+    /// The rendition the multipart bundle has always sent: an 800x480 crop.
+    pub const LEGACY: Rendition = Rendition {
+        width: Some(800),
+        height: Some(480),
+        fit: Fit::Cover,
+        format: Some(Format::Jpeg),
+        palette: None,
+        dither: Dither::FloydSteinberg,
+    };
+
+    /// # Is Original
+    /// Nothing was asked for, so the original file can be sent as is.
+    pub fn is_original(&self) -> bool {
+        self.width.is_none()
+            && self.height.is_none()
+            && self.format.is_none()
+            && self.palette.is_none()
+    }
+
+    pub fn is_valid(&self) -> bool {
+        let in_range = |dimension: Option<u32>| match dimension {
+            None => true,
+            Some(dimension) => dimension > 0 && dimension <= MAX_DIMENSION,
+        };
+        let packable = self.format() != Format::Raw || self.palette.is_some();
+        in_range(self.width) && in_range(self.height) && packable
+    }
+
+    pub fn format(&self) -> Format {
+        self.format.unwrap_or_default()
+    }
+
+    /// A short name that tells renditions apart, used for cache files and
+    /// ETags.
+    pub fn key(&self) -> String {
+        let dimension = |dimension: Option<u32>| match dimension {
+            Some(dimension) => dimension.to_string(),
+            None => String::from("auto"),
+        };
+        let mut key = format!(
+            "{}x{}-{:?}",
+            dimension(self.width),
+            dimension(self.height),
+            self.fit
+        );
+        if let Some(palette) = self.palette {
+            key.push_str(&format!("-{:?}-{:?}", palette, self.dither));
+        }
+        key.to_lowercase()
+    }
+
+    /// # Cache Path
+    /// Where the rendition of photo `id` is kept once it has been made.
+    pub fn cache_path(&self, cache_dir: &Path, id: &str) -> PathBuf {
+        cache_dir.join("renditions").join(format!(
+            "{}-{}.{}",
+            id,
+            self.key(),
+            self.format().extension()
+        ))
+    }
+
+    /// # Render
+    /// Resizes, reduces to the palette (if any) and encodes an image.
+    pub fn render(&self, image: &DynamicImage) -> anyhow::Result<Vec<u8>> {
+        let mut resized = self.resize(image);
+        if let Some(palette) = self.palette {
+            let indexed = quantize(&resized, palette, self.dither);
+            if self.format() == Format::Raw {
+                return Ok(indexed.pack());
+            }
+            resized = indexed.to_image();
+        }
+        let mut out = Vec::new();
+        match self.format() {
+            Format::Jpeg => resized.write_to(&mut out, ImageOutputFormat::Jpeg(JPEG_QUALITY))?,
+            Format::Png => resized.write_to(&mut out, ImageOutputFormat::Png)?,
+            Format::Webp => {
+                let rgba = resized.to_rgba();
+                let encoded = webp::Encoder::from_rgba(&rgba, rgba.width(), rgba.height())
+                    .encode(WEBP_QUALITY);
+                out.extend_from_slice(&encoded);
+            }
+            Format::Raw => anyhow::bail!("raw bitmaps need a palette"),
+        }
+        Ok(out)
+    }
+
+    fn resize(&self, image: &DynamicImage) -> DynamicImage {
+        let (width, height) = match (self.width, self.height) {
+            (None, None) => return image.clone(),
+            // Only one side given: keep the aspect ratio.
+            (Some(width), None) => return image.resize(width, MAX_DIMENSION, FilterType::Lanczos3),
+            (None, Some(height)) => {
+                return image.resize(MAX_DIMENSION, height, FilterType::Lanczos3)
+            }
+            (Some(width), Some(height)) => (width, height),
+        };
+        match self.fit {
+            Fit::Cover => image.resize_to_fill(width, height, FilterType::Lanczos3),
+            Fit::Contain => image.resize(width, height, FilterType::Lanczos3),
+            Fit::Fill => image.resize_exact(width, height, FilterType::Lanczos3),
+        }
+    }
+}
+
+/// # Rendition Path
+/// Returns the cached rendition of an original, making it first if needed.
+/// Renditions are written to a temporary file and renamed into place, so a
+/// half written file is never served. Serving one marks it as recently used.
+pub fn rendition_path(original: &Path, id: &str, rendition: &Rendition) -> anyhow::Result<PathBuf> {
+    let cache_dir = match original.parent() {
+        Some(cache_dir) => cache_dir,
+        None => anyhow::bail!("{:?} is not in a directory", original),
+    };
+    let path = rendition.cache_path(cache_dir, id);
+    if is_fresh(&path, original) {
+        touch(&path);
+        return Ok(path);
+    }
+    // Turn the photo upright before resizing, or a portrait photo would be
+    // cropped as if it were landscape.
+    let orientation = metadata::from_file(original).orientation;
+    let image = metadata::orient(image::open(original)?, orientation);
+    let data = rendition.render(&image)?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let temporary = path.with_extension(format!(
+        "{}.tmp",
+        TEMPORARY_FILES.fetch_add(1, Ordering::Relaxed)
+    ));
+    std::fs::write(&temporary, data)?;
+    std::fs::rename(&temporary, &path)?;
+    tracing::debug!("Made rendition {:?}", path);
+    if let Some(renditions) = path.parent() {
+        evict(renditions, &path);
+    }
+    Ok(path)
+}
+
+/// Marks a rendition as used now. Its modified time doubles as its last use,
+/// which is still after the original changed.
+fn touch(path: &Path) {
+    let touched = std::fs::OpenOptions::new()
+        .append(true)
+        .open(path)
+        .and_then(|file| file.set_modified(SystemTime::now()));
+    if let Err(err) = touched {
+        tracing::debug!("Could not touch rendition {:?}: {}", path, err);
+    }
+}
+
+/// # Evict
+/// Deletes the renditions used least recently until they fit in
+/// `MAX_CACHE_BYTES`, never the one just made.
+fn evict(renditions: &Path, keep: &Path) {
+    let entries = match std::fs::read_dir(renditions) {
+        Ok(entries) => entries,
+        Err(err) => {
+            tracing::error!("Could not list renditions: {}", err);
+            return;
+        }
+    };
+    let mut files: Vec<(SystemTime, u64, PathBuf)> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let metadata = entry.metadata().ok()?;
+            let modified = metadata.modified().ok()?;
+            Some((modified, metadata.len(), entry.path()))
+        })
+        // Leave files still being written alone.
+        .filter(|(_, _, path)| !path.to_string_lossy().ends_with(".tmp"))
+        .collect();
+    let mut total: u64 = files.iter().map(|(_, len, _)| len).sum();
+    if total <= MAX_CACHE_BYTES {
+        return;
+    }
+    files.sort();
+    let mut removed = 0;
+    for (_, len, path) in files {
+        if total <= MAX_CACHE_BYTES {
+            break;
+        }
+        if path == keep {
+            continue;
+        }
+        if std::fs::remove_file(&path).is_ok() {
+            total -= len;
+            removed += 1;
+        }
+    }
+    tracing::info!(
+        "Deleted {} renditions to stay under the cache size",
+        removed
+    );
+}
+
+/// A rendition is fresh if it was made after the original last changed.
+fn is_fresh(path: &Path, original: &Path) -> bool {
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|metadata| metadata.modified());
+    match (modified(path), modified(original)) {
+        (Ok(rendition), Ok(original)) => rendition >= original,
+        _ => false,
+    }
+}
+
+/// # Is Rendition Of
+/// Whether a file in `renditions/` was made from photo `id`: named
+/// `{id}-{key}.{extension}`, where the key starts with the size. Checking
+/// the key keeps photo `a` from matching the renditions of photo `a-b`.
+fn is_rendition_of(file_name: &str, id: &str) -> bool {
+    let key = match file_name
+        .strip_prefix(id)
+        .and_then(|rest| rest.strip_prefix('-'))
+        .and_then(|rest| rest.split('.').next())
+    {
+        Some(key) => key,
+        None => return false,
+    };
+    let parts: Vec<&str> = key.split('-').collect();
+    let is_dimension = |dimension: &str| {
+        dimension == "auto"
+            || (!dimension.is_empty() && dimension.bytes().all(|b| b.is_ascii_digit()))
+    };
+    let is_size = match parts[0].split_once('x') {
+        Some((width, height)) => is_dimension(width) && is_dimension(height),
+        None => false,
+    };
+    is_size && (parts.len() == 2 || parts.len() == 4)
+}
+
+/// # Remove Renditions
+/// Deletes every cached rendition of photo `id`.
+pub fn remove_renditions(cache_dir: &Path, id: &str) {
+    let entries = match std::fs::read_dir(cache_dir.join("renditions")) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        if is_rendition_of(&entry.file_name().to_string_lossy(), id) {
+            let _ = std::fs::remove_file(entry.path());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renditions_match_their_photo_only() {
+        assert!(is_rendition_of("abc-800x480-cover.jpg", "abc"));
+        assert!(is_rendition_of("abc-autox480-contain.webp", "abc"));
+        assert!(is_rendition_of("abc-800x480-cover-gray4-ordered.bin", "abc"));
+        assert!(is_rendition_of("abc-800x480-cover.3.tmp", "abc"));
+        assert!(!is_rendition_of("abc-def-800x480-cover.jpg", "abc"));
+        assert!(!is_rendition_of("abcd-800x480-cover.jpg", "abc"));
+        assert!(!is_rendition_of("abc.jpg", "abc"));
+    }
+
+    #[test]
+    fn keys_are_rendition_names() {
+        let name = format!("abc-{}.jpg", Rendition::LEGACY.key());
+        assert!(is_rendition_of(&name, "abc"));
+    }
+}
@@ -0,0 +1,447 @@
+use super::cache::json_response;
+use super::photo::{photo_paths, records, NewPlaylist, Playlist};
+use super::{bad_request, internal_server_error, method_not_allowed, not_found, query_parameters};
+use crate::db::DbConnection;
+use chrono::{DateTime, Datelike, Local, TimeZone, Utc};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+// this file covers the slideshow (`/photos/current`) and its playlists
+// (`/playlists`)
+
+/// Displays without a playlist of their own use this one's, if it is saved.
+const DEFAULT_DISPLAY: &str = "default";
+/// Seconds each photo is shown when nothing else is saved.
+const DEFAULT_INTERVAL: i32 = 300;
+const MIN_INTERVAL: i32 = 5;
+const MAX_INTERVAL: i32 = 7 * 24 * 60 * 60;
+
+/// # Rule
+/// Which photos a playlist picks from. When no photo matches, every photo is
+/// used, so a display never goes blank.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Rule {
+    /// Every photo.
+    All,
+    /// Photos taken in the same season as today, in any year.
+    Seasonal,
+    /// Photos taken on today's date in previous years.
+    OnThisDay,
+}
+
+impl Rule {
+    fn parse(name: &str) -> Option<Self> {
+        serde_json::from_value(serde_json::Value::String(name.to_string())).ok()
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Rule::All => "all",
+            Rule::Seasonal => "seasonal",
+            Rule::OnThisDay => "on_this_day",
+        }
+    }
+
+    /// Dates are compared in the server's time zone.
+    fn matches(self, taken_at: Option<DateTime<Utc>>, today: &DateTime<Local>) -> bool {
+        let taken_at = match (self, taken_at) {
+            (Rule::All, _) => return true,
+            (_, None) => return false,
+            (_, Some(taken_at)) => taken_at.with_timezone(&Local),
+        };
+        match self {
+            Rule::All => true,
+            Rule::Seasonal => season(taken_at.month()) == season(today.month()),
+            Rule::OnThisDay => {
+                taken_at.month() == today.month()
+                    && taken_at.day() == today.day()
+                    && taken_at.year() < today.year()
+            }
+        }
+    }
+}
+
+/// Meteorological seasons: December to February is 0, March to May is 1 and
+/// so on. Comparing months this way works in either hemisphere.
+fn season(month: u32) -> u32 {
+    (month % 12) / 3
+}
+
+#[derive(Deserialize)]
+struct CurrentInput {
+    display: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PlaylistInput {
+    interval_seconds: Option<i32>,
+    shuffle: Option<bool>,
+    rule: Option<Rule>,
+}
+
+/// A photo the slideshow can show.
+#[derive(Clone)]
+struct Slide {
+    id: String,
+    caption: Option<String>,
+    taken_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+struct CurrentPhoto {
+    display: String,
+    id: String,
+    url: String,
+    caption: Option<String>,
+    taken_at: Option<DateTime<Utc>>,
+    /// The rule the photo was picked by, which is `all` when nothing matched
+    /// the playlist's rule.
+    rule: Rule,
+    position: usize,
+    count: usize,
+    shown_at: DateTime<Utc>,
+    next_at: DateTime<Utc>,
+}
+
+/// What a request for one display's playlist came to.
+enum Outcome {
+    Found(PlaylistInfo),
+    /// The settings were out of range.
+    Invalid,
+    /// Whether there was a playlist to delete.
+    Deleted(bool),
+}
+
+#[derive(Serialize)]
+struct PlaylistInfo {
+    #[serde(flatten)]
+    playlist: Playlist,
+    /// False when the display is using the default settings.
+    saved: bool,
+    /// The photos in the order they are shown this time around.
+    photos: Vec<String>,
+}
+
+/// # Lineup
+/// The photos a playlist shows, in order, and where the slideshow is in them.
+/// Nothing is stored: the position comes from the clock, so every display
+/// sharing a playlist shows the same photo and restarts do not skip ahead.
+/// `saved` is whether the playlist is the display's own; otherwise it is the
+/// default one, and shuffles like it.
+struct Lineup {
+    rule: Rule,
+    slides: Vec<Slide>,
+    position: usize,
+    shown_at: DateTime<Utc>,
+    next_at: DateTime<Utc>,
+}
+
+impl Lineup {
+    fn new(
+        playlist: &Playlist,
+        saved: bool,
+        slides: Vec<Slide>,
+        now: DateTime<Utc>,
+    ) -> Option<Self> {
+        let wanted = Rule::parse(&playlist.rule).unwrap_or(Rule::All);
+        let today = now.with_timezone(&Local);
+        let matching: Vec<Slide> = slides
+            .iter()
+            .filter(|slide| wanted.matches(slide.taken_at, &today))
+            .cloned()
+            .collect();
+        let (rule, mut slides) = if matching.is_empty() {
+            (Rule::All, slides)
+        } else {
+            (wanted, matching)
+        };
+        if slides.is_empty() {
+            return None;
+        }
+
+        let interval = i64::from(playlist.interval_seconds.clamp(MIN_INTERVAL, MAX_INTERVAL));
+        let slot = now.timestamp().div_euclid(interval);
+        let count = slides.len() as i64;
+        if playlist.shuffle {
+            // A new order every time through the list.
+            let name = if saved {
+                playlist.display.as_str()
+            } else {
+                DEFAULT_DISPLAY
+            };
+            shuffle(&mut slides, seed(name, slot.div_euclid(count)));
+        }
+        Some(Self {
+            rule,
+            slides,
+            position: slot.rem_euclid(count) as usize,
+            shown_at: Utc.timestamp_opt(slot * interval, 0).single()?,
+            next_at: Utc.timestamp_opt((slot + 1) * interval, 0).single()?,
+        })
+    }
+}
+
+fn seed(display: &str, cycle: i64) -> u64 {
+    Sha256::digest(format!("{}:{}", display, cycle).as_bytes())
+        .iter()
+        .take(8)
+        .fold(0, |seed, byte| seed << 8 | u64::from(*byte))
+}
+
+/// Fisher-Yates, driven by splitmix64 so the same seed always gives the same
+/// order.
+fn shuffle<T>(items: &mut [T], mut state: u64) {
+    for i in (1..items.len()).rev() {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        items.swap(i, (z % (i as u64 + 1)) as usize);
+    }
+}
+
+/// Every photo in `PHOTO_CACHE_DIR`, by id.
+fn slides() -> Vec<Slide> {
+    let records = records();
+    let mut slides: Vec<Slide> = photo_paths()
+        .iter()
+        .filter_map(|path| path.file_stem())
+        .map(|id| {
+            let id = id.to_string_lossy().to_string();
+            let record = records.get(&id);
+            Slide {
+                caption: record.and_then(|record| record.caption.clone()),
+                taken_at: record.and_then(|record| record.taken_at()),
+                id,
+            }
+        })
+        .collect();
+    slides.sort_by(|a, b| a.id.cmp(&b.id));
+    slides
+}
+
+/// # Settings
+/// The display's playlist and whether it is saved. Falls back to the
+/// `default` display's playlist, then to every photo for five minutes each.
+fn settings(connection: &DbConnection, display: &str) -> diesel::QueryResult<(Playlist, bool)> {
+    if let Some(playlist) = Playlist::for_display(connection, display)? {
+        return Ok((playlist, true));
+    }
+    let playlist = match Playlist::for_display(connection, DEFAULT_DISPLAY)? {
+        Some(default) => Playlist {
+            display: display.to_string(),
+            ..default
+        },
+        None => Playlist {
+            id: 0,
+            display: display.to_string(),
+            interval_seconds: DEFAULT_INTERVAL,
+            shuffle: false,
+            rule: String::from(Rule::All.name()),
+            updated: Utc::now().naive_utc(),
+        },
+    };
+    Ok((playlist, false))
+}
+
+/// Display names end up in URLs, so keep them plain.
+fn is_valid_display(display: &str) -> bool {
+    !display.is_empty()
+        && display.len() <= 64
+        && display
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+/// # Current Photo
+/// Returns the photo a display should show right now, as a `CurrentPhoto`.
+/// `display` picks the playlist and defaults to `default`. The response may
+/// be cached until `next_at`, when the next photo is due.
+pub async fn current(req: Request<Body>) -> Response<Body> {
+    if !Method::GET.eq(req.method()) {
+        return method_not_allowed();
+    }
+    let input: CurrentInput = match query_parameters(&req) {
+        Some(input) => input,
+        None => return bad_request(),
+    };
+    let display = input
+        .display
+        .unwrap_or_else(|| String::from(DEFAULT_DISPLAY));
+    if !is_valid_display(&display) {
+        return bad_request();
+    }
+    let result = tokio::task::spawn_blocking(move || {
+        let connection = crate::db::connection()?;
+        let (playlist, saved) = settings(&connection, &display)?;
+        drop(connection);
+        let now = Utc::now();
+        Ok::<_, anyhow::Error>(Lineup::new(&playlist, saved, slides(), now).map(|lineup| {
+            let slide = lineup.slides[lineup.position].clone();
+            CurrentPhoto {
+                display,
+                url: format!("/background-photos/{}", slide.id),
+                id: slide.id,
+                caption: slide.caption,
+                taken_at: slide.taken_at,
+                rule: lineup.rule,
+                position: lineup.position,
+                count: lineup.slides.len(),
+                shown_at: lineup.shown_at,
+                next_at: lineup.next_at,
+            }
+        }))
+    })
+    .await;
+    let current = match result {
+        Ok(Ok(Some(current))) => current,
+        Ok(Ok(None)) => return not_found(),
+        Ok(Err(err)) => {
+            tracing::error!("Could not read playlist: {:?}", err);
+            return internal_server_error();
+        }
+        Err(_) => return internal_server_error(),
+    };
+    let max_age = (current.next_at - Utc::now()).num_seconds().max(0);
+    match serde_json::to_string(&current) {
+        Ok(body) => {
+            let mut response = json_response(&req, body);
+            if let Ok(cache_control) = format!("private, max-age={}", max_age).parse() {
+                response
+                    .headers_mut()
+                    .insert("Cache-Control", cache_control);
+            }
+            response
+        }
+        Err(_) => internal_server_error(),
+    }
+}
+
+/// # Playlists
+/// `GET /playlists` lists the saved playlists. For one display:
+/// - `GET /playlists/{display}` returns a `PlaylistInfo`, with the order the
+///   photos are shown in.
+/// - `PUT /playlists/{display}` saves `interval_seconds`, `shuffle` and
+///   `rule` (`all`, `seasonal` or `on_this_day`) from a JSON body. Fields left
+///   out keep their current value.
+/// - `DELETE /playlists/{display}` goes back to the default settings.
+pub async fn playlists(mut req: Request<Body>, display: Option<String>) -> Response<Body> {
+    let display = match display {
+        None if Method::GET.eq(req.method()) => return list(&req).await,
+        None => return method_not_allowed(),
+        Some(display) if is_valid_display(&display) => display,
+        Some(_) => return not_found(),
+    };
+    let input = if Method::PUT.eq(req.method()) {
+        let body =
+            match hyper::body::to_bytes(std::mem::replace(req.body_mut(), Body::empty())).await {
+                Ok(body) => body,
+                Err(_) => return bad_request(),
+            };
+        match serde_json::from_slice::<PlaylistInput>(&body) {
+            Ok(input) => Some(input),
+            Err(err) => {
+                tracing::warn!("Could not decode playlist {:?}", err);
+                return bad_request();
+            }
+        }
+    } else {
+        None
+    };
+
+    let delete = Method::DELETE.eq(req.method());
+    if !delete && input.is_none() && !Method::GET.eq(req.method()) {
+        return method_not_allowed();
+    }
+    let result = crate::db::run(move |connection| {
+        if delete {
+            return Ok(Outcome::Deleted(Playlist::delete(connection, &display)?));
+        }
+        let (playlist, saved) = match input {
+            None => settings(connection, &display)?,
+            Some(input) => match save(connection, &display, input)? {
+                Some(result) => result,
+                None => return Ok(Outcome::Invalid),
+            },
+        };
+        // Listing the photos reads the disk, so it happens here too.
+        let photos = match Lineup::new(&playlist, saved, slides(), Utc::now()) {
+            Some(lineup) => lineup.slides.into_iter().map(|slide| slide.id).collect(),
+            None => Vec::new(),
+        };
+        Ok::<_, diesel::result::Error>(Outcome::Found(PlaylistInfo {
+            playlist,
+            saved,
+            photos,
+        }))
+    })
+    .await;
+    let info = match result {
+        Ok(Ok(Outcome::Found(info))) => info,
+        Ok(Ok(Outcome::Invalid)) => return bad_request(),
+        Ok(Ok(Outcome::Deleted(true))) => return no_content(),
+        Ok(Ok(Outcome::Deleted(false))) => return not_found(),
+        Ok(Err(err)) => {
+            tracing::error!("Could not save playlist: {:?}", err);
+            return internal_server_error();
+        }
+        Err(_) => return internal_server_error(),
+    };
+    match serde_json::to_string(&info) {
+        Ok(body) => json_response(&req, body),
+        Err(_) => internal_server_error(),
+    }
+}
+
+async fn list(req: &Request<Body>) -> Response<Body> {
+    match crate::db::run(Playlist::all).await {
+        Ok(Ok(playlists)) => match serde_json::to_string(&playlists) {
+            Ok(body) => json_response(req, body),
+            Err(_) => internal_server_error(),
+        },
+        Ok(Err(err)) => {
+            tracing::error!("Could not read playlists: {:?}", err);
+            internal_server_error()
+        }
+        Err(_) => internal_server_error(),
+    }
+}
+
+/// Merges the input over the current settings and saves them. Returns `None`
+/// when the input is out of range.
+fn save(
+    connection: &DbConnection,
+    display: &str,
+    input: PlaylistInput,
+) -> diesel::QueryResult<Option<(Playlist, bool)>> {
+    let (current, _) = settings(connection, display)?;
+    let interval_seconds = input.interval_seconds.unwrap_or(current.interval_seconds);
+    if !(MIN_INTERVAL..=MAX_INTERVAL).contains(&interval_seconds) {
+        return Ok(None);
+    }
+    let playlist = NewPlaylist {
+        display: display.to_string(),
+        interval_seconds,
+        shuffle: input.shuffle.unwrap_or(current.shuffle),
+        rule: input
+            .rule
+            .map(|rule| String::from(rule.name()))
+            .unwrap_or(current.rule),
+        updated: Utc::now().naive_utc(),
+    };
+    Ok(Some((Playlist::save(connection, &playlist)?, true)))
+}
+
+fn no_content() -> Response<Body> {
+    match Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Body::empty())
+    {
+        Ok(response) => response,
+        Err(_) => internal_server_error(),
+    }
+}
@@ -0,0 +1,235 @@
+use super::cache::json_response;
+use super::{
+    bad_request, internal_server_error, method_not_allowed, not_found, query_parameters,
+    status_only,
+};
+use crate::webhook::{Delivery, Event, NewWebhook, Webhook, ANY_EVENT, DELIVERED, FAILED, PENDING};
+use chrono::Utc;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+// this file covers managing webhooks (`/webhooks`) and reading their delivery
+// log (`/webhooks/{id}/deliveries`)
+
+const DEFAULT_DELIVERIES: i64 = 100;
+const MAX_DELIVERIES: i64 = 1000;
+
+/// # Webhook Input
+/// A whole webhook, for `POST /webhooks` and `PUT /webhooks/{id}`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WebhookInput {
+    url: String,
+    /// Event names, or `*` for every event.
+    events: Vec<String>,
+    /// Made up when a webhook is created without one, and kept when a
+    /// webhook is saved without one.
+    secret: Option<String>,
+    enabled: Option<bool>,
+}
+
+impl WebhookInput {
+    /// Checks the input, explaining what is wrong with it.
+    fn into_webhook(self) -> Result<NewWebhook, String> {
+        if !self.url.starts_with("http://") && !self.url.starts_with("https://") {
+            return Err(String::from("url must be an http(s) URL"));
+        }
+        if self.events.is_empty() {
+            return Err(String::from("events must not be empty"));
+        }
+        for event in &self.events {
+            if event != ANY_EVENT && Event::parse(event).is_none() {
+                return Err(format!("{} is not an event", event));
+            }
+        }
+        if let Some(secret) = &self.secret {
+            if secret.is_empty() {
+                return Err(String::from("secret must not be empty"));
+            }
+        }
+        Ok(NewWebhook {
+            url: self.url,
+            events: self.events.join(","),
+            secret: self.secret,
+            enabled: self.enabled.unwrap_or(true),
+            updated: Utc::now().naive_utc(),
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct DeliveriesInput {
+    status: Option<String>,
+    limit: Option<i64>,
+}
+
+/// # Created Webhook
+/// A new webhook with its secret, which is not shown again.
+#[derive(Serialize)]
+struct CreatedWebhook {
+    #[serde(flatten)]
+    webhook: Webhook,
+    secret: String,
+}
+
+enum Outcome {
+    Found(Vec<Webhook>),
+    Created(Webhook),
+    Deleted(bool),
+    Missing,
+}
+
+/// 32 random bytes, in hex.
+fn new_secret() -> String {
+    rand::thread_rng()
+        .gen::<[u8; 32]>()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// # Webhooks
+/// - `GET /webhooks` lists every webhook.
+/// - `POST /webhooks` adds a webhook from a `WebhookInput` and returns it
+///   with its secret (201 Created).
+/// - `GET /webhooks/{id}` returns a webhook.
+/// - `PUT /webhooks/{id}` replaces a webhook.
+/// - `DELETE /webhooks/{id}` removes a webhook and its deliveries.
+pub async fn webhooks(mut req: Request<Body>, id: Option<String>) -> Response<Body> {
+    let id = match id.as_deref().map(str::parse::<i32>) {
+        None => None,
+        Some(Ok(id)) => Some(id),
+        Some(Err(_)) => return not_found(),
+    };
+    let method = req.method().clone();
+    let input = if (method == Method::POST && id.is_none())
+        || (method == Method::PUT && id.is_some())
+    {
+        let body =
+            match hyper::body::to_bytes(std::mem::replace(req.body_mut(), Body::empty())).await {
+                Ok(body) => body,
+                Err(_) => return bad_request(),
+            };
+        let input = match serde_json::from_slice::<WebhookInput>(&body) {
+            Ok(input) => input,
+            Err(err) => {
+                tracing::warn!("Could not decode webhook {:?}", err);
+                return bad_request();
+            }
+        };
+        match input.into_webhook() {
+            Ok(webhook) => Some(webhook),
+            Err(reason) => {
+                tracing::warn!("Webhook is not valid: {}", reason);
+                return bad_request();
+            }
+        }
+    } else {
+        None
+    };
+
+    let result = crate::db::run(move |connection| {
+        let outcome = match (method, id, input) {
+            (Method::GET, None, _) => Outcome::Found(Webhook::all(connection)?),
+            (Method::POST, None, Some(mut webhook)) => {
+                webhook.secret = Some(webhook.secret.unwrap_or_else(new_secret));
+                Outcome::Created(Webhook::create(connection, &webhook)?)
+            }
+            (Method::GET, Some(id), _) => match Webhook::by_id(connection, id)? {
+                Some(webhook) => Outcome::Found(vec![webhook]),
+                None => Outcome::Missing,
+            },
+            (Method::PUT, Some(id), Some(webhook)) => {
+                match Webhook::update(connection, id, &webhook)? {
+                    Some(webhook) => Outcome::Found(vec![webhook]),
+                    None => Outcome::Missing,
+                }
+            }
+            (Method::DELETE, Some(id), _) => Outcome::Deleted(Webhook::delete(connection, id)?),
+            _ => return Ok(None),
+        };
+        Ok::<_, diesel::result::Error>(Some(outcome))
+    })
+    .await;
+    let (status, body) = match result {
+        Ok(Ok(Some(Outcome::Found(webhooks)))) if id.is_none() => {
+            (StatusCode::OK, serde_json::to_string(&webhooks))
+        }
+        Ok(Ok(Some(Outcome::Found(webhooks)))) => match webhooks.first() {
+            Some(webhook) => (StatusCode::OK, serde_json::to_string(webhook)),
+            None => return not_found(),
+        },
+        Ok(Ok(Some(Outcome::Created(webhook)))) => {
+            let secret = webhook.secret.clone();
+            (
+                StatusCode::CREATED,
+                serde_json::to_string(&CreatedWebhook { webhook, secret }),
+            )
+        }
+        Ok(Ok(Some(Outcome::Deleted(true)))) => return status_only(StatusCode::NO_CONTENT),
+        Ok(Ok(Some(Outcome::Deleted(false)))) | Ok(Ok(Some(Outcome::Missing))) => {
+            return not_found()
+        }
+        Ok(Ok(None)) => return method_not_allowed(),
+        Ok(Err(err)) => {
+            tracing::error!("Could not save webhooks: {:?}", err);
+            return internal_server_error();
+        }
+        Err(_) => return internal_server_error(),
+    };
+    match body {
+        Ok(body) => {
+            let mut response = json_response(&req, body);
+            *response.status_mut() = status;
+            response
+        }
+        Err(_) => internal_server_error(),
+    }
+}
+
+/// # Deliveries
+/// `GET /webhooks/{id}/deliveries` lists a webhook's deliveries, newest
+/// first. Takes `status` (`pending`, `delivered` or `failed`) and `limit`
+/// (100 by default, up to 1000).
+pub async fn deliveries(req: Request<Body>, id: &str) -> Response<Body> {
+    if !Method::GET.eq(req.method()) {
+        return method_not_allowed();
+    }
+    let id = match id.parse::<i32>() {
+        Ok(id) => id,
+        Err(_) => return not_found(),
+    };
+    let input: DeliveriesInput = match query_parameters(&req) {
+        Some(input) => input,
+        None => return bad_request(),
+    };
+    let status = match input.status.as_deref() {
+        None => None,
+        Some(status) if [PENDING, DELIVERED, FAILED].contains(&status) => Some(status.to_string()),
+        Some(_) => return bad_request(),
+    };
+    let limit = input
+        .limit
+        .unwrap_or(DEFAULT_DELIVERIES)
+        .clamp(1, MAX_DELIVERIES);
+    let result = crate::db::run(move |connection| {
+        if Webhook::by_id(connection, id)?.is_none() {
+            return Ok(None);
+        }
+        Delivery::recent(connection, id, status.as_deref(), limit).map(Some)
+    })
+    .await;
+    match result {
+        Ok(Ok(Some(deliveries))) => match serde_json::to_string(&deliveries) {
+            Ok(body) => json_response(&req, body),
+            Err(_) => internal_server_error(),
+        },
+        Ok(Ok(None)) => not_found(),
+        Ok(Err(err)) => {
+            tracing::error!("Could not read webhook deliveries: {:?}", err);
+            internal_server_error()
+        }
+        Err(_) => internal_server_error(),
+    }
+}
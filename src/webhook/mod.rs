@@ -0,0 +1,274 @@
+use chrono::{DateTime, Duration, Utc};
+use hmac::{Hmac, KeyInit, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::thread;
+use std::time::Instant;
+
+use subscription::{Attempt, NewDelivery};
+pub use subscription::{Delivery, NewWebhook, Webhook};
+
+mod subscription;
+
+// this file covers sending events to webhooks: queueing a delivery for every
+// subscribed webhook, and the thread that sends them and retries failures
+
+pub const PENDING: &str = "pending";
+pub const DELIVERED: &str = "delivered";
+pub const FAILED: &str = "failed";
+/// A webhook subscribed to this gets every event.
+pub const ANY_EVENT: &str = "*";
+
+/// How often the delivery thread looks for deliveries that are due.
+const POLL: std::time::Duration = std::time::Duration::from_secs(5);
+/// Deliveries sent per look, so a backlog does not hold up retries.
+const BATCH: i64 = 50;
+/// Attempts before a delivery is given up on. With the backoff below, the
+/// last one is about an hour after the first.
+const MAX_ATTEMPTS: i32 = 8;
+/// The wait after the first failed attempt, doubled after each one.
+const FIRST_RETRY_SECONDS: i64 = 30;
+/// Deliveries that finished longer ago than this are deleted.
+const KEEP_DAYS: i64 = 30;
+/// How often old deliveries are deleted.
+const PRUNE_EVERY: std::time::Duration = std::time::Duration::from_secs(3600);
+/// Webhooks that take longer than this to answer have failed.
+const TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// # Event
+/// Something that happened, which webhooks can subscribe to.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum Event {
+    /// The worker saved a round of readings.
+    #[serde(rename = "readings.new")]
+    Readings,
+    /// The worker got a new forecast.
+    #[serde(rename = "forecast.updated")]
+    Forecast,
+    /// The Ecobee token could not be refreshed, so the install has to be
+    /// done again.
+    #[serde(rename = "ecobee.reauth_needed")]
+    EcobeeReauth,
+    /// A photo refresh finished.
+    #[serde(rename = "photos.synced")]
+    PhotosSynced,
+}
+
+impl Event {
+    pub fn parse(name: &str) -> Option<Self> {
+        serde_json::from_value(serde_json::Value::String(name.to_string())).ok()
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Event::Readings => "readings.new",
+            Event::Forecast => "forecast.updated",
+            Event::EcobeeReauth => "ecobee.reauth_needed",
+            Event::PhotosSynced => "photos.synced",
+        }
+    }
+}
+
+/// # Payload
+/// The JSON body every webhook is sent.
+#[derive(Serialize)]
+struct Payload<'a, T: Serialize> {
+    event: &'static str,
+    time: DateTime<Utc>,
+    data: &'a T,
+}
+
+/// # Emit
+/// Queues an event for every enabled webhook subscribed to it. The delivery
+/// thread sends it within a few seconds. Failures are logged; whatever
+/// emitted the event carries on.
+pub fn emit<T: Serialize>(event: Event, data: &T) {
+    if let Err(err) = queue(event, data) {
+        tracing::error!(event = event.name(), "Could not queue webhooks: {:#}", err);
+    }
+}
+
+fn queue<T: Serialize>(event: Event, data: &T) -> anyhow::Result<()> {
+    let connection = crate::db::connection()?;
+    let subscribers: Vec<Webhook> = Webhook::all(&connection)?
+        .into_iter()
+        .filter(|webhook| webhook.enabled && webhook.wants(event.name()))
+        .collect();
+    if subscribers.is_empty() {
+        return Ok(());
+    }
+    let now = Utc::now();
+    let payload = serde_json::to_string(&Payload {
+        event: event.name(),
+        time: now,
+        data,
+    })?;
+    let deliveries: Vec<NewDelivery> = subscribers
+        .iter()
+        .map(|webhook| NewDelivery {
+            webhook_id: webhook.id,
+            event: event.name().to_string(),
+            payload: payload.clone(),
+            status: PENDING.to_string(),
+            next_attempt: Some(now.naive_utc()),
+            created: now.naive_utc(),
+        })
+        .collect();
+    Delivery::insert(&connection, &deliveries)?;
+    tracing::debug!(
+        event = event.name(),
+        "Queued {} webhook deliveries",
+        deliveries.len()
+    );
+    Ok(())
+}
+
+/// # Start
+/// Starts the thread that sends queued deliveries, retrying failures with
+/// backoff, and deletes old ones.
+pub fn start() {
+    tracing::info!("Starting webhook delivery thread");
+    thread::spawn(|| {
+        let mut next_prune = Instant::now();
+        loop {
+            if let Err(err) = deliver_due() {
+                tracing::error!("Could not send webhooks: {:#}", err);
+            }
+            if Instant::now() >= next_prune {
+                next_prune = Instant::now() + PRUNE_EVERY;
+                prune();
+            }
+            thread::sleep(POLL);
+        }
+    });
+}
+
+fn deliver_due() -> anyhow::Result<()> {
+    let connection = crate::db::connection()?;
+    for (delivery, webhook) in Delivery::due(&connection, Utc::now().naive_utc(), BATCH)? {
+        let attempt = attempt(&delivery, &webhook);
+        Delivery::record(&connection, delivery.id, &attempt)?;
+    }
+    Ok(())
+}
+
+fn prune() {
+    let before = (Utc::now() - Duration::days(KEEP_DAYS)).naive_utc();
+    let pruned = crate::db::connection()
+        .map_err(anyhow::Error::from)
+        .and_then(|connection| Ok(Delivery::prune(&connection, before)?));
+    match pruned {
+        Ok(0) => (),
+        Ok(count) => tracing::info!("Deleted {} old webhook deliveries", count),
+        Err(err) => tracing::error!("Could not delete old webhook deliveries: {:#}", err),
+    }
+}
+
+/// # Attempt
+/// Sends a delivery once, and works out what happens to it next.
+fn attempt(delivery: &Delivery, webhook: &Webhook) -> Attempt {
+    let attempts = delivery.attempts + 1;
+    let result = if webhook.enabled {
+        post(
+            &webhook.url,
+            delivery,
+            &signature(&webhook.secret, &delivery.payload),
+        )
+    } else {
+        Err((None, String::from("the webhook is disabled")))
+    };
+    let now = Utc::now();
+    let outcome = match &result {
+        Ok(_) => DELIVERED,
+        Err(_) if webhook.enabled && attempts < MAX_ATTEMPTS => PENDING,
+        Err(_) => FAILED,
+    };
+    crate::metrics::inc(
+        crate::metrics::WEBHOOK_DELIVERIES,
+        &[
+            ("event", &delivery.event),
+            ("result", if outcome == PENDING { "retry" } else { outcome }),
+        ],
+    );
+    match result {
+        Ok(status) => Attempt {
+            status: DELIVERED.to_string(),
+            attempts,
+            next_attempt: None,
+            response_status: Some(status),
+            error: None,
+            delivered: Some(now.naive_utc()),
+        },
+        Err((status, error)) => {
+            tracing::warn!(
+                webhook = webhook.id,
+                delivery = delivery.id,
+                attempts,
+                "Webhook delivery failed: {}",
+                error
+            );
+            let next_attempt = if outcome == PENDING {
+                Some((now + backoff(attempts)).naive_utc())
+            } else {
+                None
+            };
+            Attempt {
+                status: outcome.to_string(),
+                attempts,
+                next_attempt,
+                response_status: status,
+                error: Some(error),
+                delivered: None,
+            }
+        }
+    }
+}
+
+/// 30 seconds after the first failure, doubling each time.
+fn backoff(attempts: i32) -> Duration {
+    Duration::seconds(FIRST_RETRY_SECONDS << (attempts - 1).clamp(0, 16))
+}
+
+/// # Signature
+/// `sha256=` and the hex HMAC-SHA256 of the body, keyed with the webhook's
+/// secret.
+pub fn signature(secret: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(body.as_bytes());
+    let hex: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!("sha256={}", hex)
+}
+
+/// Sends a delivery. Returns the HTTP status, or the status (if there was
+/// an answer) and why it failed.
+#[tokio::main]
+async fn post(
+    url: &str,
+    delivery: &Delivery,
+    signature: &str,
+) -> Result<i32, (Option<i32>, String)> {
+    let response = crate::REQWEST
+        .post(url)
+        .header("Content-Type", "application/json")
+        .header("User-Agent", "github.com/ryanknu/therm_hub")
+        .header("X-ThermHub-Event", delivery.event.as_str())
+        .header("X-ThermHub-Delivery", delivery.id.to_string())
+        .header("X-ThermHub-Signature-256", signature)
+        .timeout(TIMEOUT)
+        .body(delivery.payload.clone())
+        .send()
+        .await
+        .map_err(|err| (None, err.to_string()))?;
+    let status = response.status();
+    if status.is_success() {
+        Ok(i32::from(status.as_u16()))
+    } else {
+        Err((Some(i32::from(status.as_u16())), format!("HTTP {}", status)))
+    }
+}
@@ -0,0 +1,225 @@
+use crate::db::DbConnection;
+use crate::schema::{webhook_deliveries, webhooks};
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use serde::{Serialize, Serializer};
+
+// this file covers the webhook tables: the subscriptions in `webhooks` and
+// every event sent to them in `webhook_deliveries`
+
+/// # Webhook
+/// A URL that is sent the events it subscribes to.
+#[derive(Clone, Debug, Queryable, Serialize)]
+pub struct Webhook {
+    pub id: i32,
+    pub url: String,
+    /// Event names separated by commas, or `*` for every event. Served as a
+    /// list.
+    #[serde(serialize_with = "split_events")]
+    pub events: String,
+    /// Signs the deliveries. Only shown when the webhook is created.
+    #[serde(skip_serializing)]
+    pub secret: String,
+    pub enabled: bool,
+    /// In UTC.
+    pub created: NaiveDateTime,
+    /// When the webhook was last saved, in UTC.
+    pub updated: NaiveDateTime,
+}
+
+#[derive(AsChangeset, Insertable)]
+#[table_name = "webhooks"]
+pub struct NewWebhook {
+    pub url: String,
+    pub events: String,
+    /// `None` keeps the secret when updating.
+    pub secret: Option<String>,
+    pub enabled: bool,
+    pub updated: NaiveDateTime,
+}
+
+/// # Delivery
+/// One event for one webhook, and how sending it went.
+#[derive(Clone, Debug, Queryable, Serialize)]
+pub struct Delivery {
+    pub id: i32,
+    pub webhook_id: i32,
+    pub event: String,
+    /// The JSON body, as sent.
+    pub payload: String,
+    /// `pending` until it is delivered or given up on (`failed`).
+    pub status: String,
+    pub attempts: i32,
+    /// When it will be tried again, in UTC.
+    pub next_attempt: Option<NaiveDateTime>,
+    /// The HTTP status of the last attempt, if the webhook answered.
+    pub response_status: Option<i32>,
+    /// Why the last attempt failed.
+    pub error: Option<String>,
+    /// In UTC.
+    pub created: NaiveDateTime,
+    /// In UTC.
+    pub delivered: Option<NaiveDateTime>,
+}
+
+#[derive(Insertable)]
+#[table_name = "webhook_deliveries"]
+pub struct NewDelivery {
+    pub webhook_id: i32,
+    pub event: String,
+    pub payload: String,
+    pub status: String,
+    pub next_attempt: Option<NaiveDateTime>,
+    pub created: NaiveDateTime,
+}
+
+/// # Attempt
+/// The outcome of sending a delivery once.
+#[derive(AsChangeset)]
+#[table_name = "webhook_deliveries"]
+// `None` clears what the previous attempt left.
+#[changeset_options(treat_none_as_null = "true")]
+pub struct Attempt {
+    pub status: String,
+    pub attempts: i32,
+    pub next_attempt: Option<NaiveDateTime>,
+    pub response_status: Option<i32>,
+    pub error: Option<String>,
+    pub delivered: Option<NaiveDateTime>,
+}
+
+fn split_events<S: Serializer>(events: &str, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(events.split(',').filter(|event| !event.is_empty()))
+}
+
+impl Webhook {
+    pub fn all(connection: &DbConnection) -> QueryResult<Vec<Self>> {
+        let select = webhooks::table.order(webhooks::id);
+        crate::db::log_query(&select);
+        select.load(connection)
+    }
+
+    pub fn by_id(connection: &DbConnection, id: i32) -> QueryResult<Option<Self>> {
+        let select = webhooks::table.filter(webhooks::id.eq(id)).limit(1);
+        crate::db::log_query(&select);
+        Ok(select.load(connection)?.into_iter().next())
+    }
+
+    /// Whether the webhook wants an event.
+    pub fn wants(&self, event: &str) -> bool {
+        self.events
+            .split(',')
+            .any(|wanted| wanted == super::ANY_EVENT || wanted == event)
+    }
+
+    pub fn create(connection: &DbConnection, webhook: &NewWebhook) -> QueryResult<Self> {
+        connection.transaction(|| {
+            let insert = diesel::insert_into(webhooks::table).values(webhook);
+            crate::db::log_query(&insert);
+            insert.execute(connection)?;
+            // Postgres and SQLite both hand out growing ids.
+            let select = webhooks::table.order(webhooks::id.desc()).limit(1);
+            crate::db::log_query(&select);
+            select.first(connection)
+        })
+    }
+
+    /// Returns `None` when there is no such webhook.
+    pub fn update(
+        connection: &DbConnection,
+        id: i32,
+        webhook: &NewWebhook,
+    ) -> QueryResult<Option<Self>> {
+        let update = diesel::update(webhooks::table.filter(webhooks::id.eq(id))).set(webhook);
+        crate::db::log_query(&update);
+        if update.execute(connection)? == 0 {
+            return Ok(None);
+        }
+        Self::by_id(connection, id)
+    }
+
+    /// Deletes a webhook and its deliveries. Returns whether there was one.
+    pub fn delete(connection: &DbConnection, id: i32) -> QueryResult<bool> {
+        connection.transaction(|| {
+            // SQLite only cascades with foreign keys turned on.
+            let deliveries = diesel::delete(
+                webhook_deliveries::table.filter(webhook_deliveries::webhook_id.eq(id)),
+            );
+            crate::db::log_query(&deliveries);
+            deliveries.execute(connection)?;
+            let delete = diesel::delete(webhooks::table.filter(webhooks::id.eq(id)));
+            crate::db::log_query(&delete);
+            Ok(delete.execute(connection)? > 0)
+        })
+    }
+}
+
+impl Delivery {
+    /// A webhook's deliveries, newest first, optionally only those with a
+    /// status.
+    pub fn recent(
+        connection: &DbConnection,
+        webhook_id: i32,
+        status: Option<&str>,
+        limit: i64,
+    ) -> QueryResult<Vec<Self>> {
+        let mut select = webhook_deliveries::table
+            .filter(webhook_deliveries::webhook_id.eq(webhook_id))
+            .order(webhook_deliveries::id.desc())
+            .limit(limit)
+            .into_boxed();
+        if let Some(status) = status {
+            select = select.filter(webhook_deliveries::status.eq(status));
+        }
+        crate::db::log_query(&select);
+        select.load(connection)
+    }
+
+    /// Pending deliveries whose next attempt is due, oldest first, with their
+    /// webhooks.
+    pub fn due(
+        connection: &DbConnection,
+        now: NaiveDateTime,
+        limit: i64,
+    ) -> QueryResult<Vec<(Self, Webhook)>> {
+        let select = webhook_deliveries::table
+            .inner_join(webhooks::table)
+            .filter(webhook_deliveries::status.eq(super::PENDING))
+            .filter(webhook_deliveries::next_attempt.le(now))
+            .order(webhook_deliveries::id)
+            .limit(limit);
+        crate::db::log_query(&select);
+        select.load(connection)
+    }
+
+    pub fn insert(connection: &DbConnection, deliveries: &[NewDelivery]) -> QueryResult<()> {
+        // One at a time, as SQLite cannot insert several rows at once.
+        connection.transaction(|| {
+            for delivery in deliveries {
+                let insert = diesel::insert_into(webhook_deliveries::table).values(delivery);
+                crate::db::log_query(&insert);
+                insert.execute(connection)?;
+            }
+            Ok(())
+        })
+    }
+
+    pub fn record(connection: &DbConnection, id: i32, attempt: &Attempt) -> QueryResult<usize> {
+        let update =
+            diesel::update(webhook_deliveries::table.filter(webhook_deliveries::id.eq(id)))
+                .set(attempt);
+        crate::db::log_query(&update);
+        update.execute(connection)
+    }
+
+    /// Deletes finished deliveries created before `before`.
+    pub fn prune(connection: &DbConnection, before: NaiveDateTime) -> QueryResult<usize> {
+        let delete = diesel::delete(
+            webhook_deliveries::table
+                .filter(webhook_deliveries::status.ne(super::PENDING))
+                .filter(webhook_deliveries::created.lt(before)),
+        );
+        crate::db::log_query(&delete);
+        delete.execute(connection)
+    }
+}
@@ -1,22 +1,65 @@
 use crate::{
-    ecobee, establish_connection,
-    NowResponse, Thermostat, NOW_RES, NOW_STR,
+    alert, comfort, comfort::MoldRisk, ecobee, ecobee::Reading, establish_connection, health,
+    metrics, mqtt, rollup, tsdb, web::CachedBody, webhook, webhook::Event, NowResponse,
+    Thermostat, NOW_BODY, NOW_RES,
 };
 use weather::{daily_forecast, hourly_forecast, Forecast};
 pub use weather::{DailyCondition, HourlyCondition};
 use chrono::{DateTime, Duration as ChronoDuration, Utc};
-use std::{sync::Arc, thread, time::Duration};
+use crate::db::DbConnection;
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
 
 mod weather;
 
+/// How often the worker does work, in seconds.
+pub const INTERVAL: i64 = 300;
+/// How long, in seconds, a sensor stays in the now response after its last
+/// reading when a round does not bring a new one. Sensors that push their
+/// readings are not part of the worker's rounds.
+const KEEP_IN_NOW: i64 = 3 * INTERVAL;
+
+type StsDateTime = Arc<RwLock<DateTime<Utc>>>;
+lazy_static! {
+    static ref NEXT_RUN: StsDateTime = Arc::new(RwLock::new(Utc::now()));
+}
+/// Whether webhooks have been told the Ecobee token needs re-authorizing
+/// since it last worked.
+static REAUTH_SENT: AtomicBool = AtomicBool::new(false);
+
+#[derive(Serialize)]
+struct ReadingsEvent<'a> {
+    thermostats: &'a [Thermostat],
+}
+
+#[derive(Serialize)]
+struct ForecastEvent<'a> {
+    forecast_hourly: &'a [HourlyCondition],
+    forecast_daily: &'a [DailyCondition],
+}
+
+#[derive(Serialize)]
+struct ReauthEvent {
+    reason: &'static str,
+}
+
 /// # Start Worker Thread
 /// The worker thread is a background program that retrieves information
 /// from Internet services every 5 minutes. It will put historical entries
 /// in the database, and update static data.
 pub fn start() {
-    crate::log_message("Starting worker thread");
+    tracing::info!("Starting worker thread");
     thread::spawn(|| {
         let mut last_timestamp = Utc::now();
+        set_next_run(&last_timestamp);
         loop {
             if throttle(&mut last_timestamp) {
                 work();
@@ -28,12 +71,16 @@ pub fn start() {
 
 /// # Check
 /// Allows the `work()` function to be called outside of the background
-/// thread to make sure that readings can be obtained.
-/// TODO: Create a pathway to return false, then it would just be `work()`
+/// thread to make sure that readings can be obtained. Returns `false`, after
+/// logging why, if any of the remote API's could not be polled.
 pub fn check() -> bool {
-    crate::log_message("Starting check of worker");
+    tracing::info!("Starting check of worker");
     work();
-    true
+    let problems = health::startup_problems();
+    for problem in &problems {
+        tracing::error!("Startup check failed: {}", problem);
+    }
+    problems.is_empty()
 }
 
 /// # Throttle
@@ -41,17 +88,36 @@ pub fn check() -> bool {
 /// how often it "wakes up".
 fn throttle(last_timestamp: &mut DateTime<Utc>) -> bool {
     let now = Utc::now();
-    let decision = now - *last_timestamp > ChronoDuration::seconds(300);
+    let decision = now - *last_timestamp > ChronoDuration::seconds(INTERVAL);
     if decision {
         *last_timestamp = Utc::now();
+        set_next_run(last_timestamp);
     }
     decision
 }
 
+/// # Next Run
+/// Returns when the worker will next refresh the data. Data served from
+/// NOW_BODY is good until then, so the web server uses it for caching.
+pub fn next_run() -> DateTime<Utc> {
+    match NEXT_RUN.read() {
+        Ok(next_run) => *next_run,
+        Err(_) => Utc::now(),
+    }
+}
+
+fn set_next_run(last_timestamp: &DateTime<Utc>) {
+    if let Ok(mut next_run) = NEXT_RUN.write() {
+        *next_run = *last_timestamp + ChronoDuration::seconds(INTERVAL);
+    }
+}
+
 /// # Work
 /// The unit of work that the worker thread does every time it's invoked.
 fn work() {
+    let start = Instant::now();
     let mut therms: Vec<Thermostat> = Vec::new();
+    let mut condition = None;
 
     // TODO: convert get_weather() to return Vec<Therm>,
     //       call most_applicable from here, call
@@ -61,15 +127,20 @@ fn work() {
 
     // TODO: use join
 
-    let hourly_forecast = hourly_forecast();
+    let hourly_forecast = timed("hourly_forecast", hourly_forecast);
+    let hourly_forecast_ok = hourly_forecast.is_some();
+    if !hourly_forecast_ok {
+        failed("hourly_forecast");
+    }
     if let Some(forcast) = hourly_forecast.clone() {
-        if let Some(condition) = most_applicable(forcast.conditions) {
-            // TODO: we should really be calling "into" or "from" here...
+        if let Some(current) = most_applicable(forcast.conditions) {
+            // weather.gov gives whole degrees; readings are in 1/10 degrees.
             therms.push(Thermostat::new(
                 String::from("weather.gov"),
-                condition.date,
-                condition.temperature,
+                current.date,
+                current.temperature * 10,
             ));
+            condition = Some(current.condition);
         }
     }
     write_hourly_forecast(hourly_forecast);
@@ -77,40 +148,224 @@ fn work() {
     // Write thermostats to db
     // TODO: don't write duplicates :P
     let db = establish_connection();
-    if let Some(token) = ecobee::current_token(&db) {
-        for reading in ecobee::read(&token.access_token) {
-            therms.push(Thermostat::new2(
-                reading.name,
-                reading.time,
-                reading.is_hygrostat,
-                reading.temperature,
-                reading.relative_humidity,
-            ));
+    let readings = timed("ecobee", || read_ecobee(&db));
+    match readings {
+        Err(reason) => {
+            failed("ecobee");
+            health::failed(health::ECOBEE, reason);
+        }
+        Ok(readings) => {
+            for reading in readings {
+                therms.push(Thermostat::new2(
+                    reading.name,
+                    reading.time,
+                    reading.is_hygrostat,
+                    reading.temperature,
+                    reading.relative_humidity,
+                ));
+            }
         }
     }
 
-    for therm in &therms {
-        therm.insert(&db);
+    timed("db_write", || {
+        for therm in &therms {
+            if let Err(err) = therm.insert(&db) {
+                tracing::error!("Could not save reading: {:?}", err);
+            }
+        }
+    });
+    if let Err(err) = timed("rollup", || rollup::compact(&db)) {
+        failed("rollup");
+        tracing::error!("Could not roll up readings: {:?}", err);
+    }
+    match timed("mold_risk", || comfort::mold_risks(&db)) {
+        Ok(mold_risk) => write_mold_risk(mold_risk),
+        Err(err) => {
+            failed("mold_risk");
+            tracing::error!("Could not check mold risk: {:?}", err);
+        }
     }
     drop(db);
 
-    write_daily_forecast(daily_forecast());
+    let daily_forecast = timed("daily_forecast", daily_forecast);
+    if daily_forecast.is_none() {
+        failed("daily_forecast");
+    }
+    match (&hourly_forecast_ok, &daily_forecast) {
+        (true, Some(_)) => health::succeeded(health::WEATHER),
+        (false, _) => health::failed(health::WEATHER, "could not get the hourly forecast"),
+        (_, None) => health::failed(health::WEATHER, "could not get the daily forecast"),
+    }
+    let forecast_updated = hourly_forecast_ok || daily_forecast.is_some();
+    write_daily_forecast(daily_forecast);
+    if let Err(err) = timed("alerts", || check_alerts(&therms)) {
+        failed("alerts");
+        tracing::error!("Could not check alerts: {:?}", err);
+    }
+    timed("webhooks", || emit_events(&therms, forecast_updated));
+    if let Err(err) = timed("mqtt", || publish_mqtt(&therms, condition.as_deref())) {
+        failed("mqtt");
+        tracing::error!("Could not publish to MQTT: {:#}", err);
+    }
+    if let Err(err) = timed("tsdb", export_tsdb) {
+        failed("tsdb");
+        tracing::error!("Could not export readings: {:#}", err);
+    }
     write_thermostats(therms);
     serialize_now();
+    metrics::observe_since(metrics::JOB_DURATION, &[("job", "work")], start);
+}
+
+/// # Check Alerts
+/// Runs the alert rules against this round's readings.
+fn check_alerts(therms: &[Thermostat]) -> anyhow::Result<()> {
+    let db = crate::db::connection()?;
+    alert::evaluate(&db, therms)?;
+    Ok(())
+}
+
+/// # Emit Events
+/// Queues this round's readings, and the forecast if it changed, for the
+/// webhooks.
+fn emit_events(therms: &[Thermostat], forecast_updated: bool) {
+    if !therms.is_empty() {
+        webhook::emit(Event::Readings, &ReadingsEvent { thermostats: therms });
+    }
+    if forecast_updated {
+        let (forecast_hourly, forecast_daily) = forecasts();
+        webhook::emit(
+            Event::Forecast,
+            &ForecastEvent {
+                forecast_hourly: &forecast_hourly,
+                forecast_daily: &forecast_daily,
+            },
+        );
+    }
+}
+
+/// # Forecasts
+/// Copies the forecasts out of the now response, so the lock is not held
+/// while they are sent anywhere.
+fn forecasts() -> (Vec<HourlyCondition>, Vec<DailyCondition>) {
+    let now_res = Arc::clone(&NOW_RES);
+    let now_res = now_res.read().unwrap();
+    (now_res.forecast_hourly.clone(), now_res.forecast_daily.clone())
+}
+
+/// # Publish MQTT
+/// Publishes this round's readings and the forecast to MQTT.
+fn publish_mqtt(therms: &[Thermostat], condition: Option<&str>) -> anyhow::Result<()> {
+    let (forecast_hourly, forecast_daily) = forecasts();
+    mqtt::publish(therms, condition, &forecast_hourly, &forecast_daily)
+}
+
+/// # Export TSDB
+/// Sends InfluxDB and Prometheus the readings saved since they were last
+/// sent any, including those pushed to `POST /readings`.
+fn export_tsdb() -> anyhow::Result<()> {
+    let db = crate::db::connection()?;
+    tsdb::export(&db)
+}
+
+/// # Read Ecobee
+/// Reads every Ecobee sensor, refreshing the token first. Not having an
+/// Ecobee token yet is not an error; the install just has not been done.
+/// Nothing is read when Ecobee is not configured.
+fn read_ecobee(db: &DbConnection) -> Result<Vec<Reading>, &'static str> {
+    if crate::config::get().ecobee.is_none() {
+        return Ok(Vec::new());
+    }
+    if ecobee::saved_token(db).is_none() {
+        health::not_installed(health::ECOBEE);
+        return Ok(Vec::new());
+    }
+    let token = match ecobee::current_token(db) {
+        Some(token) => {
+            REAUTH_SENT.store(false, Ordering::Relaxed);
+            token
+        }
+        None => {
+            // Once per outage, not every round.
+            if !REAUTH_SENT.swap(true, Ordering::Relaxed) {
+                webhook::emit(
+                    Event::EcobeeReauth,
+                    &ReauthEvent {
+                        reason: "could not refresh the Ecobee token",
+                    },
+                );
+            }
+            return Err("could not refresh the Ecobee token");
+        }
+    };
+    let readings = ecobee::read(&token.access_token);
+    if readings.is_empty() {
+        return Err("Ecobee returned no readings");
+    }
+    health::succeeded(health::ECOBEE);
+    Ok(readings)
+}
+
+/// # Timed
+/// Runs one of the worker's jobs, recording how long it took.
+fn timed<T, F: FnOnce() -> T>(job: &str, f: F) -> T {
+    let start = Instant::now();
+    let result = f();
+    metrics::observe_since(metrics::JOB_DURATION, &[("job", job)], start);
+    result
+}
+
+/// # Failed
+/// Counts a failed worker job.
+fn failed(job: &str) {
+    metrics::inc(metrics::JOB_FAILURES, &[("job", job)]);
 }
 
 /// # Write Thermostats
-/// Writes thermostats to the now response
+/// Writes thermostats to the now response, keeping recent readings from
+/// sensors that were not in this round.
 fn write_thermostats(therms: Vec<Thermostat>) {
     let now_res = Arc::clone(&NOW_RES);
     let mut now_res = now_res.write().unwrap();
+    let cutoff = Utc::now() - ChronoDuration::seconds(KEEP_IN_NOW);
+    let kept: Vec<Thermostat> = now_res
+        .thermostats
+        .iter()
+        .filter(|kept| kept.time() > cutoff && !therms.iter().any(|therm| therm.name == kept.name))
+        .cloned()
+        .collect();
+    let mut thermostats = therms;
+    thermostats.extend(kept);
     *now_res = NowResponse {
         forecast_hourly: now_res.forecast_hourly.clone(),
         forecast_daily: now_res.forecast_daily.clone(),
-        thermostats: therms,
+        thermostats,
+        mold_risk: now_res.mold_risk.clone(),
     };
 }
 
+/// # Add Readings
+/// Puts readings that did not come from the worker, like those pushed to
+/// `POST /readings`, in the now response straight away. A reading replaces
+/// the sensor's current one unless it is older.
+pub fn add_readings(readings: &[Thermostat]) {
+    {
+        let now_res = Arc::clone(&NOW_RES);
+        let mut now_res = now_res.write().unwrap();
+        for reading in readings {
+            match now_res
+                .thermostats
+                .iter_mut()
+                .find(|therm| therm.name == reading.name)
+            {
+                Some(therm) if therm.time() > reading.time() => (),
+                Some(therm) => *therm = reading.clone(),
+                None => now_res.thermostats.push(reading.clone()),
+            }
+        }
+    }
+    serialize_now();
+}
+
 /// # Write Hourly Forecast
 /// Writes forecast_hourly to the now response
 fn write_hourly_forecast(forecast: Option<Forecast<HourlyCondition>>) {
@@ -123,6 +378,7 @@ fn write_hourly_forecast(forecast: Option<Forecast<HourlyCondition>>) {
                 forecast_hourly: forecast.conditions,
                 forecast_daily: now_res.forecast_daily.clone(),
                 thermostats: now_res.thermostats.clone(),
+                mold_risk: now_res.mold_risk.clone(),
             };
         }
     }
@@ -140,21 +396,36 @@ fn write_daily_forecast(forecast: Option<Forecast<DailyCondition>>) {
                 forecast_hourly: now_res.forecast_hourly.clone(),
                 forecast_daily: forecast.conditions,
                 thermostats: now_res.thermostats.clone(),
+                mold_risk: now_res.mold_risk.clone(),
             };
         }
     }
 }
 
+/// # Write Mold Risk
+/// Writes mold_risk to the now response
+fn write_mold_risk(mold_risk: Vec<MoldRisk>) {
+    let now_res = Arc::clone(&NOW_RES);
+    let mut now_res = now_res.write().unwrap();
+    *now_res = NowResponse {
+        forecast_hourly: now_res.forecast_hourly.clone(),
+        forecast_daily: now_res.forecast_daily.clone(),
+        thermostats: now_res.thermostats.clone(),
+        mold_risk,
+    };
+}
+
 /// # Serialize Now
 /// Perform a one-time JSON encoding of NOW_RES, storing the result in static
-/// NOW_STR. This gives us the world's TINIEST performance gain by repetitive
-/// calls to now not having to serialize the data again.
+/// NOW_BODY. This gives us the world's TINIEST performance gain by repetitive
+/// calls to now not having to serialize (or compress) the data again.
 fn serialize_now() {
     let now_res = Arc::clone(&NOW_RES);
     let now_res = now_res.read().unwrap();
-    let now_str = Arc::clone(&NOW_STR);
-    let mut now_str = now_str.write().unwrap();
-    *now_str = serde_json::to_string(&*now_res).unwrap();
+    let body = CachedBody::new(serde_json::to_string(&*now_res).unwrap());
+    let now_body = Arc::clone(&NOW_BODY);
+    let mut now_body = now_body.write().unwrap();
+    *now_body = body;
 }
 
 /// # Most Applicable
@@ -154,7 +154,7 @@ pub fn daily_forecast() -> Option<Forecast<DailyCondition>> {
     match weather_request_retry_wrapper(false) {
         Ok(response) => Some(response.into()),
         Err(err) => {
-            crate::log_error(&format!("Failed getting weather! {:?}", err));
+            tracing::error!("Failed getting weather! {:?}", err);
             None
         }
     }
@@ -169,27 +169,27 @@ pub fn hourly_forecast() -> Option<Forecast<HourlyCondition>> {
             HourlyCondition {
                 date: Utc.timestamp(1595232000, 0),
                 condition: String::from("Sunny"),
-                temperature: 800,
+                temperature: 80,
             },
             HourlyCondition {
                 date: Utc.timestamp(1595235600, 0),
                 condition: String::from("Sunny"),
-                temperature: 780,
+                temperature: 78,
             },
             HourlyCondition {
                 date: Utc.timestamp(1595239200, 0),
                 condition: String::from("Partly Sunny"),
-                temperature: 810,
+                temperature: 81,
             },
             HourlyCondition {
                 date: Utc.timestamp(1595242800, 0),
                 condition: String::from("Raining"),
-                temperature: 750,
+                temperature: 75,
             },
             HourlyCondition {
                 date: Utc.timestamp(1595246400, 0),
                 condition: String::from("Thunder Storms"),
-                temperature: 720,
+                temperature: 72,
             },
             // TODO: return however many the weather.gov api returns
         ],
@@ -200,7 +200,7 @@ pub fn hourly_forecast() -> Option<Forecast<HourlyCondition>> {
     match weather_request_retry_wrapper(true) {
         Ok(response) => Some(response.into()),
         Err(err) => {
-            crate::log_error(&format!("Failed getting weather! {:?}", err));
+            tracing::error!("Failed getting weather! {:?}", err);
             None
         }
     }
@@ -215,7 +215,7 @@ fn weather_request_retry_wrapper(hourly: bool) -> anyhow::Result<Vec<ApiConditio
         if let Ok(result) = weather_request(hourly) {
             return Ok(result);
         }
-        crate::log_error(&format!("Failed to get weather"));
+        tracing::warn!("Failed to get weather; retrying");
         std::thread::sleep(std::time::Duration::from_secs(2));
     }
     weather_request(hourly)
@@ -223,30 +223,37 @@ fn weather_request_retry_wrapper(hourly: bool) -> anyhow::Result<Vec<ApiConditio
 
 /// # Weather Request
 /// Gets either hourly or daily weather (based on boolean input var) from weather.gov
-/// and returns a vector of ApiCondition
+/// and returns a vector of ApiCondition. Error statuses, bodies that are not a
+/// forecast and forecasts without any periods are errors.
 #[cfg(not(any(test, feature = "offline")))]
 #[tokio::main]
 async fn weather_request(hourly: bool) -> anyhow::Result<Vec<ApiCondition>> {
-    crate::log_error(&format!(
-        "[worker] Getting {} weather",
-        if hourly { "hourly" } else { "daily" }
-    ));
+    tracing::info!(hourly, "Getting weather");
 
-    let weather_url = std::env::var(if hourly {
-        "WEATHER_URL_HOURLY"
+    let config = crate::config::get();
+    let weather_url = if hourly {
+        &config.weather.url_hourly
     } else {
-        "WEATHER_URL_DAILY"
-    })
-    .unwrap();
+        &config.weather.url_daily
+    };
+    let start = std::time::Instant::now();
     let body = crate::REQWEST
-        .get(&weather_url)
+        .get(weather_url.as_str())
         .header("User-Agent", "github.com/ryanknu/therm_hub")
         .send()
         .await?
+        .error_for_status()?
         .text()
         .await?;
-    match serde_json::from_str::<ApiResponse>(&body) {
-        Ok(data) => Ok(data.properties.periods),
-        Err(_) => Ok(Vec::new()),
+    crate::metrics::observe_since(
+        crate::metrics::WEATHER_FETCH_DURATION,
+        &[("kind", if hourly { "hourly" } else { "daily" })],
+        start,
+    );
+    let periods = serde_json::from_str::<ApiResponse>(&body)?.properties.periods;
+    // An empty forecast is no forecast, so the poll counts as failed.
+    if periods.is_empty() {
+        anyhow::bail!("weather.gov returned no forecast periods");
     }
+    Ok(periods)
 }